@@ -111,6 +111,12 @@ fn print_status(_: &ArgMatches) -> Result<()> {
     for x in devices {
         println!("Device {}", x.id());
         println!("{:?}", x.status());
+        let faulty = x.faulty_pes().context(DeviceInit {})?;
+        if faulty.is_empty() {
+            println!("All PEs are healthy.");
+        } else {
+            println!("Faulty PEs: {:?}", faulty);
+        }
     }
     Ok(())
 }
@@ -20,13 +20,13 @@
 
 use crate::device::DeviceAddress;
 use crate::device::DeviceSize;
-use crate::tlkm::tlkm_ioctl_alloc;
-use crate::tlkm::tlkm_ioctl_free;
 use crate::tlkm::tlkm_mm_cmd;
+use crate::tlkm::DeviceDriver;
+use chrono::{DateTime, Local};
 use core::fmt::Debug;
 use snafu::ResultExt;
-use std::fs::File;
-use std::os::unix::prelude::*;
+use std::backtrace::Backtrace;
+use std::collections::{BTreeMap, BTreeSet, HashMap};
 use std::sync::Arc;
 
 #[derive(Debug, Snafu, PartialEq)]
@@ -61,22 +61,87 @@ pub trait Allocator: Debug {
     fn allocate(&mut self, size: DeviceSize) -> Result<DeviceAddress>;
     fn allocate_fixed(&mut self, size: DeviceSize, offset: DeviceAddress) -> Result<DeviceAddress>;
     fn free(&mut self, ptr: DeviceAddress) -> Result<()>;
+
+    /// Current usage of the memory.
+    fn stats(&self) -> AllocatorStats;
+
+    /// All live allocations ordered by address.
+    fn dump(&self) -> Vec<AllocationInfo>;
 }
 
-#[derive(Debug, Getters, Copy, Clone)]
-struct MemoryFree {
-    base: DeviceAddress,
-    size: DeviceSize,
+/// Usage information of an allocator as returned by [`Allocator.stats`].
+///
+/// Sizes include the padding added for alignment.
+///
+/// [`Allocator.stats`]: trait.Allocator.html#tymethod.stats
+#[derive(Debug, Copy, Clone, Default, PartialEq)]
+#[repr(C)]
+pub struct AllocatorStats {
+    /// Size of the managed memory in bytes.
+    pub total: DeviceSize,
+    pub used: DeviceSize,
+    pub free: DeviceSize,
+    /// Size of the largest free segment, i.e. the largest possible allocation.
+    pub largest_free: DeviceSize,
+    /// Number of live allocations.
+    pub allocations: u64,
+    /// Share of the free memory outside of the largest free segment. 0 if all free
+    /// memory is contiguous, approaching 1 if it is split into many small segments.
+    pub fragmentation: f64,
+}
+
+impl AllocatorStats {
+    fn new(
+        used: DeviceSize,
+        free: DeviceSize,
+        largest_free: DeviceSize,
+        allocations: usize,
+    ) -> AllocatorStats {
+        AllocatorStats {
+            total: used + free,
+            used: used,
+            free: free,
+            largest_free: largest_free,
+            allocations: allocations as u64,
+            fragmentation: if free == 0 {
+                0.0
+            } else {
+                1.0 - largest_free as f64 / free as f64
+            },
+        }
+    }
+}
+
+/// A live allocation as returned by [`Allocator.dump`].
+///
+/// [`Allocator.dump`]: trait.Allocator.html#tymethod.dump
+#[derive(Debug, Copy, Clone, PartialEq)]
+#[repr(C)]
+pub struct AllocationInfo {
+    pub address: DeviceAddress,
+    pub size: DeviceSize,
+}
+
+fn sorted_allocations<I: Iterator<Item = AllocationInfo>>(it: I) -> Vec<AllocationInfo> {
+    let mut v: Vec<AllocationInfo> = it.collect();
+    v.sort_by_key(|x| x.address);
+    v
 }
-/// Simple allocator for host handled device memory
+
+/// Allocator for host handled device memory
 ///
-/// Simply finds the first free memory on the device and returns it.
-/// Keeps track of allocated and free memory through lists.
+/// Uses best-fit placement: The smallest free segment that fits the request is used.
+/// Free segments are kept in ordered maps by address and by size, so allocations and
+/// frees including the merging of neighbouring segments take O(log n).
 /// Supports memory alignment on byte granularity.
 #[derive(Debug, Getters)]
 pub struct GenericAllocator {
-    memory_free: Vec<MemoryFree>,
-    memory_used: Vec<MemoryFree>,
+    /// Free segments: base -> size
+    free_by_address: BTreeMap<DeviceAddress, DeviceSize>,
+    /// Free segments ordered by (size, base) for the best-fit lookup
+    free_by_size: BTreeSet<(DeviceSize, DeviceAddress)>,
+    /// Allocated segments: base -> size
+    used: HashMap<DeviceAddress, DeviceSize>,
     alignment: DeviceSize,
 }
 
@@ -97,14 +162,14 @@ impl GenericAllocator {
                 alignment: alignment,
             });
         }
-        Ok(GenericAllocator {
-            memory_free: vec![MemoryFree {
-                base: address,
-                size: size,
-            }],
-            memory_used: Vec::new(),
+        let mut a = GenericAllocator {
+            free_by_address: BTreeMap::new(),
+            free_by_size: BTreeSet::new(),
+            used: HashMap::new(),
             alignment: alignment,
-        })
+        };
+        a.insert_free(address, size);
+        Ok(a)
     }
 
     fn fix_alignment(&self, size: DeviceSize) -> DeviceSize {
@@ -112,27 +177,39 @@ impl GenericAllocator {
         (size + (self.alignment - 1)) & !(self.alignment - 1)
     }
 
-    fn merge_memory(&mut self) -> () {
-        let mut i = 0;
-        trace!("Merging memory, currently at {:?}.", self.memory_free);
-        while i < self.memory_free.len() {
-            let n = i + 1;
-            if n < self.memory_free.len()
-                && self.memory_free[i].base + self.memory_free[i].size == self.memory_free[n].base
-            {
-                trace!(
-                    "Merging {:?} and {:?}.",
-                    self.memory_free[i],
-                    self.memory_free[n]
-                );
-                self.memory_free[i].size += self.memory_free[n].size;
-                self.memory_free.remove(n);
-            } else {
-                trace!("Checking next element.");
-                i += 1;
-            }
+    fn insert_free(&mut self, base: DeviceAddress, size: DeviceSize) {
+        if size > 0 {
+            self.free_by_address.insert(base, size);
+            self.free_by_size.insert((size, base));
         }
     }
+
+    fn remove_free(&mut self, base: DeviceAddress, size: DeviceSize) {
+        self.free_by_address.remove(&base);
+        self.free_by_size.remove(&(size, base));
+    }
+
+    /// Mark `[offset, offset + size)` inside the free segment at `base` as used and return
+    /// the remaining parts to the free maps.
+    fn take(
+        &mut self,
+        base: DeviceAddress,
+        segment_size: DeviceSize,
+        offset: DeviceAddress,
+        size: DeviceSize,
+    ) {
+        trace!(
+            "Using {}B @ 0x{:x} of free segment {}B @ 0x{:x}.",
+            size,
+            offset,
+            segment_size,
+            base
+        );
+        self.remove_free(base, segment_size);
+        self.insert_free(base, offset - base);
+        self.insert_free(offset + size, base + segment_size - (offset + size));
+        self.used.insert(offset, size);
+    }
 }
 
 impl Allocator for GenericAllocator {
@@ -142,37 +219,11 @@ impl Allocator for GenericAllocator {
         }
         trace!("Looking for free memory.");
         let size_aligned = self.fix_alignment(size);
-        let mut element_found = None;
-        let mut addr_found = None;
-        for (i, s) in &mut self.memory_free.iter_mut().enumerate() {
-            if s.size >= size_aligned {
-                trace!("Found free space in segment {:?}.", s);
-                let addr = s.base;
-                addr_found = Some(addr);
-                self.memory_used.push(MemoryFree {
-                    base: addr,
-                    size: size_aligned,
-                });
-                s.size -= size_aligned;
-                s.base += size_aligned;
-                if s.size == 0 {
-                    element_found = Some(i);
-                } else {
-                    trace!("New segment is {:?}.", s);
-                }
-            }
-        }
-
-        match element_found {
-            Some(x) => {
-                trace!("Removing empty segment.");
-                self.memory_free.remove(x);
+        match self.free_by_size.range((size_aligned, 0)..).next() {
+            Some(&(segment_size, base)) => {
+                self.take(base, segment_size, base, size_aligned);
+                Ok(base)
             }
-            None => (),
-        };
-
-        match addr_found {
-            Some(x) => Ok(x),
             None => Err(Error::OutOfMemory { size: size_aligned }),
         }
     }
@@ -183,60 +234,12 @@ impl Allocator for GenericAllocator {
         }
         trace!("Looking for free memory at offset 0x{:x}.", offset);
         let size_aligned = self.fix_alignment(size);
-        let mut element_found = None;
-        let mut addr_found = None;
-        for (i, s) in &mut self.memory_free.iter_mut().enumerate() {
-            let segment_end = s.base + s.size;
-            if s.base <= offset && offset <= segment_end && segment_end - offset >= size_aligned {
-                trace!("Found fixed free space in segment {:?}.", s);
-                addr_found = Some(offset);
-                self.memory_used.push(MemoryFree {
-                    base: offset,
-                    size: size_aligned,
-                });
-
-                element_found = Some(i);
-                break;
-            }
-        }
-
-        match element_found {
-            Some(x) => {
-                trace!("Splitting old segment.");
-                if self.memory_free[x].base == offset {
-                    self.memory_free[x].base += size_aligned;
-                    self.memory_free[x].size -= size_aligned;
-                } else {
-                    let left_size = offset - self.memory_free[x].base;
-
-                    let split_segment = MemoryFree {
-                        base: offset + size_aligned,
-                        size: self.memory_free[x].size - (left_size + size_aligned),
-                    };
-
-                    if split_segment.size > 0 {
-                        self.memory_free.insert(x + 1, split_segment);
-                        trace!("New segment right of allocated area: {:?}.", split_segment);
-                    }
-
-                    self.memory_free[x].size = left_size;
-                }
-                if self.memory_free[x].size == 0 {
-                    trace!("Segment left of allocated area empty -> Removed.");
-                    self.memory_free.remove(x);
-                } else {
-                    trace!(
-                        "New segment left of allocated area: {:?}.",
-                        self.memory_free[x]
-                    );
-                }
+        match self.free_by_address.range(..=offset).next_back() {
+            Some((&base, &segment_size)) if offset + size_aligned <= base + segment_size => {
+                self.take(base, segment_size, offset, size_aligned);
+                Ok(offset)
             }
-            None => (),
-        };
-
-        match addr_found {
-            Some(x) => Ok(x),
-            None => {
+            _ => {
                 trace!(
                     "Could not find free memory {}B @ {:x}: {:?}.",
                     size_aligned,
@@ -252,37 +255,413 @@ impl Allocator for GenericAllocator {
     }
 
     fn free(&mut self, ptr: DeviceAddress) -> Result<()> {
-        match self.memory_used.iter().position(|&x| x.base == ptr) {
-            Some(x) => {
-                let m = self.memory_used[x];
-                self.memory_used.remove(x);
-                trace!("Freeing memory segment {:?}", m);
-                if self.memory_free.len() == 0 {
-                    trace!("No free memory right now, adding directly.");
-                    self.memory_free.push(m);
+        let size = match self.used.remove(&ptr) {
+            Some(x) => x,
+            None => return Err(Error::UnknownMemory { ptr: ptr }),
+        };
+        trace!("Freeing memory segment {}B @ 0x{:x}.", size, ptr);
+        let mut base = ptr;
+        let mut merged = size;
+
+        let left = self
+            .free_by_address
+            .range(..ptr)
+            .next_back()
+            .map(|(&b, &s)| (b, s));
+        if let Some((b, s)) = left {
+            if b + s == ptr {
+                trace!("Merging with left segment {}B @ 0x{:x}.", s, b);
+                self.remove_free(b, s);
+                base = b;
+                merged += s;
+            }
+        }
+
+        let right = self.free_by_address.get(&(ptr + size)).copied();
+        if let Some(s) = right {
+            trace!("Merging with right segment {}B @ 0x{:x}.", s, ptr + size);
+            self.remove_free(ptr + size, s);
+            merged += s;
+        }
+
+        self.insert_free(base, merged);
+        Ok(())
+    }
+
+    fn stats(&self) -> AllocatorStats {
+        AllocatorStats::new(
+            self.used.values().sum(),
+            self.free_by_address.values().sum(),
+            self.free_by_size.iter().next_back().map_or(0, |x| x.0),
+            self.used.len(),
+        )
+    }
+
+    fn dump(&self) -> Vec<AllocationInfo> {
+        sorted_allocations(self.used.iter().map(|(&a, &s)| AllocationInfo {
+            address: a,
+            size: s,
+        }))
+    }
+}
+
+/// Buddy allocator for host handled device memory
+///
+/// Allocations are rounded up to a power of two multiple of the alignment and taken from
+/// per size free lists. Freed blocks are merged with their buddy, which bounds the
+/// fragmentation for long running applications at the cost of internal fragmentation.
+/// Memories that are not a power of two in size are split into several top level blocks.
+#[derive(Debug, Getters)]
+pub struct BuddyAllocator {
+    address: DeviceAddress,
+    size: DeviceSize,
+    /// log2 of the smallest block size
+    min_order: u32,
+    /// Free blocks per order, relative to `address`
+    free: Vec<BTreeSet<DeviceAddress>>,
+    /// Allocated blocks relative to `address` and their order
+    used: HashMap<DeviceAddress, u32>,
+}
+
+impl BuddyAllocator {
+    /// Generate a new allocator with the given size and alignment
+    ///
+    /// The alignment has to be a power of two and is used as the smallest block size.
+    pub fn new(
+        address: DeviceAddress,
+        size: DeviceSize,
+        alignment: DeviceSize,
+    ) -> Result<BuddyAllocator> {
+        if alignment == 0 || !alignment.is_power_of_two() {
+            return Err(Error::InvalidAlignment {
+                alignment: alignment,
+            });
+        }
+        if size < alignment {
+            return Err(Error::InvalidSize { size: size });
+        }
+        let min_order = alignment.trailing_zeros();
+        let max_order = 63 - size.leading_zeros();
+        let mut a = BuddyAllocator {
+            address: address,
+            size: size,
+            min_order: min_order,
+            free: vec![BTreeSet::new(); (max_order + 1) as usize],
+            used: HashMap::new(),
+        };
+
+        // Cover the memory with the largest possible blocks.
+        let mut offset = 0;
+        for order in (min_order..=max_order).rev() {
+            if size - offset >= 1 << order {
+                trace!(
+                    "Adding top level block {}B @ 0x{:x}.",
+                    1u64 << order,
+                    offset
+                );
+                a.free[order as usize].insert(offset);
+                offset += 1 << order;
+            }
+        }
+        Ok(a)
+    }
+
+    fn order(&self, size: DeviceSize) -> Option<u32> {
+        let order = std::cmp::max(
+            size.checked_next_power_of_two()?.trailing_zeros(),
+            self.min_order,
+        );
+        if (order as usize) < self.free.len() {
+            Some(order)
+        } else {
+            None
+        }
+    }
+
+    /// Split the free block `block` of order `from` until a block of order `to`
+    /// containing `target` remains, which is marked as used.
+    fn split(&mut self, block: DeviceAddress, from: u32, to: u32, target: DeviceAddress) {
+        self.free[from as usize].remove(&block);
+        let mut block = block;
+        for order in (to..from).rev() {
+            let half = 1 << order;
+            if target >= block + half {
+                self.free[order as usize].insert(block);
+                block += half;
+            } else {
+                self.free[order as usize].insert(block + half);
+            }
+        }
+        self.used.insert(block, to);
+    }
+}
+
+impl Allocator for BuddyAllocator {
+    fn allocate(&mut self, size: DeviceSize) -> Result<DeviceAddress> {
+        if size == 0 {
+            return Err(Error::InvalidSize { size: size });
+        }
+        let order = match self.order(size) {
+            Some(x) => x,
+            None => return Err(Error::OutOfMemory { size: size }),
+        };
+        trace!("Looking for free block of order {}.", order);
+        for from in order as usize..self.free.len() {
+            if let Some(&block) = self.free[from].iter().next() {
+                self.split(block, from as u32, order, block);
+                return Ok(self.address + block);
+            }
+        }
+        Err(Error::OutOfMemory { size: 1 << order })
+    }
+
+    fn allocate_fixed(&mut self, size: DeviceSize, offset: DeviceAddress) -> Result<DeviceAddress> {
+        if size == 0 {
+            return Err(Error::InvalidSize { size: size });
+        }
+        trace!("Looking for free block at offset 0x{:x}.", offset);
+        let not_available = Error::FixedNotAvailable {
+            size: size,
+            offset: offset,
+        };
+        let order = match self.order(size) {
+            Some(x) => x,
+            None => return Err(not_available),
+        };
+        if offset < self.address || offset - self.address >= self.size {
+            return Err(not_available);
+        }
+        let target = offset - self.address;
+        if target & ((1 << order) - 1) != 0 {
+            trace!("Offset is not aligned to a block of order {}.", order);
+            return Err(not_available);
+        }
+        for from in order as usize..self.free.len() {
+            let block = target & !((1 << from) - 1);
+            if self.free[from].contains(&block) {
+                self.split(block, from as u32, order, target);
+                return Ok(offset);
+            }
+        }
+        Err(not_available)
+    }
+
+    fn free(&mut self, ptr: DeviceAddress) -> Result<()> {
+        let mut block = ptr.wrapping_sub(self.address);
+        let mut order = match self.used.remove(&block) {
+            Some(x) => x,
+            None => return Err(Error::UnknownMemory { ptr: ptr }),
+        };
+        trace!("Freeing block of order {} @ 0x{:x}.", order, ptr);
+        while (order as usize) + 1 < self.free.len() {
+            let buddy = block ^ (1 << order);
+            if buddy + (1 << order) > self.size || !self.free[order as usize].remove(&buddy) {
+                break;
+            }
+            trace!("Merging with buddy @ 0x{:x}.", buddy);
+            block = std::cmp::min(block, buddy);
+            order += 1;
+        }
+        self.free[order as usize].insert(block);
+        Ok(())
+    }
+
+    fn stats(&self) -> AllocatorStats {
+        let free = self
+            .free
+            .iter()
+            .enumerate()
+            .map(|(order, blocks)| (blocks.len() as u64) << order)
+            .sum();
+        let largest_free = match self.free.iter().rposition(|x| !x.is_empty()) {
+            Some(order) => 1 << order,
+            None => 0,
+        };
+        AllocatorStats::new(
+            self.used.values().map(|&order| 1u64 << order).sum(),
+            free,
+            largest_free,
+            self.used.len(),
+        )
+    }
+
+    fn dump(&self) -> Vec<AllocationInfo> {
+        sorted_allocations(self.used.iter().map(|(&block, &order)| AllocationInfo {
+            address: self.address + block,
+            size: 1 << order,
+        }))
+    }
+}
+
+/// Origin of a live allocation, used to report leaks.
+#[derive(Debug)]
+pub struct Provenance {
+    pub size: DeviceSize,
+    pub time: DateTime<Local>,
+    /// Free form tag provided by the caller.
+    pub tag: Option<String>,
+    /// ID of the job which allocated the memory for a data transfer.
+    pub job: Option<u64>,
+    /// Only captured if enabled through `capture_backtraces`.
+    pub backtrace: Option<Backtrace>,
+}
+
+/// Wraps an allocator and records the provenance of all live allocations.
+///
+/// Used by [`OffchipMemory`] to report and free leaked allocations once the memory
+/// is dropped.
+///
+/// [`OffchipMemory`]: ../device/struct.OffchipMemory.html
+#[derive(Debug)]
+pub struct TrackingAllocator {
+    allocator: Box<dyn Allocator + Sync + Send>,
+    live: HashMap<DeviceAddress, Provenance>,
+    backtraces: bool,
+}
+
+impl TrackingAllocator {
+    pub fn new(allocator: Box<dyn Allocator + Sync + Send>) -> TrackingAllocator {
+        TrackingAllocator {
+            allocator: allocator,
+            live: HashMap::new(),
+            backtraces: false,
+        }
+    }
+
+    /// Capture a backtrace for every following allocation. Expensive, meant for debugging.
+    pub fn capture_backtraces(&mut self, enable: bool) {
+        self.backtraces = enable;
+    }
+
+    /// Allocate memory and record `tag` and `job` as its origin.
+    ///
+    /// Allocates at `fixed` if given, see `allocate_fixed`.
+    pub fn allocate_tagged(
+        &mut self,
+        size: DeviceSize,
+        fixed: Option<DeviceAddress>,
+        tag: Option<&str>,
+        job: Option<u64>,
+    ) -> Result<DeviceAddress> {
+        let addr = match fixed {
+            Some(offset) => self.allocator.allocate_fixed(size, offset)?,
+            None => self.allocator.allocate(size)?,
+        };
+        self.live.insert(
+            addr,
+            Provenance {
+                size: size,
+                time: Local::now(),
+                tag: tag.map(|x| x.to_string()),
+                job: job,
+                backtrace: if self.backtraces {
+                    Some(Backtrace::force_capture())
                 } else {
-                    match self.memory_free.iter().position(|&x| x.base > m.base) {
-                        Some(x) => {
-                            trace!("Adding memory left of {}.", x);
-                            self.memory_free.insert(x, m);
-                        }
-                        None => {
-                            trace!("Adding memory to the end.");
-                            self.memory_free.push(m)
-                        }
-                    };
-                }
-                self.merge_memory();
-                Ok(())
+                    None
+                },
+            },
+        );
+        Ok(addr)
+    }
+
+    /// Live allocations and their origin ordered by address.
+    pub fn live(&self) -> Vec<(DeviceAddress, &Provenance)> {
+        let mut v: Vec<(DeviceAddress, &Provenance)> =
+            self.live.iter().map(|(&a, p)| (a, p)).collect();
+        v.sort_by_key(|x| x.0);
+        v
+    }
+
+    /// Log all live allocations of memory `name` as leaks.
+    pub fn report_leaks(&self, name: &str) {
+        if self.live.is_empty() {
+            return;
+        }
+        warn!(
+            "Memory {} has {} allocations that have not been freed:",
+            name,
+            self.live.len()
+        );
+        for (addr, p) in self.live() {
+            warn!(
+                "  0x{:x}: {} bytes allocated at {}, tag {}, job {}",
+                addr,
+                p.size,
+                p.time,
+                p.tag.as_ref().map_or("-", |x| x.as_str()),
+                p.job.map_or("-".to_string(), |x| x.to_string())
+            );
+            if let Some(b) = &p.backtrace {
+                warn!("{}", b);
             }
-            None => Err(Error::UnknownMemory { ptr: ptr }),
         }
     }
+
+    /// Free all live allocations. Returns the number of freed allocations.
+    pub fn free_all(&mut self) -> Result<usize> {
+        let addrs: Vec<DeviceAddress> = self.live.keys().copied().collect();
+        for a in &addrs {
+            self.free(*a)?;
+        }
+        Ok(addrs.len())
+    }
+
+    // The `Allocator` methods are provided as inherent methods as well, so existing
+    // users of `OffchipMemory.allocator()` do not have to import the trait.
+
+    pub fn allocate(&mut self, size: DeviceSize) -> Result<DeviceAddress> {
+        self.allocate_tagged(size, None, None, None)
+    }
+
+    pub fn allocate_fixed(
+        &mut self,
+        size: DeviceSize,
+        offset: DeviceAddress,
+    ) -> Result<DeviceAddress> {
+        self.allocate_tagged(size, Some(offset), None, None)
+    }
+
+    pub fn free(&mut self, ptr: DeviceAddress) -> Result<()> {
+        self.allocator.free(ptr)?;
+        self.live.remove(&ptr);
+        Ok(())
+    }
+
+    pub fn stats(&self) -> AllocatorStats {
+        self.allocator.stats()
+    }
+
+    pub fn dump(&self) -> Vec<AllocationInfo> {
+        self.allocator.dump()
+    }
+}
+
+impl Allocator for TrackingAllocator {
+    fn allocate(&mut self, size: DeviceSize) -> Result<DeviceAddress> {
+        TrackingAllocator::allocate(self, size)
+    }
+
+    fn allocate_fixed(&mut self, size: DeviceSize, offset: DeviceAddress) -> Result<DeviceAddress> {
+        TrackingAllocator::allocate_fixed(self, size, offset)
+    }
+
+    fn free(&mut self, ptr: DeviceAddress) -> Result<()> {
+        TrackingAllocator::free(self, ptr)
+    }
+
+    fn stats(&self) -> AllocatorStats {
+        TrackingAllocator::stats(self)
+    }
+
+    fn dump(&self) -> Vec<AllocationInfo> {
+        TrackingAllocator::dump(self)
+    }
 }
 
 #[cfg(test)]
 mod allocator_tests {
     use crate::allocator::Allocator;
+    use crate::allocator::BuddyAllocator;
     use crate::allocator::Error;
     use crate::allocator::GenericAllocator;
     use crate::allocator::Result;
@@ -330,6 +709,24 @@ mod allocator_tests {
         Ok(())
     }
 
+    #[test]
+    fn allocated_fixed_in_used_block() -> Result<()> {
+        init();
+        let mut a = GenericAllocator::new(0, 1024, 64)?;
+        assert_eq!(a.allocate_fixed(64, 64)?, 64);
+        // Free segments are 0..64 and 128..1024, 100 lies inside the allocation.
+        assert_eq!(
+            a.allocate_fixed(64, 100),
+            Err(Error::FixedNotAvailable {
+                size: 64,
+                offset: 100
+            })
+        );
+        assert_eq!(a.allocate_fixed(64, 0)?, 0);
+        assert_eq!(a.allocate(896)?, 128);
+        Ok(())
+    }
+
     #[test]
     fn allocated_fixed_large() -> Result<()> {
         init();
@@ -402,6 +799,128 @@ mod allocator_tests {
         Ok(())
     }
 
+    #[test]
+    fn best_fit() -> Result<()> {
+        init();
+        let mut a = GenericAllocator::new(0, 1024, 64)?;
+        let m = a.allocate(256)?;
+        let m2 = a.allocate(64)?;
+        let m3 = a.allocate(128)?;
+        let _m4 = a.allocate(64)?;
+        assert_eq!(a.free(m), Ok(()));
+        assert_eq!(a.free(m3), Ok(()));
+        // The 128 byte hole fits better than the 256 byte one.
+        assert_eq!(a.allocate(100)?, m3);
+        assert_eq!(a.allocate(200)?, m);
+        assert_eq!(a.free(m2), Ok(()));
+        assert_eq!(a.allocate(64)?, m2);
+        Ok(())
+    }
+
+    #[test]
+    fn many_allocations() -> Result<()> {
+        init();
+        let mut a = GenericAllocator::new(0x1000, 10000 * 64, 64)?;
+        let ms = (0..10000)
+            .map(|_| a.allocate(64))
+            .collect::<Result<Vec<_>>>()?;
+        assert_eq!(a.allocate(64), Err(Error::OutOfMemory { size: 64 }));
+        for m in ms.iter().step_by(2).chain(ms.iter().skip(1).step_by(2)) {
+            assert_eq!(a.free(*m), Ok(()));
+        }
+        assert_eq!(a.allocate(10000 * 64)?, 0x1000);
+        Ok(())
+    }
+
+    #[test]
+    fn buddy_split_merge() -> Result<()> {
+        init();
+        let mut a = BuddyAllocator::new(0x1000, 1024, 64)?;
+        let m = a.allocate(100)?;
+        let m2 = a.allocate(64)?;
+        let m3 = a.allocate(64)?;
+        assert_eq!(m, 0x1000);
+        assert_eq!(m2, 0x1080);
+        assert_eq!(m3, 0x10c0);
+        assert_eq!(a.allocate(1024), Err(Error::OutOfMemory { size: 1024 }));
+        assert_eq!(a.free(m2), Ok(()));
+        assert_eq!(a.free(m), Ok(()));
+        assert_eq!(a.free(m3), Ok(()));
+        assert_eq!(a.allocate(1024)?, 0x1000);
+        assert_eq!(a.free(0x1000), Ok(()));
+        assert_eq!(a.free(0x1000), Err(Error::UnknownMemory { ptr: 0x1000 }));
+        Ok(())
+    }
+
+    #[test]
+    fn buddy_fixed() -> Result<()> {
+        init();
+        let mut a = BuddyAllocator::new(0, 1024, 64)?;
+        assert_eq!(a.allocate_fixed(128, 512)?, 512);
+        assert_eq!(
+            a.allocate_fixed(128, 520),
+            Err(Error::FixedNotAvailable {
+                size: 128,
+                offset: 520
+            })
+        );
+        assert_eq!(a.allocate_fixed(64, 640)?, 640);
+        assert!(a.allocate_fixed(64, 512).is_err());
+        assert_eq!(a.allocate(512)?, 0);
+        assert_eq!(a.free(512), Ok(()));
+        assert_eq!(a.free(640), Ok(()));
+        assert_eq!(a.free(0), Ok(()));
+        assert_eq!(a.allocate(1024)?, 0);
+        Ok(())
+    }
+
+    #[test]
+    fn buddy_non_power_of_two() -> Result<()> {
+        init();
+        let mut a = BuddyAllocator::new(0, 1024 + 256 + 64, 64)?;
+        assert_eq!(a.allocate(1024)?, 0);
+        assert_eq!(a.allocate(256)?, 1024);
+        assert_eq!(a.allocate(64)?, 1280);
+        assert_eq!(a.allocate(64), Err(Error::OutOfMemory { size: 64 }));
+        assert_eq!(a.free(1280), Ok(()));
+        assert_eq!(a.free(1024), Ok(()));
+        assert_eq!(a.allocate(256)?, 1024);
+        let huge = (1 << 63) + 1;
+        assert_eq!(a.allocate(huge), Err(Error::OutOfMemory { size: huge }));
+        Ok(())
+    }
+
+    #[test]
+    fn stats_and_dump() -> Result<()> {
+        init();
+        let mut a = GenericAllocator::new(0, 1024, 64)?;
+        let m = a.allocate(100)?;
+        let m2 = a.allocate(64)?;
+        let _m3 = a.allocate(64)?;
+        assert_eq!(a.free(m2), Ok(()));
+        let s = a.stats();
+        assert_eq!(s.total, 1024);
+        assert_eq!(s.used, 192);
+        assert_eq!(s.free, 832);
+        assert_eq!(s.largest_free, 768);
+        assert_eq!(s.allocations, 2);
+        assert!((s.fragmentation - 64.0 / 832.0).abs() < 1e-9);
+        let d = a.dump();
+        assert_eq!(d.len(), 2);
+        assert_eq!(d[0].address, m);
+        assert_eq!(d[0].size, 128);
+        assert_eq!(d[1].address, 192);
+
+        let mut b = BuddyAllocator::new(0, 1024, 64)?;
+        b.allocate(100)?;
+        let s = b.stats();
+        assert_eq!(s.used, 128);
+        assert_eq!(s.free, 896);
+        assert_eq!(s.largest_free, 512);
+        assert_eq!(b.dump()[0].size, 128);
+        Ok(())
+    }
+
     #[test]
     fn empty_allocate() -> Result<()> {
         init();
@@ -417,14 +936,19 @@ mod allocator_tests {
 /// This version is currently used for Zynq based devices.
 /// No actual memory handling is performed, the request is simply
 /// translated to IOCTLs and forwarded to the driver.
+///
+/// The driver does not report its free memory, so the statistics only contain the
+/// allocations made through this allocator.
 #[derive(Debug, Getters)]
 pub struct DriverAllocator {
-    tlkm_file: Arc<File>,
+    tlkm_file: Arc<dyn DeviceDriver>,
+    used: HashMap<DeviceAddress, DeviceSize>,
 }
 impl DriverAllocator {
-    pub fn new(tlkm_file: &Arc<File>) -> Result<DriverAllocator> {
+    pub fn new(tlkm_file: &Arc<dyn DeviceDriver>) -> Result<DriverAllocator> {
         Ok(DriverAllocator {
             tlkm_file: tlkm_file.clone(),
+            used: HashMap::new(),
         })
     }
 }
@@ -436,9 +960,10 @@ impl Allocator for DriverAllocator {
             sz: size as usize,
             dev_addr: std::u64::MAX,
         };
-        match unsafe { tlkm_ioctl_alloc(self.tlkm_file.as_raw_fd(), &mut cmd) } {
+        match self.tlkm_file.alloc(&mut cmd) {
             Ok(_x) => {
                 trace!("Received address 0x{:x} from driver.", cmd.dev_addr);
+                self.used.insert(cmd.dev_addr, size);
                 Ok(cmd.dev_addr)
             }
             Err(_e) => Err(Error::OutOfMemory { size: size }),
@@ -459,7 +984,23 @@ impl Allocator for DriverAllocator {
             sz: 0,
             dev_addr: ptr,
         };
-        unsafe { tlkm_ioctl_free(self.tlkm_file.as_raw_fd(), &mut cmd).context(IOCTLFree)? };
+        self.tlkm_file.free(&mut cmd).context(IOCTLFree)?;
+        self.used.remove(&ptr);
         Ok(())
     }
+
+    fn stats(&self) -> AllocatorStats {
+        AllocatorStats {
+            used: self.used.values().sum(),
+            allocations: self.used.len() as u64,
+            ..AllocatorStats::default()
+        }
+    }
+
+    fn dump(&self) -> Vec<AllocationInfo> {
+        sorted_allocations(self.used.iter().map(|(&a, &s)| AllocationInfo {
+            address: a,
+            size: s,
+        }))
+    }
 }
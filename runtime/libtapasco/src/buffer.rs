@@ -0,0 +1,266 @@
+/*
+ * Copyright (c) 2014-2020 Embedded Systems and Applications, TU Darmstadt.
+ *
+ * This file is part of TaPaSCo
+ * (see https://github.com/esa-tu-darmstadt/tapasco).
+ *
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU Lesser General Public License as published by
+ * the Free Software Foundation, either version 3 of the License, or
+ * (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU Lesser General Public License for more details.
+ *
+ * You should have received a copy of the GNU Lesser General Public License
+ * along with this program. If not, see <http://www.gnu.org/licenses/>.
+ */
+
+use crate::device::DeviceAddress;
+use crate::device::DeviceSize;
+use crate::device::OffchipMemory;
+use crate::device::PEParameter;
+use snafu::ResultExt;
+use std::marker::PhantomData;
+use std::mem::size_of;
+use std::slice;
+use std::sync::Arc;
+
+#[derive(Debug, Snafu)]
+pub enum Error {
+    #[snafu(display("Allocator Error: {}", source))]
+    AllocatorError { source: crate::allocator::Error },
+
+    #[snafu(display("DMA Error: {}", source))]
+    DMAError { source: crate::dma::Error },
+
+    #[snafu(display("Mutex has been poisoned"))]
+    MutexError {},
+
+    #[snafu(display("Buffer of {} elements exceeds the address space.", len))]
+    SizeOverflow { len: usize },
+
+    #[snafu(display("Access to {} elements exceeds buffer of {} elements.", len, capacity))]
+    OutOfBounds { len: usize, capacity: usize },
+}
+
+type Result<T, E = Error> = std::result::Result<T, E>;
+
+impl<T> From<std::sync::PoisonError<T>> for Error {
+    fn from(_error: std::sync::PoisonError<T>) -> Self {
+        Error::MutexError {}
+    }
+}
+
+/// Types that can be copied to and from the device byte by byte.
+///
+/// # Safety
+/// Implementors must not contain padding, pointers or invalid bit patterns.
+pub unsafe trait Pod: Copy + Send + Sync + 'static {}
+
+unsafe impl Pod for u8 {}
+unsafe impl Pod for u16 {}
+unsafe impl Pod for u32 {}
+unsafe impl Pod for u64 {}
+unsafe impl Pod for usize {}
+unsafe impl Pod for i8 {}
+unsafe impl Pod for i16 {}
+unsafe impl Pod for i32 {}
+unsafe impl Pod for i64 {}
+unsafe impl Pod for isize {}
+unsafe impl Pod for f32 {}
+unsafe impl Pod for f64 {}
+
+/// Untyped allocation on a device memory. Freed once the last reference is dropped.
+///
+/// Shared between a [`DeviceBuffer`] and the jobs using it as parameter, so the
+/// memory stays allocated while a PE may still access it.
+///
+/// [`DeviceBuffer`]: struct.DeviceBuffer.html
+#[derive(Debug, Getters)]
+pub struct Allocation {
+    #[get = "pub"]
+    memory: Arc<OffchipMemory>,
+    #[get = "pub"]
+    address: DeviceAddress,
+    #[get = "pub"]
+    size: DeviceSize,
+}
+
+impl Allocation {
+    /// Allocate `size` bytes on `memory`.
+    pub fn new(memory: &Arc<OffchipMemory>, size: DeviceSize) -> Result<Allocation> {
+        let address = memory
+            .allocator()
+            .lock()?
+            .allocate(size)
+            .context(AllocatorError)?;
+        trace!(
+            "Allocated buffer of {} bytes at 0x{:x} on memory {}.",
+            size,
+            address,
+            memory.name()
+        );
+        Ok(Allocation {
+            memory: memory.clone(),
+            address: address,
+            size: size,
+        })
+    }
+}
+
+impl Drop for Allocation {
+    fn drop(&mut self) {
+        trace!(
+            "Freeing buffer at 0x{:x} on memory {}.",
+            self.address,
+            self.memory.name()
+        );
+        let r = match self.memory.allocator().lock() {
+            Ok(mut a) => a.free(self.address).context(AllocatorError),
+            Err(e) => Err(e.into()),
+        };
+        if let Err(e) = r {
+            error!("Could not free buffer at 0x{:x}: {}", self.address, e);
+        }
+    }
+}
+
+/// Typed buffer on a device memory which is freed when dropped.
+///
+/// Can be passed to a job using [`param`], which turns into the device address of the
+/// buffer. The allocation stays valid until the job is released even if the buffer is
+/// dropped in between.
+///
+/// [`param`]: #method.param
+#[derive(Debug)]
+pub struct DeviceBuffer<T: Pod> {
+    allocation: Arc<Allocation>,
+    len: usize,
+    _type: PhantomData<T>,
+}
+
+impl<T: Pod> DeviceBuffer<T> {
+    /// Allocate space for `len` elements on `memory`.
+    pub fn new(memory: &Arc<OffchipMemory>, len: usize) -> Result<DeviceBuffer<T>> {
+        let size = match len.checked_mul(size_of::<T>()) {
+            Some(x) => x as DeviceSize,
+            None => return Err(Error::SizeOverflow { len: len }),
+        };
+        Ok(DeviceBuffer {
+            allocation: Arc::new(Allocation::new(memory, size)?),
+            len: len,
+            _type: PhantomData,
+        })
+    }
+
+    /// Number of elements in the buffer.
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    pub fn device_address(&self) -> DeviceAddress {
+        self.allocation.address
+    }
+
+    pub fn memory(&self) -> &Arc<OffchipMemory> {
+        &self.allocation.memory
+    }
+
+    /// Copy `data` to the start of the buffer.
+    pub fn write(&self, data: &[T]) -> Result<()> {
+        self.check_len(data.len())?;
+        let bytes = unsafe {
+            slice::from_raw_parts(data.as_ptr() as *const u8, data.len() * size_of::<T>())
+        };
+        self.allocation
+            .memory
+            .dma()
+            .copy_to(bytes, self.allocation.address)
+            .context(DMAError)
+    }
+
+    /// Fill `data` from the start of the buffer.
+    pub fn read(&self, data: &mut [T]) -> Result<()> {
+        self.check_len(data.len())?;
+        let bytes = unsafe {
+            slice::from_raw_parts_mut(data.as_mut_ptr() as *mut u8, data.len() * size_of::<T>())
+        };
+        self.allocation
+            .memory
+            .dma()
+            .copy_from(self.allocation.address, bytes)
+            .context(DMAError)
+    }
+
+    /// Job parameter referring to this buffer.
+    pub fn param(&self) -> PEParameter {
+        PEParameter::Buffer(self.allocation.clone())
+    }
+
+    fn check_len(&self, len: usize) -> Result<()> {
+        if len > self.len {
+            Err(Error::OutOfBounds {
+                len: len,
+                capacity: self.len,
+            })
+        } else {
+            Ok(())
+        }
+    }
+}
+
+impl<T: Pod> From<&DeviceBuffer<T>> for PEParameter {
+    fn from(buffer: &DeviceBuffer<T>) -> Self {
+        buffer.param()
+    }
+}
+
+#[cfg(test)]
+mod buffer_tests {
+    use crate::buffer::DeviceBuffer;
+    use crate::device::PEParameter;
+    use crate::sim::testing::{init, Result};
+    use crate::tlkm::tlkm_access;
+    use std::collections::HashMap;
+
+    #[test]
+    fn device_buffer() -> Result<()> {
+        let tlkm = init()?;
+        let mut dev = tlkm.device_alloc(0, &HashMap::new())?;
+        dev.change_access(tlkm_access::TlkmAccessExclusive)?;
+        let mem = dev.default_memory()?;
+        let buf: DeviceBuffer<u32> = DeviceBuffer::new(&mem, 1024)?;
+        let addr = buf.device_address();
+        assert_eq!(buf.len(), 1024);
+        buf.write(&vec![0x01020304; 1024])?;
+        assert!(buf.write(&vec![0; 1025]).is_err());
+
+        let mut job = dev.acquire_pe(14)?;
+        job.start(vec![buf.param(), PEParameter::Single64(4096)])?;
+        job.release(true, true)?;
+        let mut back = vec![0u32; 1024];
+        buf.read(&mut back)?;
+        assert!(back.iter().all(|x| *x == 0x02030405));
+
+        let mut job = dev.acquire_pe(14)?;
+        job.start(vec![(&buf).into(), PEParameter::Single64(4096)])?;
+        drop(buf);
+        // The job still references the buffer.
+        let other = mem.allocator().lock().unwrap().allocate(64)?;
+        assert!(other != addr);
+        mem.allocator().lock().unwrap().free(other)?;
+        job.release(true, true)?;
+
+        let again: DeviceBuffer<u8> = DeviceBuffer::new(&mem, 64)?;
+        assert_eq!(again.device_address(), addr);
+        assert!(DeviceBuffer::<u64>::new(&mem, usize::MAX / 4).is_err());
+        Ok(())
+    }
+}
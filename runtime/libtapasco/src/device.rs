@@ -18,30 +18,38 @@
  * along with this program. If not, see <http://www.gnu.org/licenses/>.
  */
 
-use crate::allocator::{Allocator, DriverAllocator, GenericAllocator};
+use crate::allocator::{
+    AllocationInfo, Allocator, AllocatorStats, GenericAllocator, TrackingAllocator,
+};
+use crate::buffer::Allocation;
 use crate::debug::DebugGenerator;
-use crate::dma::{DMAControl, DirectDMA, DriverDMA};
-use crate::dma_user_space::UserSpaceDMA;
+use crate::dispatcher::{Dispatcher, JobHandle};
+use crate::dma::{DMAControl, DirectDMA};
 use crate::job::Job;
 use crate::pe::PEId;
+use crate::platform::{PlatformContext, PlatformDriver};
+use crate::reactor::Reactor;
+use crate::scheduler::AcquireOptions;
+use crate::scheduler::PEHealth;
+use crate::scheduler::PEInstance;
+use crate::scheduler::ReleaseSignal;
 use crate::scheduler::Scheduler;
+use crate::scheduler::SchedulerStats;
 use crate::tlkm::tlkm_access;
-use crate::tlkm::tlkm_ioctl_create;
-use crate::tlkm::tlkm_ioctl_destroy;
 use crate::tlkm::tlkm_ioctl_device_cmd;
+use crate::tlkm::DeviceDriver;
 use crate::tlkm::DeviceId;
+use crate::tlkm::Driver;
 use config::Config;
 use memmap::MmapMut;
-use memmap::MmapOptions;
 use prost::Message;
 use snafu::ResultExt;
 use std::collections::HashMap;
 use std::collections::VecDeque;
-use std::fs::File;
-use std::fs::OpenOptions;
-use std::os::unix::io::AsRawFd;
+use std::path::PathBuf;
 use std::sync::Arc;
 use std::sync::Mutex;
+use std::time::Duration;
 
 /// Wrapper for the status core parser auto generated by prost from `status_core.proto`.
 pub mod status {
@@ -77,17 +85,20 @@ pub enum Error {
     #[snafu(display("PE acquisition requires Exclusive Access mode."))]
     ExclusiveRequired {},
 
-    #[snafu(display("Could not find any DMA engines."))]
-    DMAEngineMissing {},
-
     #[snafu(display("Could not destroy device {}: {}", id, source))]
     IOCTLDestroy { source: nix::Error, id: DeviceId },
 
+    #[snafu(display("Dispatcher Error: {}", source))]
+    DispatcherError { source: crate::dispatcher::Error },
+
     #[snafu(display("Scheduler Error: {}", source))]
     SchedulerError { source: crate::scheduler::Error },
 
-    #[snafu(display("DMA Error: {}", source))]
-    DMAError { source: crate::dma::Error },
+    #[snafu(display("Reactor Error: {}", source))]
+    ReactorError { source: crate::reactor::Error },
+
+    #[snafu(display("Platform Error: {}", source))]
+    PlatformError { source: crate::platform::Error },
 
     #[snafu(display("Allocator Error: {}", source))]
     AllocatorError { source: crate::allocator::Error },
@@ -100,6 +111,9 @@ pub enum Error {
 
     #[snafu(display("Could not parse configuration {}", source))]
     ConfigError { source: config::ConfigError },
+
+    #[snafu(display("Memory {} not found on device.", name))]
+    MemoryMissing { name: String },
 }
 type Result<T, E = Error> = std::result::Result<T, E>;
 
@@ -118,12 +132,64 @@ pub type DeviceSize = u64;
 ///
 /// Access to the memory is provided through the allocator to manage memory allocations
 /// and the DMA which can be used to transfer data to and from the memory.
+///
+/// Allocations that are still alive when the memory is dropped are reported as leaks
+/// and freed.
 #[derive(Debug, Getters)]
 pub struct OffchipMemory {
     #[get = "pub"]
-    allocator: Mutex<Box<dyn Allocator + Sync + Send>>,
+    name: String,
     #[get = "pub"]
-    dma: Box<dyn DMAControl + Sync + Send>,
+    allocator: Mutex<TrackingAllocator>,
+    #[get = "pub"]
+    dma: Arc<dyn DMAControl + Sync + Send>,
+}
+
+impl OffchipMemory {
+    pub fn new(
+        name: String,
+        allocator: Box<dyn Allocator + Sync + Send>,
+        dma: Arc<dyn DMAControl + Sync + Send>,
+    ) -> OffchipMemory {
+        OffchipMemory {
+            name: name,
+            allocator: Mutex::new(TrackingAllocator::new(allocator)),
+            dma: dma,
+        }
+    }
+
+    /// Free all remaining allocations, e.g. after a failed job.
+    ///
+    /// Returns the number of freed allocations.
+    pub fn free_all(&self) -> Result<usize> {
+        self.allocator.lock()?.free_all().context(AllocatorError)
+    }
+
+    /// Current usage of the memory, see [`Allocator.stats`].
+    ///
+    /// [`Allocator.stats`]: ../allocator/trait.Allocator.html#tymethod.stats
+    pub fn stats(&self) -> Result<AllocatorStats> {
+        Ok(self.allocator.lock()?.stats())
+    }
+
+    /// Live allocations of the memory ordered by address.
+    pub fn dump(&self) -> Result<Vec<AllocationInfo>> {
+        Ok(self.allocator.lock()?.dump())
+    }
+}
+
+impl Drop for OffchipMemory {
+    fn drop(&mut self) {
+        match self.allocator.get_mut() {
+            Ok(a) => {
+                a.report_leaks(&self.name);
+                if let Err(e) = a.free_all() {
+                    warn!("Could not free leaked memory of {}: {}", self.name, e);
+                }
+            }
+            Err(_) => warn!("Allocator of memory {} has been poisoned.", self.name),
+        }
+    }
 }
 
 // Types to describe PE parameters.
@@ -193,10 +259,28 @@ pub enum PEParameter {
     DataTransferAlloc(DataTransferAlloc),
     /// Transfer using any memory with preallocated space.
     DataTransferPrealloc(DataTransferPrealloc),
+    /// Address of a [`DeviceBuffer`], which is kept allocated until the job is released.
+    ///
+    /// [`DeviceBuffer`]: ../buffer/struct.DeviceBuffer.html
+    Buffer(Arc<Allocation>),
 }
 
 // End of PE parameters.
 
+/// Read an integer setting that has to be at least `min`.
+fn config_int(settings: &Config, key: &str, min: i64) -> Result<u64> {
+    let x = settings.get_int(key).context(ConfigError)?;
+    if x < min {
+        return Err(Error::ConfigError {
+            source: config::ConfigError::Message(format!(
+                "{} has to be at least {}, got {}.",
+                key, min, x
+            )),
+        });
+    }
+    Ok(x as u64)
+}
+
 /// Description of a TaPaSCo device. Contains all relevant information and the operations
 /// to interact with the device.
 ///
@@ -205,6 +289,8 @@ pub enum PEParameter {
 /// [`TLKM.device_alloc`]: ../tlkm/struct.TLKM.html#method.device_alloc
 #[derive(Debug, Getters)]
 pub struct Device {
+    /// Declared first to finish submitted jobs before the rest of the device is dropped.
+    dispatcher: Mutex<Option<Arc<Dispatcher>>>,
     #[get = "pub"]
     status: status::Status,
     #[get = "pub"]
@@ -220,8 +306,8 @@ pub struct Device {
     platform: Arc<MmapMut>,
     arch: Arc<MmapMut>,
     offchip_memory: Vec<Arc<OffchipMemory>>,
-    tlkm_file: Arc<File>,
-    tlkm_device_file: Arc<File>,
+    tlkm_file: Arc<dyn Driver>,
+    tlkm_device_file: Arc<dyn DeviceDriver>,
     settings: Arc<Config>,
 }
 
@@ -234,39 +320,35 @@ impl Device {
     ///
     /// [`TLKM.device_alloc`]: ../tlkm/struct.TLKM.html#method.device_alloc
     pub fn new(
-        tlkm_file: Arc<File>,
+        tlkm_file: Arc<dyn Driver>,
         id: DeviceId,
         vendor: u32,
         product: u32,
         name: String,
         settings: Arc<Config>,
         debug_impls: &HashMap<String, Box<dyn DebugGenerator + Sync + Send>>,
+        platforms: &HashMap<String, Arc<dyn PlatformDriver>>,
     ) -> Result<Device> {
         trace!("Open driver device file.");
 
-        let tlkm_dma_file = Arc::new(
-            OpenOptions::new()
-                .read(true)
-                .write(true)
-                .open(format!(
+        let tlkm_dma_file = tlkm_file
+            .open_device(
+                id,
+                &PathBuf::from(format!(
                     "{}{:02}",
                     settings
                         .get_str("tlkm.device_driver_file")
                         .context(ConfigError)?,
                     id
-                ))
-                .context(DeviceUnavailable { id: id })?,
-        );
+                )),
+            )
+            .context(DeviceUnavailable { id: id })?;
 
         trace!("Mapping status core.");
         let s = {
-            let mmap = unsafe {
-                MmapOptions::new()
-                    .len(8192)
-                    .offset(0)
-                    .map(&tlkm_dma_file)
-                    .context(DeviceUnavailable { id: id })?
-            };
+            let mmap = tlkm_dma_file
+                .map(0, 8192)
+                .context(DeviceUnavailable { id: id })?;
             trace!("Mapped status core: {}", mmap[0]);
 
             // copy the status core byte by byte from the device to avoid
@@ -292,13 +374,11 @@ impl Device {
             }),
         }?;
 
-        let platform = Arc::new(unsafe {
-            MmapOptions::new()
-                .len(platform_size as usize)
-                .offset(8192)
-                .map_mut(&tlkm_dma_file)
-                .context(DeviceUnavailable { id: id })?
-        });
+        let platform = Arc::new(
+            tlkm_dma_file
+                .map_mut(8192, platform_size as usize)
+                .context(DeviceUnavailable { id: id })?,
+        );
 
         let arch_size = match &s.arch_base {
             Some(base) => Ok(base.size),
@@ -307,84 +387,34 @@ impl Device {
             }),
         }?;
 
-        let arch = Arc::new(unsafe {
-            MmapOptions::new()
-                .len(arch_size as usize)
-                .offset(4096)
-                .map_mut(&tlkm_dma_file)
-                .context(DeviceUnavailable { id: id })?
-        });
-
-        // Initialize the global memories.
-        // Currently falls back to PCIe and Zynq allocation using the default 4GB at 0x0.
-        // This will be replaced with proper dynamic initialization after the status core
-        // has been updated to contain the required information.
-        info!("Using static memory allocation due to lack of dynamic data in the status core.");
-        let mut allocator = Vec::new();
-        let mut is_pcie = false;
-        if name == "pcie" {
-            info!("Allocating the default of 4GB at 0x0 for a PCIe platform");
-            let mut dma_offset = 0;
-            let mut dma_interrupt_read = 0;
-            let mut dma_interrupt_write = 1;
-            for comp in &s.platform {
-                if comp.name == "PLATFORM_COMPONENT_DMA0" {
-                    dma_offset = comp.offset;
-                    for v in &comp.interrupts {
-                        if v.name == "READ" {
-                            dma_interrupt_read = v.mapping as usize;
-                        } else if v.name == "WRITE" {
-                            dma_interrupt_write = v.mapping as usize;
-                        } else {
-                            trace!("Unknown DMA interrupt: {}.", v.name);
-                        }
-                    }
-                }
-            }
-            if dma_offset == 0 {
-                trace!("Could not find DMA engine.");
-                return Err(Error::DMAEngineMissing {});
-            }
+        let arch = Arc::new(
+            tlkm_dma_file
+                .map_mut(4096, arch_size as usize)
+                .context(DeviceUnavailable { id: id })?,
+        );
 
-            is_pcie = true;
+        trace!("Starting completion reactor.");
+        let reactor = Arc::new(Reactor::new().context(ReactorError)?);
 
-            allocator.push(Arc::new(OffchipMemory {
-                allocator: Mutex::new(Box::new(
-                    GenericAllocator::new(0, 4 * 1024 * 1024 * 1024, 64).context(AllocatorError)?,
-                )),
-                dma: Box::new(
-                    UserSpaceDMA::new(
-                        &tlkm_dma_file,
-                        dma_offset as usize,
-                        dma_interrupt_read,
-                        dma_interrupt_write,
-                        &platform,
-                        settings
-                            .get::<usize>("dma.read_buffer_size")
-                            .context(ConfigError)?,
-                        settings
-                            .get::<usize>("dma.read_buffers")
-                            .context(ConfigError)?,
-                        settings
-                            .get::<usize>("dma.write_buffer_size")
-                            .context(ConfigError)?,
-                        settings
-                            .get::<usize>("dma.write_buffers")
-                            .context(ConfigError)?,
-                    )
-                    .context(DMAError)?,
-                ),
-            }));
-        } else if name == "zynq" || name == "zynqmp" {
-            info!("Using driver allocation for Zynq/ZynqMP based platform.");
-            allocator.push(Arc::new(OffchipMemory {
-                allocator: Mutex::new(Box::new(
-                    DriverAllocator::new(&tlkm_dma_file).context(AllocatorError)?,
-                )),
-                dma: Box::new(DriverDMA::new(&tlkm_dma_file)),
-            }));
-        } else {
-            return Err(Error::DeviceType { name: name });
+        // Initialize the global memories using the driver of the platform.
+        let platform_driver = match platforms.get(&name) {
+            Some(x) => x,
+            None => return Err(Error::DeviceType { name: name }),
+        };
+        trace!("Using platform driver {:?}.", platform_driver);
+        let allocator = platform_driver
+            .memories(&PlatformContext {
+                status: &s,
+                device: &tlkm_dma_file,
+                platform: &platform,
+                settings: &settings,
+                reactor: &reactor,
+            })
+            .context(PlatformError)?;
+        if allocator.is_empty() {
+            return Err(Error::MemoryMissing {
+                name: "default".to_string(),
+            });
         }
 
         trace!("Initialize PE local memories.");
@@ -392,17 +422,29 @@ impl Device {
         for pe in s.pe.iter() {
             match &pe.local_memory {
                 Some(l) => {
-                    pe_local_memories.push_back(Arc::new(OffchipMemory {
-                        allocator: Mutex::new(Box::new(
-                            GenericAllocator::new(0, l.size, 1).context(AllocatorError)?,
-                        )),
-                        dma: Box::new(DirectDMA::new(l.base, l.size, arch.clone())),
-                    }));
+                    pe_local_memories.push_back(Arc::new(OffchipMemory::new(
+                        format!("{}_local", pe.name),
+                        Box::new(GenericAllocator::new(0, l.size, 1).context(AllocatorError)?),
+                        Arc::new(DirectDMA::new(l.base, l.size, arch.clone())),
+                    )));
                 }
                 None => (),
             }
         }
 
+        let backtraces = settings
+            .get_bool("memory.backtraces")
+            .context(ConfigError)?;
+        for m in allocator.iter().chain(pe_local_memories.iter()) {
+            m.allocator.lock()?.capture_backtraces(backtraces);
+        }
+
+        let policy = match settings.get_str(&format!("scheduler.{}.policy", id)) {
+            Ok(x) => x,
+            Err(_) => settings.get_str("scheduler.policy").context(ConfigError)?,
+        };
+        trace!("Using {} scheduling policy for device {}.", policy, id);
+
         trace!("Initialize PE scheduler.");
         let scheduler = Arc::new(
             Scheduler::new(
@@ -411,7 +453,10 @@ impl Device {
                 pe_local_memories,
                 &tlkm_dma_file,
                 &debug_impls,
-                is_pcie,
+                platform_driver,
+                &reactor,
+                &policy,
+                Duration::from_millis(config_int(&settings, "job.drop_timeout_ms", 0)?),
             )
             .context(SchedulerError)?,
         );
@@ -431,6 +476,7 @@ impl Device {
             tlkm_file: tlkm_file,
             tlkm_device_file: tlkm_dma_file,
             settings: settings,
+            dispatcher: Mutex::new(None),
         };
 
         device.change_access(tlkm_access::TlkmAccessMonitor)?;
@@ -454,6 +500,248 @@ impl Device {
         Ok(Job::new(pe, &self.scheduler))
     }
 
+    /// Request a PE from the device, waiting at most `timeout` for one to become free.
+    ///
+    /// Returns `None` if no PE of the given type was released in time.
+    pub fn acquire_pe_timeout(&self, id: PEId, timeout: Duration) -> Result<Option<Job>> {
+        self.check_exclusive_access()?;
+        trace!("Trying to acquire PE of type {} within {:?}.", id, timeout);
+        let pe = self
+            .scheduler
+            .acquire_pe_timeout(id, timeout)
+            .context(SchedulerError)?;
+        Ok(pe.map(|pe| Job::new(pe, &self.scheduler)))
+    }
+
+    /// Request a PE from the device with a priority and an optional timeout.
+    ///
+    /// With the `priority` scheduling policy, waiting requests with a higher priority
+    /// are served first, e.g. to let latency critical work overtake batch jobs. Returns
+    /// `None` if a timeout is given and no PE became available in time.
+    pub fn acquire_pe_with(&self, id: PEId, opts: &AcquireOptions) -> Result<Option<Job>> {
+        self.check_exclusive_access()?;
+        trace!("Trying to acquire PE of type {} with {:?}.", id, opts);
+        let pe = self
+            .scheduler
+            .acquire_pe_with(id, opts)
+            .context(SchedulerError)?;
+        Ok(pe.map(|pe| Job::new(pe, &self.scheduler)))
+    }
+
+    /// Request a PE from the device without waiting.
+    ///
+    /// Returns `None` if all PEs of the given type are currently in use.
+    pub fn try_acquire_pe(&self, id: PEId) -> Result<Option<Job>> {
+        self.check_exclusive_access()?;
+        trace!("Trying to acquire PE of type {} without waiting.", id);
+        let pe = self.scheduler.try_acquire_pe(id).context(SchedulerError)?;
+        Ok(pe.map(|pe| Job::new(pe, &self.scheduler)))
+    }
+
+    /// Queue a job for a PE of type `id` and return immediately.
+    ///
+    /// A background dispatcher waits for a free PE, starts the job and releases it.
+    /// Return value and copy back buffers are delivered through the [`JobHandle`].
+    /// Blocks while `dispatcher.queue_size` jobs are waiting, at most
+    /// `dispatcher.in_flight` jobs are executed at the same time.
+    ///
+    /// [`JobHandle`]: ../dispatcher/struct.JobHandle.html
+    pub fn submit(&self, id: PEId, args: Vec<PEParameter>) -> Result<JobHandle> {
+        self.check_submit(id)?;
+        trace!("Submitting job for PE type {}.", id);
+        self.dispatcher()?.submit(id, args).context(DispatcherError)
+    }
+
+    /// Same as [`submit`] but fails with `QueueFull` instead of waiting for room in
+    /// the queue.
+    ///
+    /// [`submit`]: #method.submit
+    pub fn try_submit(&self, id: PEId, args: Vec<PEParameter>) -> Result<JobHandle> {
+        self.check_submit(id)?;
+        self.dispatcher()?
+            .try_submit(id, args)
+            .context(DispatcherError)
+    }
+
+    fn check_submit(&self, id: PEId) -> Result<()> {
+        self.check_exclusive_access()?;
+        if self.scheduler.num_pes(id) == 0 {
+            return Err(Error::SchedulerError {
+                source: crate::scheduler::Error::NoSuchPE { id: id },
+            });
+        }
+        if self.scheduler.healthy_pes(id).context(SchedulerError)? == 0 {
+            return Err(Error::SchedulerError {
+                source: crate::scheduler::Error::AllFaulty { id: id },
+            });
+        }
+        Ok(())
+    }
+
+    /// The dispatcher is started on the first submission.
+    fn dispatcher(&self) -> Result<Arc<Dispatcher>> {
+        let mut d = self.dispatcher.lock()?;
+        if let Some(x) = d.as_ref() {
+            return Ok(x.clone());
+        }
+        let (queue_size, in_flight) = self.dispatcher_config()?;
+        let x = Arc::new(
+            Dispatcher::new(&self.scheduler, queue_size, in_flight).context(DispatcherError)?,
+        );
+        *d = Some(x.clone());
+        Ok(x)
+    }
+
+    /// Notify `signal` whenever a PE of this device might have become free.
+    pub(crate) fn add_listener(&self, signal: &Arc<ReleaseSignal>) -> Result<()> {
+        self.scheduler.add_listener(signal).context(SchedulerError)
+    }
+
+    /// Queue size and number of concurrent jobs of the dispatcher.
+    pub(crate) fn dispatcher_config(&self) -> Result<(usize, usize)> {
+        Ok((
+            config_int(&self.settings, "dispatcher.queue_size", 1)? as usize,
+            config_int(&self.settings, "dispatcher.in_flight", 1)? as usize,
+        ))
+    }
+
+    /// Request a PE of any of the given types, e.g. for bitstreams with several
+    /// implementations of the same function. Blocks until one is free.
+    ///
+    /// Types are preferred in the given order if PEs of several types are free.
+    /// Use [`Job::type_id`] to find out which type has been acquired.
+    ///
+    /// [`Job::type_id`]: ../job/struct.Job.html#method.type_id
+    pub fn acquire_any(&self, ids: &[PEId]) -> Result<Job> {
+        self.check_exclusive_access()?;
+        trace!("Trying to acquire any PE of types {:?}.", ids);
+        let pe = self.scheduler.acquire_any(ids).context(SchedulerError)?;
+        trace!("Successfully acquired PE of type {}.", pe.type_id());
+        Ok(Job::new(pe, &self.scheduler))
+    }
+
+    /// Same as [`acquire_any`] but waits at most `timeout` for a PE to become free.
+    ///
+    /// [`acquire_any`]: #method.acquire_any
+    pub fn acquire_any_timeout(&self, ids: &[PEId], timeout: Duration) -> Result<Option<Job>> {
+        self.check_exclusive_access()?;
+        trace!(
+            "Trying to acquire any PE of types {:?} within {:?}.",
+            ids,
+            timeout
+        );
+        let pe = self
+            .scheduler
+            .acquire_any_timeout(ids, timeout)
+            .context(SchedulerError)?;
+        Ok(pe.map(|pe| Job::new(pe, &self.scheduler)))
+    }
+
+    /// Same as [`acquire_any`] but does not wait if all PEs are in use.
+    ///
+    /// [`acquire_any`]: #method.acquire_any
+    pub fn try_acquire_any(&self, ids: &[PEId]) -> Result<Option<Job>> {
+        self.check_exclusive_access()?;
+        trace!(
+            "Trying to acquire any PE of types {:?} without waiting.",
+            ids
+        );
+        let pe = self
+            .scheduler
+            .try_acquire_any(ids)
+            .context(SchedulerError)?;
+        Ok(pe.map(|pe| Job::new(pe, &self.scheduler)))
+    }
+
+    /// Request several PEs at once, e.g. for a pipeline that needs all of them running
+    /// at the same time.
+    ///
+    /// # Arguments
+    ///   * pes: Pairs of PE type and the number of PEs of that type.
+    ///
+    /// Blocks until all PEs are available. No PE is held while waiting, so threads
+    /// requesting overlapping sets do not deadlock. The jobs are returned in the
+    /// order of the request.
+    pub fn acquire_pes(&self, pes: &[(PEId, usize)]) -> Result<Vec<Job>> {
+        self.check_exclusive_access()?;
+        trace!("Trying to acquire PEs {:?}.", pes);
+        let pes = self.scheduler.acquire_pes(pes).context(SchedulerError)?;
+        trace!("Successfully acquired {} PEs.", pes.len());
+        Ok(pes
+            .into_iter()
+            .map(|pe| Job::new(pe, &self.scheduler))
+            .collect())
+    }
+
+    /// Same as [`acquire_pes`] but waits at most `timeout` for all PEs to become free.
+    ///
+    /// [`acquire_pes`]: #method.acquire_pes
+    pub fn acquire_pes_timeout(
+        &self,
+        pes: &[(PEId, usize)],
+        timeout: Duration,
+    ) -> Result<Option<Vec<Job>>> {
+        self.check_exclusive_access()?;
+        trace!("Trying to acquire PEs {:?} within {:?}.", pes, timeout);
+        let pes = self
+            .scheduler
+            .acquire_pes_timeout(pes, timeout)
+            .context(SchedulerError)?;
+        Ok(pes.map(|pes| {
+            pes.into_iter()
+                .map(|pe| Job::new(pe, &self.scheduler))
+                .collect()
+        }))
+    }
+
+    /// Request the PE instance `slot` from the device, blocks until it is free.
+    ///
+    /// In contrast to [`acquire_pe`] this always returns the same PE, e.g. to make use of
+    /// state kept in its local memory. Valid slots are listed by [`pe_instances`].
+    ///
+    /// [`acquire_pe`]: #method.acquire_pe
+    /// [`pe_instances`]: #method.pe_instances
+    pub fn acquire_pe_instance(&self, slot: usize) -> Result<Job> {
+        self.check_exclusive_access()?;
+        trace!("Trying to acquire PE instance {}.", slot);
+        let pe = self
+            .scheduler
+            .acquire_pe_instance(slot)
+            .context(SchedulerError)?;
+        trace!("Successfully acquired PE instance {}.", slot);
+        Ok(Job::new(pe, &self.scheduler))
+    }
+
+    /// Request the PE instance `slot`, waiting at most `timeout` for it to become free.
+    pub fn acquire_pe_instance_timeout(
+        &self,
+        slot: usize,
+        timeout: Duration,
+    ) -> Result<Option<Job>> {
+        self.check_exclusive_access()?;
+        trace!(
+            "Trying to acquire PE instance {} within {:?}.",
+            slot,
+            timeout
+        );
+        let pe = self
+            .scheduler
+            .acquire_pe_instance_timeout(slot, timeout)
+            .context(SchedulerError)?;
+        Ok(pe.map(|pe| Job::new(pe, &self.scheduler)))
+    }
+
+    /// Request the PE instance `slot` without waiting.
+    pub fn try_acquire_pe_instance(&self, slot: usize) -> Result<Option<Job>> {
+        self.check_exclusive_access()?;
+        trace!("Trying to acquire PE instance {} without waiting.", slot);
+        let pe = self
+            .scheduler
+            .try_acquire_pe_instance(slot)
+            .context(SchedulerError)?;
+        Ok(pe.map(|pe| Job::new(pe, &self.scheduler)))
+    }
+
     fn check_exclusive_access(&self) -> Result<()> {
         if self.access != tlkm_access::TlkmAccessExclusive {
             Err(Error::ExclusiveRequired {})
@@ -475,6 +763,8 @@ impl Device {
             return Ok(());
         }
 
+        // Finish submitted jobs while the access mode still allows it.
+        self.dispatcher.lock()?.take();
         self.destroy()?;
 
         let mut request = tlkm_ioctl_device_cmd {
@@ -484,12 +774,12 @@ impl Device {
 
         trace!("Device {}: Trying to change mode to {:?}", self.id, access,);
 
-        unsafe {
-            tlkm_ioctl_create(self.tlkm_file.as_raw_fd(), &mut request).context(IOCTLCreate {
+        self.tlkm_file
+            .create_device(&mut request)
+            .context(IOCTLCreate {
                 access: access,
                 id: self.id,
             })?;
-        };
 
         self.access = access;
 
@@ -510,10 +800,9 @@ impl Device {
                 dev_id: self.id,
                 access: self.access,
             };
-            unsafe {
-                tlkm_ioctl_destroy(self.tlkm_file.as_raw_fd(), &mut request)
-                    .context(IOCTLDestroy { id: self.id })?;
-            }
+            self.tlkm_file
+                .destroy_device(&mut request)
+                .context(IOCTLDestroy { id: self.id })?;
             self.access = tlkm_access::TlkmAccessTypes;
         }
 
@@ -572,13 +861,168 @@ impl Device {
         Ok(self.offchip_memory[0].clone())
     }
 
+    /// Return all off-chip memories of the device. The index of a memory in this list
+    /// is stable for the lifetime of the device, the first entry is the default memory.
+    pub fn memories(&self) -> &[Arc<OffchipMemory>] {
+        &self.offchip_memory
+    }
+
+    /// Return the off-chip memory with the given name, e.g. `HBM3`.
+    pub fn memory(&self, name: &str) -> Result<Arc<OffchipMemory>> {
+        match self.offchip_memory.iter().find(|m| m.name == name) {
+            Some(m) => Ok(m.clone()),
+            None => Err(Error::MemoryMissing {
+                name: name.to_string(),
+            }),
+        }
+    }
+
     /// Return the number of PEs of a given ID in the bitstream.
     pub fn num_pes(&self, pe: PEId) -> usize {
         self.scheduler.num_pes(pe)
     }
 
+    /// Return the number of PEs of a given ID that have not been taken out of service.
+    pub fn healthy_pes(&self, pe: PEId) -> Result<usize> {
+        self.scheduler.healthy_pes(pe).context(SchedulerError)
+    }
+
+    /// Return the number of requests currently waiting for a PE of type `id`.
+    pub fn waiting(&self, id: PEId) -> Result<usize> {
+        self.scheduler.waiting(id).context(SchedulerError)
+    }
+
+    /// Return the number of PEs of every type in the bitstream.
+    pub fn pe_types(&self) -> HashMap<PEId, usize> {
+        self.scheduler.pe_types()
+    }
+
     /// Return the PEId of the PE with the given name
     pub fn get_pe_id(&self, name: &str) -> Result<PEId> {
         self.scheduler.get_pe_id(name).context(SchedulerError)
     }
+
+    /// Utilization of every PE instance and PE type since the device has been created
+    /// or [`reset_stats`] has been called.
+    ///
+    /// [`reset_stats`]: #method.reset_stats
+    pub fn stats(&self) -> Result<SchedulerStats> {
+        self.scheduler.stats().context(SchedulerError)
+    }
+
+    pub fn reset_stats(&self) -> Result<()> {
+        self.scheduler.reset_stats().context(SchedulerError)
+    }
+
+    /// Describe all instances of the given PE type, including their slot, interrupt
+    /// and local memory.
+    pub fn pe_instances(&self, id: PEId) -> Result<Vec<PEInstance>> {
+        self.scheduler.pe_instances(id).context(SchedulerError)
+    }
+
+    /// Health of all PE instances, indexed by the position of the PE in the status core.
+    pub fn pe_health(&self) -> Result<Vec<PEHealth>> {
+        self.scheduler.pe_health().context(SchedulerError)
+    }
+
+    /// Return the instances of all PEs that have been taken out of service.
+    pub fn faulty_pes(&self) -> Result<Vec<usize>> {
+        self.scheduler.faulty_pes().context(SchedulerError)
+    }
+
+    /// Put the faulty PE instance `slot` back into service if it is idle or can be reset.
+    pub fn reset_faulty_pe(&self, slot: usize) -> Result<()> {
+        self.check_exclusive_access()?;
+        self.scheduler.reset_faulty_pe(slot).context(SchedulerError)
+    }
+}
+
+#[cfg(test)]
+mod device_tests {
+    use crate::device::{DataTransferAlloc, PEParameter};
+    use crate::sim::testing::{init, Result};
+    use crate::sim::{PEContext, SimulatedDevice, SimulatedPE, SimulatedTLKM};
+    use crate::tlkm::{tlkm_access, TLKM};
+    use std::collections::HashMap;
+    use std::sync::Arc;
+
+    #[test]
+    fn memories_from_status() -> Result<()> {
+        let tlkm = TLKM::with_driver(Arc::new(SimulatedTLKM::new(vec![SimulatedDevice::new(
+            "pcie",
+        )
+        .pe(SimulatedPE::new(14, "counter", |_: &mut PEContext| 0), 1)
+        .memory("DDR", 0x1_0000_0000, 1024 * 1024 * 1024)])))?;
+        let dev = tlkm.device_alloc(0, &HashMap::new())?;
+        let mem = dev.default_memory()?;
+        let addr = mem.allocator().lock().unwrap().allocate(4096)?;
+        assert_eq!(addr, 0x1_0000_0000);
+        let len = 4096;
+        let data: Vec<u8> = (0..len).map(|x| (x % 7) as u8).collect();
+        let mut back = vec![0u8; len];
+        mem.dma().copy_to(&data, addr)?;
+        mem.dma().copy_from(addr, &mut back)?;
+        assert!(data == back);
+        Ok(())
+    }
+
+    #[test]
+    fn named_memories() -> Result<()> {
+        let tlkm = TLKM::with_driver(Arc::new(SimulatedTLKM::new(vec![SimulatedDevice::new(
+            "pcie",
+        )
+        .pe(SimulatedPE::new(14, "counter", |_: &mut PEContext| 0), 1)
+        .memory("DDR", 0, 1024 * 1024 * 1024)
+        .memory("HBM3", 0x2_0000_0000, 256 * 1024 * 1024)])))?;
+        let dev = tlkm.device_alloc(0, &HashMap::new())?;
+        let names: Vec<&str> = dev.memories().iter().map(|m| m.name().as_str()).collect();
+        assert_eq!(names, vec!["DDR", "HBM3"]);
+        assert_eq!(dev.default_memory()?.name(), "DDR");
+        let hbm = dev.memory("HBM3")?;
+        assert_eq!(hbm.allocator().lock().unwrap().allocate(64)?, 0x2_0000_0000);
+        assert!(dev.memory("HBM4").is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn leak_tracking() -> Result<()> {
+        let tlkm = init()?;
+        let mut dev = tlkm.device_alloc(0, &HashMap::new())?;
+        dev.change_access(tlkm_access::TlkmAccessExclusive)?;
+        let mem = dev.default_memory()?;
+        let a = mem
+            .allocator()
+            .lock()
+            .unwrap()
+            .allocate_tagged(128, None, Some("input"), None)?;
+        let _ = mem.allocator().lock().unwrap().allocate(64)?;
+
+        let mut job = dev.acquire_pe(14)?;
+        let id = job.id();
+        job.start(vec![
+            PEParameter::DataTransferAlloc(DataTransferAlloc {
+                data: vec![0u8; 256].into_boxed_slice(),
+                from_device: false,
+                to_device: true,
+                free: false,
+                memory: mem.clone(),
+                fixed: None,
+            }),
+            PEParameter::Single64(256),
+        ])?;
+        job.release(true, false)?;
+
+        {
+            let alloc = mem.allocator().lock().unwrap();
+            let live = alloc.live();
+            assert_eq!(live.len(), 3);
+            assert_eq!(live[0].0, a);
+            assert_eq!(live[0].1.tag.as_deref(), Some("input"));
+            assert!(live.iter().any(|(_, p)| p.job == Some(id) && p.size == 256));
+            alloc.report_leaks(mem.name());
+        }
+        assert_eq!(mem.free_all()?, 3);
+        assert_eq!(mem.stats()?.allocations, 0);
+        Ok(())
+    }
 }
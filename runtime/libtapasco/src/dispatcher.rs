@@ -0,0 +1,498 @@
+/*
+ * Copyright (c) 2014-2020 Embedded Systems and Applications, TU Darmstadt.
+ *
+ * This file is part of TaPaSCo
+ * (see https://github.com/esa-tu-darmstadt/tapasco).
+ *
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU Lesser General Public License as published by
+ * the Free Software Foundation, either version 3 of the License, or
+ * (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU Lesser General Public License for more details.
+ *
+ * You should have received a copy of the GNU Lesser General Public License
+ * along with this program. If not, see <http://www.gnu.org/licenses/>.
+ */
+
+use crate::device::PEParameter;
+use crate::job::Job;
+use crate::pe::PEId;
+use crate::scheduler::{ReleaseSignal, Scheduler};
+use crossbeam::channel::{bounded, unbounded, Receiver, RecvTimeoutError, Sender, TryRecvError};
+use snafu::ResultExt;
+use std::collections::{HashSet, VecDeque};
+use std::sync::Arc;
+use std::sync::{Condvar, Mutex};
+use std::thread;
+use std::time::Duration;
+
+#[derive(Debug, Snafu)]
+pub enum Error {
+    #[snafu(display("Job Error: {}", source))]
+    JobError { source: crate::job::Error },
+
+    #[snafu(display("Scheduler Error: {}", source))]
+    SchedulerError { source: crate::scheduler::Error },
+
+    #[snafu(display("Device Pool Error: {}", source))]
+    PoolError { source: Box<crate::pool::Error> },
+
+    #[snafu(display("The job queue is full, the job has not been submitted."))]
+    QueueFull { args: Vec<PEParameter> },
+
+    #[snafu(display("The dispatcher has been shut down."))]
+    Shutdown {},
+
+    #[snafu(display("Could not create dispatcher thread: {}", source))]
+    ThreadCreate { source: std::io::Error },
+
+    #[snafu(display("Mutex has been poisoned"))]
+    MutexError {},
+}
+
+type Result<T, E = Error> = std::result::Result<T, E>;
+
+impl<T> From<std::sync::PoisonError<T>> for Error {
+    fn from(_error: std::sync::PoisonError<T>) -> Self {
+        Error::MutexError {}
+    }
+}
+
+/// Return value and copy back buffers of a finished job, see `Job::release`.
+pub type JobResult = Result<(u64, Vec<Box<[u8]>>)>;
+
+/// Starts a job on an acquired PE and waits for it to finish.
+pub type Run = Box<dyn FnOnce(Vec<PEParameter>) -> JobResult + Send>;
+
+/// Acquires a PE of the given type without blocking and returns how to run a job on
+/// it, or `None` if no PE is free right now.
+pub type Executor = Arc<dyn Fn(PEId) -> Result<Option<Run>> + Send + Sync>;
+
+#[derive(Debug)]
+struct Request {
+    id: PEId,
+    args: Vec<PEParameter>,
+    result: Sender<JobResult>,
+}
+
+#[derive(Debug, Default)]
+struct Queue {
+    pending: VecDeque<Request>,
+    running: usize,
+    shutdown: bool,
+}
+
+#[derive(Debug)]
+struct Shared {
+    queue: Mutex<Queue>,
+    /// Notified when queued jobs have been started.
+    space: Condvar,
+    /// Wakes the dispatching thread on new jobs, finished jobs and released PEs.
+    signal: Arc<ReleaseSignal>,
+    queue_size: usize,
+    in_flight: usize,
+}
+
+/// Runs submitted jobs in the background.
+///
+/// Jobs are kept in a bounded queue. Submitting blocks while the queue is full, which
+/// slows down producers that are faster than the PEs. A dispatching thread hands a job
+/// to one of the worker threads as soon as a PE for it has been acquired, so at most
+/// `in_flight` jobs are executed at the same time. Jobs for the same PE type are started
+/// in order, jobs for a type without a free PE do not hold up other types.
+///
+/// Dropping the dispatcher waits until all queued jobs have been executed.
+#[derive(Debug)]
+pub struct Dispatcher {
+    shared: Arc<Shared>,
+    threads: Vec<thread::JoinHandle<()>>,
+}
+
+impl Drop for Dispatcher {
+    fn drop(&mut self) {
+        trace!("Stopping dispatcher.");
+        match self.shared.queue.lock() {
+            Ok(mut q) => q.shutdown = true,
+            Err(_) => error!("Dispatcher queue has been poisoned."),
+        }
+        self.shared.space.notify_all();
+        if let Err(e) = self.shared.signal.notify() {
+            error!("Could not wake dispatcher: {}", e);
+        }
+        for t in self.threads.drain(..) {
+            if t.join().is_err() {
+                error!("Dispatcher thread panicked.");
+            }
+        }
+    }
+}
+
+impl Dispatcher {
+    pub fn new(
+        scheduler: &Arc<Scheduler>,
+        queue_size: usize,
+        in_flight: usize,
+    ) -> Result<Dispatcher> {
+        let signal = Arc::new(ReleaseSignal::default());
+        scheduler.add_listener(&signal).context(SchedulerError)?;
+        let scheduler = scheduler.clone();
+        Dispatcher::with_executor(
+            Arc::new(move |id| Dispatcher::acquire(&scheduler, id)),
+            signal,
+            queue_size,
+            in_flight,
+        )
+    }
+
+    /// Run jobs with a custom `executor`, e.g. to pick the PE from several devices.
+    ///
+    /// `signal` has to be notified whenever a PE might have become free.
+    pub fn with_executor(
+        executor: Executor,
+        signal: Arc<ReleaseSignal>,
+        queue_size: usize,
+        in_flight: usize,
+    ) -> Result<Dispatcher> {
+        trace!(
+            "Starting dispatcher with {} workers and room for {} jobs.",
+            in_flight,
+            queue_size
+        );
+        let mut d = Dispatcher {
+            shared: Arc::new(Shared {
+                queue: Mutex::new(Queue::default()),
+                space: Condvar::new(),
+                signal: signal,
+                queue_size: std::cmp::max(1, queue_size),
+                in_flight: std::cmp::max(1, in_flight),
+            }),
+            threads: Vec::new(),
+        };
+        // Dropped before `d` if a thread can not be created, which stops the workers.
+        let (jobs, started) = unbounded();
+        for i in 0..d.shared.in_flight {
+            let shared = d.shared.clone();
+            let started: Receiver<(Run, Request)> = started.clone();
+            let w = thread::Builder::new()
+                .name(format!("tapasco-dispatcher-{}", i))
+                .spawn(move || Dispatcher::work(&shared, &started))
+                .context(ThreadCreate)?;
+            d.threads.push(w);
+        }
+        let shared = d.shared.clone();
+        let t = thread::Builder::new()
+            .name("tapasco-dispatcher".to_string())
+            .spawn(move || {
+                if let Err(e) = Dispatcher::dispatch(&shared, &executor, &jobs) {
+                    error!("Dispatcher failed: {}", e);
+                }
+            })
+            .context(ThreadCreate)?;
+        d.threads.push(t);
+        Ok(d)
+    }
+
+    /// Start queued jobs whenever PEs and workers are free, until the dispatcher is
+    /// dropped and the queue is empty.
+    fn dispatch(shared: &Shared, executor: &Executor, jobs: &Sender<(Run, Request)>) -> Result<()> {
+        loop {
+            let seen = shared.signal.generation().context(SchedulerError)?;
+            {
+                let mut q = shared.queue.lock()?;
+                if q.shutdown && q.pending.is_empty() {
+                    return Ok(());
+                }
+                if Dispatcher::start_jobs(shared, &mut q, executor, jobs) {
+                    shared.space.notify_all();
+                }
+            }
+            shared.signal.wait(seen, None).context(SchedulerError)?;
+        }
+    }
+
+    /// Acquire PEs for queued jobs, oldest first, and pass them to the workers.
+    /// Returns `true` if jobs have been removed from the queue.
+    fn start_jobs(
+        shared: &Shared,
+        q: &mut Queue,
+        executor: &Executor,
+        jobs: &Sender<(Run, Request)>,
+    ) -> bool {
+        let mut unavailable = HashSet::new();
+        let mut removed = false;
+        let mut i = 0;
+        while i < q.pending.len() && q.running < shared.in_flight {
+            let id = q.pending[i].id;
+            if unavailable.contains(&id) {
+                i += 1;
+                continue;
+            }
+            match executor(id) {
+                Ok(Some(run)) => {
+                    let r = q.pending.remove(i).unwrap();
+                    q.running += 1;
+                    removed = true;
+                    if let Err(e) = jobs.send((run, r)) {
+                        error!("Dispatcher workers are gone: {}", e);
+                    }
+                }
+                Ok(None) => {
+                    unavailable.insert(id);
+                    i += 1;
+                }
+                Err(e) => {
+                    let r = q.pending.remove(i).unwrap();
+                    removed = true;
+                    if r.result.send(Err(e)).is_err() {
+                        trace!("Handle of job on PE type {} has been dropped.", r.id);
+                    }
+                }
+            }
+        }
+        removed
+    }
+
+    fn work(shared: &Shared, started: &Receiver<(Run, Request)>) {
+        for (run, r) in started.iter() {
+            let result = run(r.args);
+            if r.result.send(result).is_err() {
+                trace!("Handle of job on PE type {} has been dropped.", r.id);
+            }
+            match shared.queue.lock() {
+                Ok(mut q) => q.running -= 1,
+                Err(_) => error!("Dispatcher queue has been poisoned."),
+            }
+            if let Err(e) = shared.signal.notify() {
+                error!("Could not wake dispatcher: {}", e);
+            }
+        }
+    }
+
+    fn acquire(scheduler: &Arc<Scheduler>, id: PEId) -> Result<Option<Run>> {
+        match scheduler.try_acquire_pe(id).context(SchedulerError)? {
+            Some(pe) => {
+                let job = Job::new(pe, scheduler);
+                Ok(Some(Box::new(move |args| Dispatcher::execute(job, args))))
+            }
+            None => {
+                // Nobody would wake the dispatcher for this type again.
+                if scheduler.healthy_pes(id).context(SchedulerError)? == 0 {
+                    return Err(Error::SchedulerError {
+                        source: crate::scheduler::Error::AllFaulty { id: id },
+                    });
+                }
+                Ok(None)
+            }
+        }
+    }
+
+    /// Start the job on the acquired PE and release it once it has finished.
+    pub(crate) fn execute(mut job: Job, args: Vec<PEParameter>) -> JobResult {
+        job.start(args).context(JobError)?;
+        job.release(true, true).context(JobError)
+    }
+
+    /// Queue a job for a PE of type `id`, blocks while the queue is full.
+    pub fn submit(&self, id: PEId, args: Vec<PEParameter>) -> Result<JobHandle> {
+        let (request, handle) = Dispatcher::request(id, args);
+        {
+            let mut q = self.shared.queue.lock()?;
+            while !q.shutdown && q.pending.len() >= self.shared.queue_size {
+                q = self.shared.space.wait(q)?;
+            }
+            ensure!(!q.shutdown, Shutdown {});
+            q.pending.push_back(request);
+        }
+        self.shared.signal.notify().context(SchedulerError)?;
+        Ok(handle)
+    }
+
+    /// Queue a job without waiting. Returns the arguments in `Error::QueueFull` if
+    /// the queue is full.
+    pub fn try_submit(&self, id: PEId, args: Vec<PEParameter>) -> Result<JobHandle> {
+        let (request, handle) = Dispatcher::request(id, args);
+        {
+            let mut q = self.shared.queue.lock()?;
+            ensure!(!q.shutdown, Shutdown {});
+            if q.pending.len() >= self.shared.queue_size {
+                return Err(Error::QueueFull { args: request.args });
+            }
+            q.pending.push_back(request);
+        }
+        self.shared.signal.notify().context(SchedulerError)?;
+        Ok(handle)
+    }
+
+    /// Number of jobs waiting for a PE or a worker.
+    pub fn queued(&self) -> usize {
+        match self.shared.queue.lock() {
+            Ok(q) => q.pending.len(),
+            Err(_) => 0,
+        }
+    }
+
+    fn request(id: PEId, args: Vec<PEParameter>) -> (Request, JobHandle) {
+        let (result, receiver) = bounded(1);
+        (
+            Request {
+                id: id,
+                args: args,
+                result: result,
+            },
+            JobHandle { result: receiver },
+        )
+    }
+}
+
+/// Result of a job submitted through [`Device.submit`].
+///
+/// Dropping the handle does not cancel the job, its result is discarded.
+///
+/// [`Device.submit`]: ../device/struct.Device.html#method.submit
+#[derive(Debug)]
+pub struct JobHandle {
+    result: Receiver<JobResult>,
+}
+
+impl JobHandle {
+    /// Block until the job has finished.
+    pub fn wait(self) -> JobResult {
+        match self.result.recv() {
+            Ok(r) => r,
+            Err(_) => Err(Error::Shutdown {}),
+        }
+    }
+
+    /// Wait at most `timeout` for the job. Returns `None` if it is still pending.
+    pub fn wait_timeout(&self, timeout: Duration) -> Option<JobResult> {
+        match self.result.recv_timeout(timeout) {
+            Ok(r) => Some(r),
+            Err(RecvTimeoutError::Timeout) => None,
+            Err(RecvTimeoutError::Disconnected) => Some(Err(Error::Shutdown {})),
+        }
+    }
+
+    /// Returns the result if the job has finished, `None` otherwise.
+    pub fn try_wait(&self) -> Option<JobResult> {
+        match self.result.try_recv() {
+            Ok(r) => Some(r),
+            Err(TryRecvError::Empty) => None,
+            Err(TryRecvError::Disconnected) => Some(Err(Error::Shutdown {})),
+        }
+    }
+}
+
+#[cfg(test)]
+mod dispatcher_tests {
+    use crate::device::{DataTransferAlloc, PEParameter};
+    use crate::sim::testing::Result;
+    use crate::sim::{PEContext, SimulatedDevice, SimulatedPE, SimulatedTLKM};
+    use crate::tlkm::{tlkm_access, TLKM};
+    use std::collections::HashMap;
+    use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+    use std::sync::Arc;
+    use std::thread;
+    use std::time::Duration;
+
+    #[test]
+    fn submit() -> Result<()> {
+        let started = Arc::new(AtomicUsize::new(0));
+        let open = Arc::new(AtomicBool::new(false));
+        let (s, o) = (started.clone(), open.clone());
+        // Jobs with a second argument of 1 wait until `open` is set.
+        let pe = SimulatedPE::new(12, "sim:double", move |pe: &mut PEContext| {
+            s.fetch_add(1, Ordering::SeqCst);
+            while pe.arg(1) == 1 && !o.load(Ordering::SeqCst) {
+                thread::sleep(Duration::from_millis(1));
+            }
+            pe.arg(0) * 2
+        });
+        let sim = SimulatedTLKM::new(vec![SimulatedDevice::new("pcie").pe(pe, 2)]);
+        let mut tlkm = TLKM::with_driver(Arc::new(sim))?;
+        tlkm.set_config("dispatcher.queue_size", "1")?;
+        tlkm.set_config("dispatcher.in_flight", "1")?;
+        let mut dev = tlkm.device_alloc(0, &HashMap::new())?;
+        dev.change_access(tlkm_access::TlkmAccessExclusive)?;
+        let mem = dev.default_memory()?;
+
+        assert!(dev.submit(42, vec![]).is_err());
+
+        // One job in flight, one queued, no room for a third.
+        let first = dev.submit(12, vec![PEParameter::Single64(1), PEParameter::Single64(1)])?;
+        while started.load(Ordering::SeqCst) == 0 {
+            thread::yield_now();
+        }
+        let second = dev.submit(12, vec![PEParameter::Single64(2), PEParameter::Single64(0)])?;
+        match dev.try_submit(12, vec![PEParameter::Single64(3)]) {
+            Err(crate::device::Error::DispatcherError {
+                source: crate::dispatcher::Error::QueueFull { args },
+            }) => assert_eq!(args.len(), 1),
+            r => panic!("Expected full queue, got {:?}", r),
+        }
+        assert!(first.try_wait().is_none());
+        open.store(true, Ordering::SeqCst);
+        assert_eq!(first.wait()?.0, 2);
+        assert_eq!(second.wait()?.0, 4);
+
+        let handles = (0..8)
+            .map(|i| {
+                dev.submit(
+                    12,
+                    vec![
+                        PEParameter::Single64(i),
+                        PEParameter::Single64(0),
+                        PEParameter::DataTransferAlloc(DataTransferAlloc {
+                            data: vec![i as u8; 16].into_boxed_slice(),
+                            from_device: true,
+                            to_device: true,
+                            free: true,
+                            memory: mem.clone(),
+                            fixed: None,
+                        }),
+                    ],
+                )
+            })
+            .collect::<std::result::Result<Vec<_>, _>>()?;
+        for (i, h) in handles.into_iter().enumerate() {
+            let (rv, buffers) = h.wait_timeout(Duration::from_secs(10)).unwrap()?;
+            assert_eq!(rv, 2 * i as u64);
+            assert!(buffers[0].iter().all(|x| *x == i as u8));
+        }
+        assert_eq!(dev.stats()?.types[&12].jobs, 10);
+        Ok(())
+    }
+
+    #[test]
+    fn submit_mixed_types() -> Result<()> {
+        let a = SimulatedPE::new(12, "sim:a", |_: &mut PEContext| 1);
+        let b = SimulatedPE::new(13, "sim:b", |_: &mut PEContext| 2);
+        let sim = SimulatedTLKM::new(vec![SimulatedDevice::new("pcie").pe(a, 1).pe(b, 1)]);
+        let mut tlkm = TLKM::with_driver(Arc::new(sim))?;
+        tlkm.set_config("dispatcher.in_flight", "1")?;
+        let mut dev = tlkm.device_alloc(0, &HashMap::new())?;
+        dev.change_access(tlkm_access::TlkmAccessExclusive)?;
+
+        // A job for a busy PE type does not hold up the only worker.
+        let held = dev.acquire_pe(12)?;
+        let blocked = dev.submit(12, vec![])?;
+        let other = dev.submit(13, vec![])?;
+        assert_eq!(other.wait_timeout(Duration::from_secs(10)).unwrap()?.0, 2);
+        assert!(blocked.try_wait().is_none());
+        drop(held);
+        assert_eq!(blocked.wait_timeout(Duration::from_secs(10)).unwrap()?.0, 1);
+        drop(dev);
+
+        tlkm.set_config("dispatcher.in_flight", "-1")?;
+        let mut dev = tlkm.device_alloc(0, &HashMap::new())?;
+        dev.change_access(tlkm_access::TlkmAccessExclusive)?;
+        match dev.submit(12, vec![]) {
+            Err(crate::device::Error::ConfigError { .. }) => (),
+            r => panic!("Expected configuration error, got {:?}", r.map(|_| ())),
+        }
+        Ok(())
+    }
+}
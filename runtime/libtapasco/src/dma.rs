@@ -22,14 +22,16 @@ use crate::device::DeviceAddress;
 use crate::device::DeviceSize;
 use crate::tlkm::tlkm_copy_cmd_from;
 use crate::tlkm::tlkm_copy_cmd_to;
-use crate::tlkm::tlkm_ioctl_copy_from;
-use crate::tlkm::tlkm_ioctl_copy_to;
+use crate::tlkm::DeviceDriver;
 use core::fmt::Debug;
 use memmap::MmapMut;
 use snafu::ResultExt;
-use std::fs::File;
-use std::os::unix::prelude::*;
+use std::future::Future;
+use std::pin::Pin;
 use std::sync::Arc;
+use std::task::{Context, Poll, Wake, Waker};
+use std::thread;
+use std::time::{Duration, Instant};
 
 #[derive(Debug, Snafu)]
 #[snafu(visibility(pub))]
@@ -71,24 +73,162 @@ pub enum Error {
         "Got interrupt but outstanding buffers are empty. This should never happen."
     ))]
     TooManyInterrupts {},
+
+    #[snafu(display("A striped transfer thread panicked."))]
+    StripeThreadPanic {},
 }
 type Result<T, E = Error> = std::result::Result<T, E>;
 
 /// Specifies a method to interact with DMA methods
 ///
 /// The methods will block and the transfer is assumed complete when they return.
+/// The asynchronous variants return a [`DMAHandle`] instead. By default they perform the
+/// transfer synchronously and return a completed handle.
+///
+/// [`DMAHandle`]: struct.DMAHandle.html
 pub trait DMAControl: Debug {
     fn copy_to(&self, data: &[u8], ptr: DeviceAddress) -> Result<()>;
     fn copy_from(&self, ptr: DeviceAddress, data: &mut [u8]) -> Result<()>;
+
+    fn copy_to_async<'a>(&'a self, data: &'a [u8], ptr: DeviceAddress) -> Result<DMAHandle<'a>> {
+        self.copy_to(data, ptr)?;
+        Ok(DMAHandle::completed())
+    }
+
+    fn copy_from_async<'a>(
+        &'a self,
+        ptr: DeviceAddress,
+        data: &'a mut [u8],
+    ) -> Result<DMAHandle<'a>> {
+        self.copy_from(ptr, data)?;
+        Ok(DMAHandle::completed())
+    }
+}
+
+/// State of an outstanding asynchronous transfer.
+///
+/// Implemented by the DMA engines that support asynchronous transfers.
+pub trait DMAProgress: Debug + Send {
+    /// Advance the transfer without blocking. Returns `true` once it has completed.
+    ///
+    /// If the transfer is not done yet, `waker` is woken once further progress is possible.
+    fn progress(&mut self, waker: &Waker) -> Result<bool>;
+}
+
+/// Handle of an asynchronous transfer started by `copy_to_async` or `copy_from_async`.
+///
+/// The host buffer stays borrowed until the handle is dropped. The transfer advances
+/// whenever the handle is polled, waited on or awaited. Dropping an unfinished handle
+/// does not start the remaining parts of the transfer.
+#[derive(Debug)]
+pub struct DMAHandle<'a> {
+    progress: Option<Box<dyn DMAProgress + 'a>>,
+}
+
+struct ThreadWaker(thread::Thread);
+
+impl Wake for ThreadWaker {
+    fn wake(self: Arc<Self>) {
+        self.0.unpark();
+    }
+}
+
+impl<'a> DMAHandle<'a> {
+    pub fn new(progress: Box<dyn DMAProgress + 'a>) -> DMAHandle<'a> {
+        DMAHandle {
+            progress: Some(progress),
+        }
+    }
+
+    /// A handle for a transfer that finished already.
+    pub fn completed() -> DMAHandle<'a> {
+        DMAHandle { progress: None }
+    }
+
+    fn advance(&mut self, waker: &Waker) -> Result<bool> {
+        let done = match &mut self.progress {
+            Some(p) => p.progress(waker)?,
+            None => true,
+        };
+        if done {
+            self.progress = None;
+        }
+        Ok(done)
+    }
+
+    /// Check for completion without blocking.
+    pub fn poll(&mut self) -> Result<bool> {
+        self.advance(&Waker::from(Arc::new(ThreadWaker(thread::current()))))
+    }
+
+    /// Block until the transfer has finished.
+    pub fn wait(mut self) -> Result<()> {
+        let waker = Waker::from(Arc::new(ThreadWaker(thread::current())));
+        while !self.advance(&waker)? {
+            thread::park();
+        }
+        Ok(())
+    }
+
+    /// Block until the transfer has finished or `timeout` expired.
+    ///
+    /// Returns `true` if the transfer has finished.
+    pub fn wait_timeout(&mut self, timeout: Duration) -> Result<bool> {
+        let deadline = Instant::now() + timeout;
+        let waker = Waker::from(Arc::new(ThreadWaker(thread::current())));
+        loop {
+            if self.advance(&waker)? {
+                return Ok(true);
+            }
+            let now = Instant::now();
+            if now >= deadline {
+                return Ok(false);
+            }
+            thread::park_timeout(deadline - now);
+        }
+    }
+}
+
+impl Future for DMAHandle<'_> {
+    type Output = Result<()>;
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context) -> Poll<Self::Output> {
+        match self.advance(cx.waker()) {
+            Ok(true) => Poll::Ready(Ok(())),
+            Ok(false) => Poll::Pending,
+            Err(e) => Poll::Ready(Err(e)),
+        }
+    }
+}
+
+/// Progress of all parts of a transfer that has been split, e.g. by the `StripedDMA`.
+#[derive(Debug)]
+struct CombinedProgress<'a> {
+    parts: Vec<DMAHandle<'a>>,
+}
+
+impl DMAProgress for CombinedProgress<'_> {
+    fn progress(&mut self, waker: &Waker) -> Result<bool> {
+        let mut r = Ok(());
+        self.parts.retain_mut(|p| match p.advance(waker) {
+            Ok(done) => !done,
+            Err(e) => {
+                r = Err(e);
+                false
+            }
+        });
+        r?;
+        Ok(self.parts.is_empty())
+    }
 }
 
 #[derive(Debug, Getters)]
 pub struct DriverDMA {
-    tlkm_file: Arc<File>,
+    tlkm_file: Arc<dyn DeviceDriver>,
 }
 
 impl DriverDMA {
-    pub fn new(tlkm_file: &Arc<File>) -> DriverDMA {
+    pub fn new(tlkm_file: &Arc<dyn DeviceDriver>) -> DriverDMA {
         DriverDMA {
             tlkm_file: tlkm_file.clone(),
         }
@@ -106,17 +246,13 @@ impl DMAControl for DriverDMA {
             ptr,
             data.len()
         );
-        unsafe {
-            tlkm_ioctl_copy_to(
-                self.tlkm_file.as_raw_fd(),
-                &mut tlkm_copy_cmd_to {
-                    dev_addr: ptr,
-                    length: data.len(),
-                    user_addr: data.as_ptr(),
-                },
-            )
+        self.tlkm_file
+            .copy_to(&mut tlkm_copy_cmd_to {
+                dev_addr: ptr,
+                length: data.len(),
+                user_addr: data.as_ptr(),
+            })
             .context(DMAToDevice)?;
-        };
         Ok(())
     }
 
@@ -127,17 +263,13 @@ impl DMAControl for DriverDMA {
             data.as_mut_ptr(),
             data.len()
         );
-        unsafe {
-            tlkm_ioctl_copy_from(
-                self.tlkm_file.as_raw_fd(),
-                &mut tlkm_copy_cmd_from {
-                    dev_addr: ptr,
-                    length: data.len(),
-                    user_addr: data.as_mut_ptr(),
-                },
-            )
+        self.tlkm_file
+            .copy_from(&mut tlkm_copy_cmd_from {
+                dev_addr: ptr,
+                length: data.len(),
+                user_addr: data.as_mut_ptr(),
+            })
             .context(DMAFromDevice)?;
-        };
         Ok(())
     }
 }
@@ -218,3 +350,193 @@ impl DMAControl for DirectDMA {
         Ok(())
     }
 }
+
+/// Stripe transfers over several DMA engines
+///
+/// Transfers are split into chunks of `chunk_size` bytes which are distributed round
+/// robin over the engines. Every engine copies its chunks in a thread of its own, so the
+/// bandwidth of all engines is used for large transfers. Transfers smaller than a
+/// single chunk are handled by the first engine only.
+#[derive(Debug, Getters)]
+pub struct StripedDMA {
+    #[get = "pub"]
+    engines: Vec<Arc<dyn DMAControl + Sync + Send>>,
+    chunk_size: usize,
+}
+
+impl StripedDMA {
+    pub fn new(engines: Vec<Arc<dyn DMAControl + Sync + Send>>, chunk_size: usize) -> StripedDMA {
+        assert!(
+            !engines.is_empty(),
+            "StripedDMA requires at least one engine."
+        );
+        StripedDMA {
+            engines: engines,
+            chunk_size: std::cmp::max(chunk_size, 1),
+        }
+    }
+
+    fn is_striped(&self, len: usize) -> bool {
+        self.engines.len() > 1 && len > self.chunk_size
+    }
+
+    /// Run `f` for the chunks assigned to every engine concurrently.
+    fn run_striped<T, F>(&self, mut chunks: Vec<Vec<T>>, f: F) -> Result<()>
+    where
+        T: Send,
+        F: Fn(&(dyn DMAControl + Sync + Send), T) -> Result<()> + Sync,
+    {
+        let f = &f;
+        let results = crossbeam::scope(|s| {
+            let handles: Vec<_> = self
+                .engines
+                .iter()
+                .zip(chunks.drain(..))
+                .map(|(engine, c)| {
+                    s.spawn(move |_| -> Result<()> {
+                        for x in c {
+                            f(engine.as_ref(), x)?;
+                        }
+                        Ok(())
+                    })
+                })
+                .collect();
+            handles
+                .into_iter()
+                .map(|h| h.join().unwrap_or(Err(Error::StripeThreadPanic {})))
+                .collect::<Vec<Result<()>>>()
+        })
+        .map_err(|_| Error::StripeThreadPanic {})?;
+        results.into_iter().collect()
+    }
+}
+
+impl DMAControl for StripedDMA {
+    fn copy_to(&self, data: &[u8], ptr: DeviceAddress) -> Result<()> {
+        if !self.is_striped(data.len()) {
+            return self.engines[0].copy_to(data, ptr);
+        }
+        trace!(
+            "Striping Host({:?}) -> Device(0x{:x}) ({} Bytes) over {} engines",
+            data.as_ptr(),
+            ptr,
+            data.len(),
+            self.engines.len()
+        );
+        let mut chunks: Vec<Vec<(DeviceAddress, &[u8])>> =
+            self.engines.iter().map(|_| Vec::new()).collect();
+        for (i, c) in data.chunks(self.chunk_size).enumerate() {
+            chunks[i % self.engines.len()].push((ptr + (i * self.chunk_size) as u64, c));
+        }
+        self.run_striped(chunks, |engine, (addr, c)| engine.copy_to(c, addr))
+    }
+
+    fn copy_from(&self, ptr: DeviceAddress, data: &mut [u8]) -> Result<()> {
+        if !self.is_striped(data.len()) {
+            return self.engines[0].copy_from(ptr, data);
+        }
+        trace!(
+            "Striping Device(0x{:x}) -> Host({:?}) ({} Bytes) over {} engines",
+            ptr,
+            data.as_mut_ptr(),
+            data.len(),
+            self.engines.len()
+        );
+        let mut chunks: Vec<Vec<(DeviceAddress, &mut [u8])>> =
+            self.engines.iter().map(|_| Vec::new()).collect();
+        let n = self.engines.len();
+        for (i, c) in data.chunks_mut(self.chunk_size).enumerate() {
+            chunks[i % n].push((ptr + (i * self.chunk_size) as u64, c));
+        }
+        self.run_striped(chunks, |engine, (addr, c)| engine.copy_from(addr, c))
+    }
+
+    fn copy_to_async<'a>(&'a self, data: &'a [u8], ptr: DeviceAddress) -> Result<DMAHandle<'a>> {
+        if !self.is_striped(data.len()) {
+            return self.engines[0].copy_to_async(data, ptr);
+        }
+        let mut parts = Vec::new();
+        for (i, c) in data.chunks(self.chunk_size).enumerate() {
+            let engine = &self.engines[i % self.engines.len()];
+            parts.push(engine.copy_to_async(c, ptr + (i * self.chunk_size) as u64)?);
+        }
+        Ok(DMAHandle::new(Box::new(CombinedProgress { parts: parts })))
+    }
+
+    fn copy_from_async<'a>(
+        &'a self,
+        ptr: DeviceAddress,
+        data: &'a mut [u8],
+    ) -> Result<DMAHandle<'a>> {
+        if !self.is_striped(data.len()) {
+            return self.engines[0].copy_from_async(ptr, data);
+        }
+        let mut parts = Vec::new();
+        for (i, c) in data.chunks_mut(self.chunk_size).enumerate() {
+            let engine = &self.engines[i % self.engines.len()];
+            parts.push(engine.copy_from_async(ptr + (i * self.chunk_size) as u64, c)?);
+        }
+        Ok(DMAHandle::new(Box::new(CombinedProgress { parts: parts })))
+    }
+}
+
+#[cfg(test)]
+mod dma_tests {
+    use crate::sim::testing::{block_on, Result};
+    use crate::sim::{PEContext, SimulatedDevice, SimulatedPE, SimulatedTLKM};
+    use crate::tlkm::TLKM;
+    use std::collections::HashMap;
+    use std::sync::Arc;
+    use std::time::Duration;
+
+    #[test]
+    fn striped_dma() -> Result<()> {
+        let sim = SimulatedTLKM::new(vec![SimulatedDevice::new("pcie")
+            .pe(SimulatedPE::new(14, "counter", |_: &mut PEContext| 0), 1)
+            .dma_engines(2)]);
+        let sim = Arc::new(sim);
+        let tlkm = TLKM::with_driver(sim.clone())?;
+        let dev = tlkm.device_alloc(0, &HashMap::new())?;
+        let mem = dev.default_memory()?;
+        let len = 9 * 1024 * 1024 + 5;
+        let data: Vec<u8> = (0..len).map(|x| (x % 253) as u8).collect();
+        let addr = mem.allocator().lock().unwrap().allocate(len as u64)?;
+        mem.dma().copy_to(&data, addr)?;
+        let mut back = vec![0u8; len];
+        mem.dma().copy_from(addr, &mut back)?;
+        assert!(data == back);
+
+        // Both engines had commands pending at the same time.
+        assert_eq!(sim.dma_overlap(0), 2);
+        Ok(())
+    }
+
+    #[test]
+    fn async_dma() -> Result<()> {
+        let sim = SimulatedTLKM::new(vec![SimulatedDevice::new("pcie")
+            .pe(SimulatedPE::new(14, "counter", |_: &mut PEContext| 0), 1)
+            .dma_engines(2)]);
+        let tlkm = TLKM::with_driver(Arc::new(sim))?;
+        let dev = tlkm.device_alloc(0, &HashMap::new())?;
+        let mem = dev.default_memory()?;
+        let len = 5 * 1024 * 1024 + 3;
+        let data: Vec<u8> = (0..len).map(|x| (x % 251) as u8).collect();
+        let a = mem.allocator().lock().unwrap().allocate(len as u64)?;
+        let b = mem.allocator().lock().unwrap().allocate(len as u64)?;
+
+        let h = mem.dma().copy_to_async(&data, a)?;
+        block_on(mem.dma().copy_to_async(&data[..1024], b)?)?;
+        h.wait()?;
+
+        let mut back = vec![0u8; len];
+        let mut h = mem.dma().copy_from_async(a, &mut back)?;
+        while !h.wait_timeout(Duration::from_millis(10))? {}
+        drop(h);
+        assert!(data == back);
+
+        let mut head = vec![0u8; 1024];
+        block_on(mem.dma().copy_from_async(b, &mut head)?)?;
+        assert!(data[..1024] == head[..]);
+        Ok(())
+    }
+}
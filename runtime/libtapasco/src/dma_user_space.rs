@@ -22,28 +22,26 @@ use crate::device::DeviceAddress;
 use crate::device::DeviceSize;
 use crate::dma::DMABufferAllocate;
 use crate::dma::DMAControl;
+use crate::dma::DMAHandle;
+use crate::dma::DMAProgress;
 use crate::dma::Error;
 use crate::dma::ErrorInterrupt;
 use crate::dma::FailedMMapDMA;
 use crate::interrupt::Interrupt;
+use crate::reactor::Reactor;
 use crate::tlkm::tlkm_dma_buffer_allocate;
 use crate::tlkm::tlkm_dma_buffer_op;
-use crate::tlkm::tlkm_ioctl_dma_buffer_allocate;
-use crate::tlkm::tlkm_ioctl_dma_buffer_from_dev;
-use crate::tlkm::tlkm_ioctl_dma_buffer_to_dev;
+use crate::tlkm::DeviceDriver;
 use core::fmt::Debug;
 use core::sync::atomic::AtomicU64;
 use crossbeam::deque::{Injector, Steal};
 use lockfree::queue::Queue;
 use memmap::MmapMut;
-use memmap::MmapOptions;
 use snafu::ResultExt;
-use std::fs::File;
-use std::os::unix::prelude::*;
 use std::sync::atomic::Ordering;
 use std::sync::Arc;
 use std::sync::Mutex;
-use std::sync::MutexGuard;
+use std::task::Waker;
 use std::thread;
 use volatile::Volatile;
 
@@ -55,6 +53,62 @@ impl<T> From<std::sync::PoisonError<T>> for Error {
 
 type Result<T, E = Error> = std::result::Result<T, E>;
 
+/// Register protocol of a DMA engine driven by [`UserSpaceDMA`].
+///
+/// Implementations only enqueue single transfers between a bounce buffer and the device
+/// memory. Buffer management and completion tracking through the READ and WRITE
+/// interrupts of the engine are handled by [`UserSpaceDMA`].
+///
+/// [`UserSpaceDMA`]: struct.UserSpaceDMA.html
+pub trait DmaEngine: Debug + Send + Sync {
+    /// Enqueue a transfer in the engine located at `offset` in the platform memory.
+    fn schedule_transfer(
+        &self,
+        memory: &MmapMut,
+        offset: usize,
+        addr_host: u64,
+        addr_device: DeviceAddress,
+        size: DeviceSize,
+        from_device: bool,
+    ) -> Result<()>;
+}
+
+/// The BlueDMA engine used by the PCIe platforms of TaPaSCo.
+#[derive(Debug)]
+pub struct BlueDMA {}
+
+impl BlueDMA {
+    fn write_register(memory: &MmapMut, offset: usize, value: u64) {
+        unsafe {
+            let ptr = memory.as_ptr().offset(offset as isize);
+            let volatile_ptr = ptr as *mut Volatile<u64>;
+            (*volatile_ptr).write(value);
+        };
+    }
+}
+
+impl DmaEngine for BlueDMA {
+    fn schedule_transfer(
+        &self,
+        memory: &MmapMut,
+        offset: usize,
+        addr_host: u64,
+        addr_device: DeviceAddress,
+        size: DeviceSize,
+        from_device: bool,
+    ) -> Result<()> {
+        BlueDMA::write_register(memory, offset + 0x00, addr_host);
+        BlueDMA::write_register(memory, offset + 0x08, addr_device);
+        BlueDMA::write_register(memory, offset + 0x10, size);
+        BlueDMA::write_register(
+            memory,
+            offset + 0x20,
+            if from_device { 0x10001000 } else { 0x10000001 },
+        );
+        Ok(())
+    }
+}
+
 #[derive(Debug)]
 struct DMABuffer {
     id: usize,
@@ -75,8 +129,9 @@ struct DMABuffer {
 /// The implementation uses TLKM to allocate the required bounce buffers and retrieve interrupts.
 #[derive(Debug, Getters)]
 pub struct UserSpaceDMA {
-    tlkm_file: Arc<File>,
+    tlkm_file: Arc<dyn DeviceDriver>,
     memory: Mutex<Arc<MmapMut>>,
+    engine: Arc<dyn DmaEngine>,
     engine_offset: usize,
     to_dev_buffer: Injector<DMABuffer>,
     from_dev_buffer: Injector<DMABuffer>,
@@ -87,15 +142,19 @@ pub struct UserSpaceDMA {
     write_int_cntr: AtomicU64,
     read_cntr: AtomicU64,
     read_int_cntr: AtomicU64,
+    reactor: Arc<Reactor>,
+    waiters: Mutex<Vec<Waker>>,
 }
 
 impl UserSpaceDMA {
     pub fn new(
-        tlkm_file: &Arc<File>,
+        tlkm_file: &Arc<dyn DeviceDriver>,
+        engine: Arc<dyn DmaEngine>,
         offset: usize,
         read_interrupt: usize,
         write_interrupt: usize,
         memory: &Arc<MmapMut>,
+        reactor: &Arc<Reactor>,
         read_buf_size: usize,
         read_num_buf: usize,
         write_buf_size: usize,
@@ -119,10 +178,9 @@ impl UserSpaceDMA {
                 buffer_id: 42,
                 addr: 42,
             };
-            unsafe {
-                tlkm_ioctl_dma_buffer_allocate(tlkm_file.as_raw_fd(), &mut to_dev_buf)
-                    .context(DMABufferAllocate)?;
-            };
+            tlkm_file
+                .dma_buffer_allocate(&mut to_dev_buf)
+                .context(DMABufferAllocate)?;
 
             trace!("Retrieved {:?} for to_dev_buffer.", to_dev_buf);
 
@@ -130,13 +188,9 @@ impl UserSpaceDMA {
                 id: to_dev_buf.buffer_id,
                 addr: to_dev_buf.addr,
                 size: write_buf_size,
-                mapped: unsafe {
-                    MmapOptions::new()
-                        .len(write_buf_size)
-                        .offset(((4 + to_dev_buf.buffer_id) * 4096) as u64)
-                        .map_mut(&tlkm_file)
-                        .context(FailedMMapDMA)?
-                },
+                mapped: tlkm_file
+                    .map_mut(((4 + to_dev_buf.buffer_id) * 4096) as u64, write_buf_size)
+                    .context(FailedMMapDMA)?,
             });
         }
 
@@ -147,10 +201,9 @@ impl UserSpaceDMA {
                 buffer_id: 42,
                 addr: 42,
             };
-            unsafe {
-                tlkm_ioctl_dma_buffer_allocate(tlkm_file.as_raw_fd(), &mut from_dev_buf)
-                    .context(DMABufferAllocate)?;
-            };
+            tlkm_file
+                .dma_buffer_allocate(&mut from_dev_buf)
+                .context(DMABufferAllocate)?;
 
             trace!("Retrieved {:?} for from_dev_buffer.", from_dev_buf);
 
@@ -158,72 +211,163 @@ impl UserSpaceDMA {
                 id: from_dev_buf.buffer_id,
                 addr: from_dev_buf.addr,
                 size: read_buf_size,
-                mapped: unsafe {
-                    MmapOptions::new()
-                        .len(read_buf_size)
-                        .offset(((4 + from_dev_buf.buffer_id) * 4096) as u64)
-                        .map_mut(&tlkm_file)
-                        .context(FailedMMapDMA)?
-                },
+                mapped: tlkm_file
+                    .map_mut(((4 + from_dev_buf.buffer_id) * 4096) as u64, read_buf_size)
+                    .context(FailedMMapDMA)?,
             });
         }
 
         Ok(UserSpaceDMA {
             tlkm_file: tlkm_file.clone(),
             memory: Mutex::new(memory.clone()),
+            engine: engine,
             engine_offset: offset,
             to_dev_buffer: write_map,
             from_dev_buffer: read_map,
-            read_int: Interrupt::new(tlkm_file, read_interrupt, false).context(ErrorInterrupt)?,
-            write_int: Interrupt::new(tlkm_file, write_interrupt, false).context(ErrorInterrupt)?,
+            read_int: Interrupt::new(tlkm_file.as_ref(), read_interrupt, false)
+                .context(ErrorInterrupt)?,
+            write_int: Interrupt::new(tlkm_file.as_ref(), write_interrupt, false)
+                .context(ErrorInterrupt)?,
             write_out: Queue::new(),
             write_cntr: AtomicU64::new(0),
             write_int_cntr: AtomicU64::new(0),
             read_int_cntr: AtomicU64::new(0),
             read_cntr: AtomicU64::new(0),
+            reactor: reactor.clone(),
+            waiters: Mutex::new(Vec::new()),
         })
     }
 
-    /// Enqueue a DMA transfer in the DMA engine
+    /// Collect finished writes and return their buffers to the pool.
     ///
-    /// This function currently supports only BlueDMA.
-    fn schedule_dma_transfer(
-        &self,
-        dma_engine_memory: &MutexGuard<Arc<MmapMut>>,
-        addr_host: u64,
-        addr_device: DeviceAddress,
-        size: DeviceSize,
-        from_device: bool,
-    ) -> Result<()> {
-        let mut offset = (self.engine_offset as usize + 0x00) as isize;
-        unsafe {
-            let ptr = dma_engine_memory.as_ptr().offset(offset);
-            let volatile_ptr = ptr as *mut Volatile<u64>;
-            (*volatile_ptr).write(addr_host);
-        };
+    /// Returns the number of writes that have finished since the last call.
+    fn collect_writes(&self) -> Result<u64> {
+        let n = self
+            .write_int
+            .check_for_interrupt()
+            .context(ErrorInterrupt)?;
+        for _ in 0..n {
+            self.write_int_cntr.fetch_add(1, Ordering::Relaxed);
+            match self.write_out.pop() {
+                Some(buf) => self.to_dev_buffer.push(buf),
+                None => Err(Error::TooManyInterrupts {})?,
+            }
+        }
+        if n > 0 {
+            self.wake_waiters()?;
+        }
+        Ok(n)
+    }
 
-        offset = (self.engine_offset as usize + 0x08) as isize;
-        unsafe {
-            let ptr = dma_engine_memory.as_ptr().offset(offset);
-            let volatile_ptr = ptr as *mut Volatile<u64>;
-            (*volatile_ptr).write(addr_device);
+    /// Wake all asynchronous transfers as interrupts they might wait for have been consumed.
+    fn wake_waiters(&self) -> Result<()> {
+        let waiters: Vec<Waker> = self.waiters.lock()?.drain(..).collect();
+        for w in waiters {
+            w.wake();
+        }
+        Ok(())
+    }
+
+    /// Wake `waker` once the interrupt fires or another transfer consumed interrupts.
+    fn register_waker(&self, interrupt: &Interrupt, waker: &Waker) -> Result<()> {
+        {
+            let mut waiters = self.waiters.lock()?;
+            if !waiters.iter().any(|w| w.will_wake(waker)) {
+                waiters.push(waker.clone());
+            }
+        }
+        interrupt
+            .register_waker(&self.reactor, waker)
+            .context(ErrorInterrupt)
+    }
+
+    /// Copy the next part of a transfer to a free write buffer and start the transfer.
+    ///
+    /// Returns the counter of the started transfer or `None` if no buffer is available.
+    fn start_write(&self, data: &[u8], ptr_device: DeviceAddress) -> Result<Option<(u64, usize)>> {
+        let mut buffer = loop {
+            match self.to_dev_buffer.steal() {
+                Steal::Success(buffer) => break buffer,
+                Steal::Empty => return Ok(None),
+                Steal::Retry => (),
+            }
         };
 
-        offset = (self.engine_offset as usize + 0x10) as isize;
-        unsafe {
-            let ptr = dma_engine_memory.as_ptr().offset(offset);
-            let volatile_ptr = ptr as *mut Volatile<u64>;
-            (*volatile_ptr).write(size);
+        let btt_this = if data.len() < buffer.size {
+            data.len()
+        } else {
+            buffer.size
         };
 
-        offset = (self.engine_offset as usize + 0x20) as isize;
-        unsafe {
-            let ptr = dma_engine_memory.as_ptr().offset(offset);
-            let volatile_ptr = ptr as *mut Volatile<u64>;
-            (*volatile_ptr).write(if from_device { 0x10001000 } else { 0x10000001 });
+        self.tlkm_file
+            .dma_buffer_from_dev(&mut tlkm_dma_buffer_op {
+                buffer_id: buffer.id,
+            })
+            .context(DMABufferAllocate)?;
+
+        buffer.mapped[0..btt_this].copy_from_slice(&data[0..btt_this]);
+
+        self.tlkm_file
+            .dma_buffer_to_dev(&mut tlkm_dma_buffer_op {
+                buffer_id: buffer.id,
+            })
+            .context(DMABufferAllocate)?;
+
+        let dma_engine_memory = self.memory.lock()?;
+        let addr = buffer.addr;
+        self.write_out.push(buffer);
+        self.engine.schedule_transfer(
+            &dma_engine_memory,
+            self.engine_offset,
+            addr,
+            ptr_device,
+            btt_this as u64,
+            false,
+        )?;
+        Ok(Some((
+            self.write_cntr.fetch_add(1, Ordering::Relaxed),
+            btt_this,
+        )))
+    }
+
+    /// Start reading the next part of a transfer into a free read buffer.
+    ///
+    /// Returns the buffer together with the counter of the started transfer or `None`
+    /// if no buffer is available.
+    fn start_read(
+        &self,
+        ptr_device: DeviceAddress,
+        btt: usize,
+    ) -> Result<Option<(u64, DMABuffer, usize)>> {
+        let buffer = loop {
+            match self.from_dev_buffer.steal() {
+                Steal::Success(buffer) => break buffer,
+                Steal::Empty => return Ok(None),
+                Steal::Retry => (),
+            }
         };
 
-        Ok(())
+        let btt_this = if btt < buffer.size { btt } else { buffer.size };
+
+        self.tlkm_file
+            .dma_buffer_to_dev(&mut tlkm_dma_buffer_op {
+                buffer_id: buffer.id,
+            })
+            .context(DMABufferAllocate)?;
+
+        let cntr = {
+            let dma_engine_memory = self.memory.lock()?;
+            self.engine.schedule_transfer(
+                &dma_engine_memory,
+                self.engine_offset,
+                buffer.addr,
+                ptr_device,
+                btt_this as u64,
+                true,
+            )?;
+            self.read_cntr.fetch_add(1, Ordering::Relaxed)
+        };
+        Ok(Some((cntr, buffer, btt_this)))
     }
 
     fn wait_for_write(&self, next: bool, cntr: u64) -> Result<()> {
@@ -231,17 +375,7 @@ impl UserSpaceDMA {
             while (next && self.to_dev_buffer.is_empty())
                 || (!next && self.write_int_cntr.load(Ordering::Relaxed) <= cntr)
             {
-                let n = self
-                    .write_int
-                    .check_for_interrupt()
-                    .context(ErrorInterrupt)?;
-                for _ in 0..n {
-                    self.write_int_cntr.fetch_add(1, Ordering::Relaxed);
-                    match self.write_out.pop() {
-                        Some(buf) => self.to_dev_buffer.push(buf),
-                        None => Err(Error::TooManyInterrupts {})?,
-                    }
-                }
+                self.collect_writes()?;
                 thread::yield_now();
             }
         }
@@ -258,6 +392,9 @@ impl UserSpaceDMA {
             .check_for_interrupt()
             .context(ErrorInterrupt)?;
         self.read_int_cntr.fetch_add(n, Ordering::Relaxed);
+        if n > 0 {
+            self.wake_waiters()?;
+        }
 
         Ok(())
     }
@@ -270,13 +407,9 @@ impl UserSpaceDMA {
         offset: usize,
         btt: usize,
     ) -> Result<()> {
-        unsafe {
-            tlkm_ioctl_dma_buffer_from_dev(
-                self.tlkm_file.as_raw_fd(),
-                &mut tlkm_dma_buffer_op { buffer_id: buf.id },
-            )
+        self.tlkm_file
+            .dma_buffer_from_dev(&mut tlkm_dma_buffer_op { buffer_id: buf.id })
             .context(DMABufferAllocate)?;
-        };
 
         data[offset..offset + btt].copy_from_slice(&buf.mapped[0..btt]);
 
@@ -325,55 +458,15 @@ impl DMAControl for UserSpaceDMA {
         let mut highest_used = 0;
 
         while btt > 0 {
-            let mut buffer = loop {
-                match self.to_dev_buffer.steal() {
-                    Steal::Success(buffer) => break buffer,
-                    Steal::Empty => self.wait_for_write(true, 0)?,
-                    Steal::Retry => (),
+            match self.start_write(&data[ptr_buffer..], ptr_device)? {
+                Some((cntr, btt_this)) => {
+                    highest_used = cntr;
+                    btt -= btt_this;
+                    ptr_buffer += btt_this;
+                    ptr_device += btt_this as u64;
                 }
-            };
-
-            let btt_this = if btt < buffer.size { btt } else { buffer.size };
-
-            unsafe {
-                tlkm_ioctl_dma_buffer_from_dev(
-                    self.tlkm_file.as_raw_fd(),
-                    &mut tlkm_dma_buffer_op {
-                        buffer_id: buffer.id,
-                    },
-                )
-                .context(DMABufferAllocate)?;
-            };
-
-            buffer.mapped[0..btt_this].copy_from_slice(&data[ptr_buffer..ptr_buffer + btt_this]);
-
-            unsafe {
-                tlkm_ioctl_dma_buffer_to_dev(
-                    self.tlkm_file.as_raw_fd(),
-                    &mut tlkm_dma_buffer_op {
-                        buffer_id: buffer.id,
-                    },
-                )
-                .context(DMABufferAllocate)?;
-            };
-
-            {
-                let dma_engine_memory = self.memory.lock()?;
-                let addr = buffer.addr;
-                self.write_out.push(buffer);
-                self.schedule_dma_transfer(
-                    &dma_engine_memory,
-                    addr,
-                    ptr_device,
-                    btt_this as u64,
-                    false,
-                )?;
-                highest_used = self.write_cntr.fetch_add(1, Ordering::Relaxed);
+                None => self.wait_for_write(true, 0)?,
             }
-
-            btt -= btt_this;
-            ptr_buffer += btt_this;
-            ptr_device += btt_this as u64;
         }
 
         self.wait_for_write(false, highest_used)?;
@@ -396,46 +489,18 @@ impl DMAControl for UserSpaceDMA {
         let mut used_buffers: Vec<(u64, Option<DMABuffer>, usize, usize)> = Vec::new();
 
         while btt > 0 {
-            let buffer = loop {
-                self.update_interrupts()?;
-                self.release_buffer(&mut used_buffers, data)?;
-
-                match self.from_dev_buffer.steal() {
-                    Steal::Success(buffer) => break buffer,
-                    Steal::Empty => thread::yield_now(),
-                    Steal::Retry => (),
-                }
-            };
-
-            let btt_this = if btt < buffer.size { btt } else { buffer.size };
-
-            unsafe {
-                tlkm_ioctl_dma_buffer_to_dev(
-                    self.tlkm_file.as_raw_fd(),
-                    &mut tlkm_dma_buffer_op {
-                        buffer_id: buffer.id,
-                    },
-                )
-                .context(DMABufferAllocate)?;
-            };
-
-            let cntr = {
-                let dma_engine_memory = self.memory.lock()?;
-                self.schedule_dma_transfer(
-                    &dma_engine_memory,
-                    buffer.addr,
-                    ptr_device,
-                    btt_this as u64,
-                    true,
-                )?;
-                self.read_cntr.fetch_add(1, Ordering::Relaxed)
-            };
-
-            used_buffers.push((cntr, Some(buffer), ptr_buffer, btt_this));
+            self.update_interrupts()?;
+            self.release_buffer(&mut used_buffers, data)?;
 
-            btt -= btt_this;
-            ptr_buffer += btt_this;
-            ptr_device += btt_this as u64;
+            match self.start_read(ptr_device, btt)? {
+                Some((cntr, buffer, btt_this)) => {
+                    used_buffers.push((cntr, Some(buffer), ptr_buffer, btt_this));
+                    btt -= btt_this;
+                    ptr_buffer += btt_this;
+                    ptr_device += btt_this as u64;
+                }
+                None => thread::yield_now(),
+            }
         }
 
         while used_buffers.len() > 0 {
@@ -446,4 +511,157 @@ impl DMAControl for UserSpaceDMA {
 
         Ok(())
     }
+
+    fn copy_to_async<'a>(&'a self, data: &'a [u8], ptr: DeviceAddress) -> Result<DMAHandle<'a>> {
+        trace!(
+            "Start asynchronous copy Host({:?}) -> Device(0x{:x}) ({} Bytes)",
+            data.as_ptr(),
+            ptr,
+            data.len()
+        );
+        let mut handle = DMAHandle::new(Box::new(ToDeviceProgress {
+            dma: self,
+            data: data,
+            ptr_buffer: 0,
+            ptr_device: ptr,
+            highest_used: None,
+        }));
+        handle.poll()?;
+        Ok(handle)
+    }
+
+    fn copy_from_async<'a>(
+        &'a self,
+        ptr: DeviceAddress,
+        data: &'a mut [u8],
+    ) -> Result<DMAHandle<'a>> {
+        trace!(
+            "Start asynchronous copy Device(0x{:x}) -> Host({:?}) ({} Bytes)",
+            ptr,
+            data.as_mut_ptr(),
+            data.len()
+        );
+        let btt = data.len();
+        let mut handle = DMAHandle::new(Box::new(FromDeviceProgress {
+            dma: self,
+            data: data,
+            ptr_buffer: 0,
+            ptr_device: ptr,
+            btt: btt,
+            used_buffers: Vec::new(),
+        }));
+        handle.poll()?;
+        Ok(handle)
+    }
+}
+
+/// Asynchronous transfer to the device.
+///
+/// Parts of the transfer are started whenever a write buffer is available. The transfer
+/// is done once the interrupt of the last part has been received.
+#[derive(Debug)]
+struct ToDeviceProgress<'a> {
+    dma: &'a UserSpaceDMA,
+    data: &'a [u8],
+    ptr_buffer: usize,
+    ptr_device: DeviceAddress,
+    highest_used: Option<u64>,
+}
+
+impl ToDeviceProgress<'_> {
+    fn step(&mut self) -> Result<bool> {
+        self.dma.collect_writes()?;
+        while self.ptr_buffer < self.data.len() {
+            match self
+                .dma
+                .start_write(&self.data[self.ptr_buffer..], self.ptr_device)?
+            {
+                Some((cntr, btt_this)) => {
+                    self.highest_used = Some(cntr);
+                    self.ptr_buffer += btt_this;
+                    self.ptr_device += btt_this as u64;
+                }
+                None => return Ok(false),
+            }
+        }
+        Ok(match self.highest_used {
+            Some(cntr) => self.dma.write_int_cntr.load(Ordering::Relaxed) > cntr,
+            None => true,
+        })
+    }
+}
+
+impl DMAProgress for ToDeviceProgress<'_> {
+    fn progress(&mut self, waker: &Waker) -> Result<bool> {
+        if self.step()? {
+            return Ok(true);
+        }
+        // Register before checking again, interrupts consumed in between by other
+        // transfers wake this one through the waiter list.
+        self.dma.register_waker(&self.dma.write_int, waker)?;
+        self.step()
+    }
+}
+
+/// Asynchronous transfer from the device.
+///
+/// Parts of the transfer are started whenever a read buffer is available and copied
+/// to the host buffer once their interrupt has been received.
+#[derive(Debug)]
+struct FromDeviceProgress<'a> {
+    dma: &'a UserSpaceDMA,
+    data: &'a mut [u8],
+    ptr_buffer: usize,
+    ptr_device: DeviceAddress,
+    btt: usize,
+    used_buffers: Vec<(u64, Option<DMABuffer>, usize, usize)>,
+}
+
+impl FromDeviceProgress<'_> {
+    fn step(&mut self) -> Result<bool> {
+        loop {
+            self.dma.update_interrupts()?;
+            self.dma
+                .release_buffer(&mut self.used_buffers, &mut self.data)?;
+            if self.btt == 0 {
+                return Ok(self.used_buffers.is_empty());
+            }
+            match self.dma.start_read(self.ptr_device, self.btt)? {
+                Some((cntr, buffer, btt_this)) => {
+                    self.used_buffers
+                        .push((cntr, Some(buffer), self.ptr_buffer, btt_this));
+                    self.btt -= btt_this;
+                    self.ptr_buffer += btt_this;
+                    self.ptr_device += btt_this as u64;
+                }
+                None => return Ok(false),
+            }
+        }
+    }
+}
+
+impl DMAProgress for FromDeviceProgress<'_> {
+    fn progress(&mut self, waker: &Waker) -> Result<bool> {
+        if self.step()? {
+            return Ok(true);
+        }
+        // Register before checking again, see ToDeviceProgress.
+        self.dma.register_waker(&self.dma.read_int, waker)?;
+        self.step()
+    }
+}
+
+/// Read buffers belong to the pool of the engine. Wait for the parts that have been
+/// started already so the buffers can be returned.
+impl Drop for FromDeviceProgress<'_> {
+    fn drop(&mut self) {
+        self.btt = 0;
+        while !self.used_buffers.is_empty() {
+            if let Err(e) = self.step() {
+                error!("Failed to finish aborted transfer: {}", e);
+                return;
+            }
+            thread::yield_now();
+        }
+    }
 }
@@ -18,6 +18,8 @@
  * along with this program. If not, see <http://www.gnu.org/licenses/>.
  */
 
+use crate::allocator::AllocationInfo;
+use crate::allocator::AllocatorStats;
 use crate::device::DataTransferAlloc;
 use crate::device::DataTransferLocal;
 use crate::device::DataTransferPrealloc;
@@ -27,6 +29,7 @@ use crate::device::OffchipMemory;
 use crate::device::PEParameter;
 use crate::job::Job;
 use crate::pe::PEId;
+use crate::pe::PEStatsSummary;
 use crate::tlkm::tlkm_access;
 use crate::tlkm::DeviceId;
 use crate::tlkm::DeviceInfo;
@@ -40,6 +43,7 @@ use std::ffi::CStr;
 use std::ptr;
 use std::slice;
 use std::sync::Arc;
+use std::time::Duration;
 use std::u64;
 
 #[derive(Debug, Snafu)]
@@ -70,6 +74,30 @@ pub enum Error {
 
     #[snafu(display("Failed to retrieve default memory: {}", source))]
     RetrieveDefaultMemory { source: crate::device::Error },
+
+    #[snafu(display("Memory index {} out of range, device has {} memories.", idx, len))]
+    MemoryIndex { idx: usize, len: usize },
+
+    #[snafu(display("Name string to short, need {} bytes.", len))]
+    NameStringToShort { len: usize },
+
+    #[snafu(display("No PE of type {} available.", id))]
+    PEUnavailable { id: PEId },
+
+    #[snafu(display("Not enough space for allocation info, need {} entries.", len))]
+    AllocationInfoToShort { len: usize },
+
+    #[snafu(display("Not enough space for jobs, need {} entries.", len))]
+    JobsToShort { len: usize },
+
+    #[snafu(display("Requested PEs are unavailable."))]
+    PEsUnavailable {},
+
+    #[snafu(display("PE index {} out of range, device has {} PEs.", idx, len))]
+    PEIndex { idx: usize, len: usize },
+
+    #[snafu(display("PE type {} is not part of the bitstream.", id))]
+    NoSuchPEType { id: PEId },
 }
 
 //////////////////////
@@ -494,6 +522,87 @@ pub extern "C" fn tapasco_job_param_prealloc(
     list
 }
 
+#[no_mangle]
+pub extern "C" fn tapasco_job_param_alloc_memory(
+    mem: *mut TapascoOffchipMemory,
+    ptr: *mut u8,
+    bytes: usize,
+    to_device: bool,
+    from_device: bool,
+    free: bool,
+    uses_fixed: bool,
+    fixed: u64,
+    list: *mut JobList,
+) -> *mut JobList {
+    if list.is_null() {
+        warn!("Null pointer passed into tapasco_job_param_alloc_memory() as the list");
+        update_last_error(Error::NullPointerTLKM {});
+        return ptr::null_mut();
+    }
+
+    if mem.is_null() {
+        warn!("Null pointer passed into tapasco_job_param_alloc_memory() as the memory");
+        update_last_error(Error::NullPointerTLKM {});
+        return ptr::null_mut();
+    }
+
+    let m = unsafe { &*mem };
+
+    let v = unsafe { Box::from_raw(slice::from_raw_parts_mut(ptr, bytes)) };
+
+    let f = if uses_fixed { Some(fixed) } else { None };
+
+    let tl = unsafe { &mut *list };
+    tl.push(PEParameter::DataTransferAlloc(DataTransferAlloc {
+        data: v,
+        from_device: from_device,
+        to_device: to_device,
+        free: free,
+        memory: m.clone(),
+        fixed: f,
+    }));
+    list
+}
+
+#[no_mangle]
+pub extern "C" fn tapasco_job_param_prealloc_memory(
+    mem: *mut TapascoOffchipMemory,
+    ptr: *mut u8,
+    addr: DeviceAddress,
+    bytes: usize,
+    to_device: bool,
+    from_device: bool,
+    free: bool,
+    list: *mut JobList,
+) -> *mut JobList {
+    if list.is_null() {
+        warn!("Null pointer passed into tapasco_job_param_prealloc_memory() as the list");
+        update_last_error(Error::NullPointerTLKM {});
+        return ptr::null_mut();
+    }
+
+    if mem.is_null() {
+        warn!("Null pointer passed into tapasco_job_param_prealloc_memory() as the memory");
+        update_last_error(Error::NullPointerTLKM {});
+        return ptr::null_mut();
+    }
+
+    let m = unsafe { &*mem };
+
+    let v = unsafe { Box::from_raw(slice::from_raw_parts_mut(ptr, bytes)) };
+
+    let tl = unsafe { &mut *list };
+    tl.push(PEParameter::DataTransferPrealloc(DataTransferPrealloc {
+        data: v,
+        device_address: addr,
+        from_device: from_device,
+        to_device: to_device,
+        free: free,
+        memory: m.clone(),
+    }));
+    list
+}
+
 /////////////////
 // Handle Device Access
 /////////////////
@@ -527,6 +636,144 @@ pub extern "C" fn tapasco_device_num_pes(dev: *mut Device, id: PEId) -> isize {
     tl.num_pes(id) as isize
 }
 
+/// Utilization of the PE instance `slot`, see `Device::stats`.
+#[no_mangle]
+pub extern "C" fn tapasco_device_pe_stats(
+    dev: *mut Device,
+    slot: usize,
+    stats: *mut PEStatsSummary,
+) -> isize {
+    if dev.is_null() {
+        warn!("Null pointer passed into tapasco_device_pe_stats() as the device");
+        update_last_error(Error::NullPointerTLKM {});
+        return -1;
+    }
+
+    if stats.is_null() {
+        warn!("Null pointer passed into tapasco_device_pe_stats() as the stats");
+        update_last_error(Error::NullPointerTLKM {});
+        return -1;
+    }
+
+    let tl = unsafe { &*dev };
+    match tl.stats().context(DeviceError) {
+        Ok(x) => match x.pes.get(slot) {
+            Some(s) => {
+                unsafe {
+                    *stats = s.summary();
+                }
+                0
+            }
+            None => {
+                update_last_error(Error::PEIndex {
+                    idx: slot,
+                    len: x.pes.len(),
+                });
+                -1
+            }
+        },
+        Err(e) => {
+            update_last_error(e);
+            -1
+        }
+    }
+}
+
+/// Combined utilization of all PEs of type `id`, see `Device::stats`.
+#[no_mangle]
+pub extern "C" fn tapasco_device_type_stats(
+    dev: *mut Device,
+    id: PEId,
+    stats: *mut PEStatsSummary,
+) -> isize {
+    if dev.is_null() {
+        warn!("Null pointer passed into tapasco_device_type_stats() as the device");
+        update_last_error(Error::NullPointerTLKM {});
+        return -1;
+    }
+
+    if stats.is_null() {
+        warn!("Null pointer passed into tapasco_device_type_stats() as the stats");
+        update_last_error(Error::NullPointerTLKM {});
+        return -1;
+    }
+
+    let tl = unsafe { &*dev };
+    match tl.stats().context(DeviceError) {
+        Ok(x) => match x.types.get(&id) {
+            Some(s) => {
+                unsafe {
+                    *stats = s.summary();
+                }
+                0
+            }
+            None => {
+                update_last_error(Error::NoSuchPEType { id: id });
+                -1
+            }
+        },
+        Err(e) => {
+            update_last_error(e);
+            -1
+        }
+    }
+}
+
+#[no_mangle]
+pub extern "C" fn tapasco_device_reset_stats(dev: *mut Device) -> isize {
+    if dev.is_null() {
+        warn!("Null pointer passed into tapasco_device_reset_stats() as the device");
+        update_last_error(Error::NullPointerTLKM {});
+        return -1;
+    }
+
+    let tl = unsafe { &*dev };
+    match tl.reset_stats().context(DeviceError) {
+        Ok(_) => 0,
+        Err(e) => {
+            update_last_error(e);
+            -1
+        }
+    }
+}
+
+/// Returns 1 if the PE instance `slot` has been taken out of service, 0 otherwise.
+#[no_mangle]
+pub extern "C" fn tapasco_device_pe_faulty(dev: *mut Device, slot: usize) -> isize {
+    if dev.is_null() {
+        warn!("Null pointer passed into tapasco_device_pe_faulty() as the device");
+        update_last_error(Error::NullPointerTLKM {});
+        return -1;
+    }
+
+    let tl = unsafe { &mut *dev };
+    match tl.faulty_pes().context(DeviceError) {
+        Ok(x) => x.contains(&slot) as isize,
+        Err(e) => {
+            update_last_error(e);
+            -1
+        }
+    }
+}
+
+#[no_mangle]
+pub extern "C" fn tapasco_device_reset_faulty_pe(dev: *mut Device, slot: usize) -> isize {
+    if dev.is_null() {
+        warn!("Null pointer passed into tapasco_device_reset_faulty_pe() as the device");
+        update_last_error(Error::NullPointerTLKM {});
+        return -1;
+    }
+
+    let tl = unsafe { &mut *dev };
+    match tl.reset_faulty_pe(slot).context(DeviceError) {
+        Ok(_) => 0,
+        Err(e) => {
+            update_last_error(e);
+            -1
+        }
+    }
+}
+
 #[no_mangle]
 pub extern "C" fn tapasco_device_get_pe_id(dev: *mut Device, name: *const c_char) -> PEId {
     if dev.is_null() {
@@ -578,6 +825,223 @@ pub extern "C" fn tapasco_device_acquire_pe(dev: *mut Device, id: PEId) -> *mut
     }
 }
 
+/// Same as `tapasco_device_acquire_pe` but gives up after `timeout_ms` milliseconds.
+///
+/// Returns a null pointer if no PE became available in time.
+#[no_mangle]
+pub extern "C" fn tapasco_device_acquire_pe_timeout(
+    dev: *mut Device,
+    id: PEId,
+    timeout_ms: u64,
+) -> *mut Job {
+    if dev.is_null() {
+        warn!("Null pointer passed into tapasco_device_acquire_pe_timeout() as the device");
+        update_last_error(Error::NullPointerTLKM {});
+        return ptr::null_mut();
+    }
+
+    let tl = unsafe { &mut *dev };
+    match tl
+        .acquire_pe_timeout(id, Duration::from_millis(timeout_ms))
+        .context(DeviceError)
+    {
+        Ok(Some(x)) => std::boxed::Box::<Job>::into_raw(Box::new(x)),
+        Ok(None) => {
+            update_last_error(Error::PEUnavailable { id: id });
+            ptr::null_mut()
+        }
+        Err(e) => {
+            update_last_error(e);
+            ptr::null_mut()
+        }
+    }
+}
+
+/// Acquire a PE of any of the `len` types in `ids`, see `Device::acquire_any`.
+///
+/// Use `tapasco_job_pe_type` to find out which type has been acquired.
+#[no_mangle]
+pub extern "C" fn tapasco_device_acquire_any(
+    dev: *mut Device,
+    ids: *const PEId,
+    len: usize,
+) -> *mut Job {
+    if dev.is_null() {
+        warn!("Null pointer passed into tapasco_device_acquire_any() as the device");
+        update_last_error(Error::NullPointerTLKM {});
+        return ptr::null_mut();
+    }
+
+    if ids.is_null() {
+        warn!("Null pointer passed into tapasco_device_acquire_any() as the ids");
+        update_last_error(Error::NullPointerTLKM {});
+        return ptr::null_mut();
+    }
+
+    let ids_r = unsafe { slice::from_raw_parts(ids, len) };
+    let tl = unsafe { &mut *dev };
+    match tl.acquire_any(ids_r).context(DeviceError) {
+        Ok(x) => std::boxed::Box::<Job>::into_raw(Box::new(x)),
+        Err(e) => {
+            update_last_error(e);
+            ptr::null_mut()
+        }
+    }
+}
+
+/// Acquire several PEs at once, see `Device::acquire_pes`.
+///
+/// Requests `counts[i]` PEs of type `ids[i]` for every `i < len` and stores the jobs
+/// in request order in `jobs`. Returns the number of jobs or -1 on error.
+#[no_mangle]
+pub extern "C" fn tapasco_device_acquire_pes(
+    dev: *mut Device,
+    ids: *const PEId,
+    counts: *const usize,
+    len: usize,
+    jobs: *mut *mut Job,
+    jobs_len: usize,
+) -> isize {
+    let request = match pe_request("tapasco_device_acquire_pes", ids, counts, len) {
+        Some(x) => x,
+        None => return -1,
+    };
+    acquire_pes(
+        "tapasco_device_acquire_pes",
+        dev,
+        &request,
+        jobs,
+        jobs_len,
+        None,
+    )
+}
+
+/// Same as `tapasco_device_acquire_pes` but gives up after `timeout_ms` milliseconds.
+#[no_mangle]
+pub extern "C" fn tapasco_device_acquire_pes_timeout(
+    dev: *mut Device,
+    ids: *const PEId,
+    counts: *const usize,
+    len: usize,
+    jobs: *mut *mut Job,
+    jobs_len: usize,
+    timeout_ms: u64,
+) -> isize {
+    let request = match pe_request("tapasco_device_acquire_pes_timeout", ids, counts, len) {
+        Some(x) => x,
+        None => return -1,
+    };
+    acquire_pes(
+        "tapasco_device_acquire_pes_timeout",
+        dev,
+        &request,
+        jobs,
+        jobs_len,
+        Some(Duration::from_millis(timeout_ms)),
+    )
+}
+
+fn pe_request(
+    caller: &str,
+    ids: *const PEId,
+    counts: *const usize,
+    len: usize,
+) -> Option<Vec<(PEId, usize)>> {
+    if len == 0 {
+        return Some(Vec::new());
+    }
+    if ids.is_null() || counts.is_null() {
+        warn!("Null pointer passed into {}() as the request", caller);
+        update_last_error(Error::NullPointerTLKM {});
+        return None;
+    }
+    let ids_r = unsafe { slice::from_raw_parts(ids, len) };
+    let counts_r = unsafe { slice::from_raw_parts(counts, len) };
+    Some(
+        ids_r
+            .iter()
+            .cloned()
+            .zip(counts_r.iter().cloned())
+            .collect(),
+    )
+}
+
+fn acquire_pes(
+    caller: &str,
+    dev: *mut Device,
+    request: &[(PEId, usize)],
+    jobs: *mut *mut Job,
+    jobs_len: usize,
+    timeout: Option<Duration>,
+) -> isize {
+    if dev.is_null() {
+        warn!("Null pointer passed into {}() as the device", caller);
+        update_last_error(Error::NullPointerTLKM {});
+        return -1;
+    }
+
+    let total: usize = request.iter().map(|x| x.1).sum();
+    if jobs_len < total {
+        update_last_error(Error::JobsToShort { len: total });
+        return -1;
+    }
+    if total > 0 && jobs.is_null() {
+        warn!("Null pointer passed into {}() as the jobs", caller);
+        update_last_error(Error::NullPointerTLKM {});
+        return -1;
+    }
+
+    let tl = unsafe { &mut *dev };
+    let r = match timeout {
+        Some(t) => tl.acquire_pes_timeout(request, t),
+        None => tl.acquire_pes(request).map(Some),
+    };
+    match r.context(DeviceError) {
+        Ok(Some(x)) => {
+            let n = x.len();
+            if n > 0 {
+                let js = unsafe { slice::from_raw_parts_mut(jobs, jobs_len) };
+                for (j, job) in js.iter_mut().zip(x.into_iter()) {
+                    *j = std::boxed::Box::<Job>::into_raw(Box::new(job));
+                }
+            }
+            n as isize
+        }
+        Ok(None) => {
+            update_last_error(Error::PEsUnavailable {});
+            -1
+        }
+        Err(e) => {
+            update_last_error(e);
+            -1
+        }
+    }
+}
+
+/// Same as `tapasco_device_acquire_pe` but returns a null pointer immediately
+/// if all PEs of the given type are in use.
+#[no_mangle]
+pub extern "C" fn tapasco_device_try_acquire_pe(dev: *mut Device, id: PEId) -> *mut Job {
+    if dev.is_null() {
+        warn!("Null pointer passed into tapasco_device_try_acquire_pe() as the device");
+        update_last_error(Error::NullPointerTLKM {});
+        return ptr::null_mut();
+    }
+
+    let tl = unsafe { &mut *dev };
+    match tl.try_acquire_pe(id).context(DeviceError) {
+        Ok(Some(x)) => std::boxed::Box::<Job>::into_raw(Box::new(x)),
+        Ok(None) => {
+            update_last_error(Error::PEUnavailable { id: id });
+            ptr::null_mut()
+        }
+        Err(e) => {
+            update_last_error(e);
+            ptr::null_mut()
+        }
+    }
+}
+
 #[no_mangle]
 pub extern "C" fn tapasco_job_start(job: *mut Job, params: *mut *mut JobList) -> isize {
     if job.is_null() {
@@ -607,7 +1071,7 @@ pub extern "C" fn tapasco_job_start(job: *mut Job, params: *mut *mut JobList) ->
     let jl = *jl;
 
     let tl = unsafe { &mut *job };
-    match tl.start(jl).context(JobError) {
+    match tl.start(jl) {
         Ok(x) => {
             for d in x.into_iter() {
                 // Make sure Rust doesn't release the memory received from C
@@ -615,8 +1079,21 @@ pub extern "C" fn tapasco_job_start(job: *mut Job, params: *mut *mut JobList) ->
             }
             return 0;
         }
+        Err(crate::job::Error::StartFailed { source, buffers }) => {
+            // The buffers are owned by the caller again
+            for d in buffers.into_iter() {
+                let _p = std::boxed::Box::<[u8]>::into_raw(d);
+            }
+            update_last_error(Error::JobError {
+                source: crate::job::Error::StartFailed {
+                    source: source,
+                    buffers: Vec::new(),
+                },
+            });
+            return -1;
+        }
         Err(e) => {
-            update_last_error(e);
+            update_last_error(Error::JobError { source: e });
             return -1;
         }
     }
@@ -663,6 +1140,113 @@ pub extern "C" fn tapasco_job_release(
     }
 }
 
+/// Release the job if it finishes within `timeout_ms`, see `Job::release_timeout`.
+///
+/// Copy back buffers are left unchanged if the deadline expired.
+#[no_mangle]
+pub extern "C" fn tapasco_job_release_timeout(
+    job: *mut Job,
+    return_value: *mut u64,
+    release: bool,
+    timeout_ms: u64,
+) -> isize {
+    if job.is_null() {
+        warn!("Null pointer passed into tapasco_job_release_timeout() as the job");
+        update_last_error(Error::NullPointerTLKM {});
+        return -1;
+    }
+
+    let tl = unsafe { &mut *job };
+    match tl.release_timeout(
+        release,
+        !return_value.is_null(),
+        Duration::from_millis(timeout_ms),
+    ) {
+        Ok(x) => {
+            for d in x.1.into_iter() {
+                // Make sure Rust doesn't release the memory received from C
+                let _p = std::boxed::Box::<[u8]>::into_raw(d);
+            }
+            if !return_value.is_null() {
+                unsafe {
+                    *return_value = x.0;
+                }
+            }
+            0
+        }
+        Err(crate::job::Error::Timeout {
+            id,
+            timeout,
+            buffers,
+        }) => {
+            // The buffers are owned by the caller again
+            for d in buffers.into_iter() {
+                let _p = std::boxed::Box::<[u8]>::into_raw(d);
+            }
+            update_last_error(Error::JobError {
+                source: crate::job::Error::Timeout {
+                    id: id,
+                    timeout: timeout,
+                    buffers: Vec::new(),
+                },
+            });
+            -1
+        }
+        Err(e) => {
+            update_last_error(Error::JobError { source: e });
+            -1
+        }
+    }
+}
+
+/// Type of the PE executing the job, see `Job::type_id`.
+#[no_mangle]
+pub extern "C" fn tapasco_job_pe_type(job: *mut Job) -> PEId {
+    if job.is_null() {
+        warn!("Null pointer passed into tapasco_job_pe_type() as the job");
+        update_last_error(Error::NullPointerTLKM {});
+        return PEId::MAX;
+    }
+
+    let tl = unsafe { &*job };
+    match tl.type_id() {
+        Some(x) => x,
+        None => {
+            update_last_error(Error::JobError {
+                source: crate::job::Error::NoPEtoRelease {},
+            });
+            PEId::MAX
+        }
+    }
+}
+
+/// Abort the job without waiting for the PE, see `Job::abort`.
+///
+/// Copy back buffers are left unchanged.
+#[no_mangle]
+pub extern "C" fn tapasco_job_abort(job: *mut Job) -> isize {
+    if job.is_null() {
+        warn!("Null pointer passed into tapasco_job_abort() as the job");
+        update_last_error(Error::NullPointerTLKM {});
+        return -1;
+    }
+
+    let tl = unsafe { &mut *job };
+    match tl.abort().context(JobError) {
+        Ok(x) => {
+            for d in x.into_iter() {
+                // Make sure Rust doesn't release the memory received from C
+                let _p = std::boxed::Box::<[u8]>::into_raw(d);
+            }
+            0
+        }
+        Err(e) => {
+            update_last_error(e);
+            -1
+        }
+    }
+}
+
 ///////////////////
 // Memory handling
 ///////////////////
@@ -687,6 +1271,95 @@ pub extern "C" fn tapasco_get_default_memory(dev: *mut Device) -> *mut TapascoOf
     }
 }
 
+#[no_mangle]
+pub extern "C" fn tapasco_device_num_memories(dev: *mut Device) -> isize {
+    if dev.is_null() {
+        warn!("Null pointer passed into tapasco_device_num_memories() as the device");
+        update_last_error(Error::NullPointerTLKM {});
+        return -1;
+    }
+
+    let tl = unsafe { &mut *dev };
+    tl.memories().len() as isize
+}
+
+#[no_mangle]
+pub extern "C" fn tapasco_get_memory(dev: *mut Device, idx: usize) -> *mut TapascoOffchipMemory {
+    if dev.is_null() {
+        warn!("Null pointer passed into tapasco_get_memory() as the device");
+        update_last_error(Error::NullPointerTLKM {});
+        return ptr::null_mut();
+    }
+
+    let tl = unsafe { &mut *dev };
+    match tl.memories().get(idx) {
+        Some(x) => Box::into_raw(Box::new(x.clone())),
+        None => {
+            update_last_error(Error::MemoryIndex {
+                idx: idx,
+                len: tl.memories().len(),
+            });
+            ptr::null_mut()
+        }
+    }
+}
+
+#[no_mangle]
+pub extern "C" fn tapasco_get_memory_by_name(
+    dev: *mut Device,
+    name: *const c_char,
+) -> *mut TapascoOffchipMemory {
+    if dev.is_null() {
+        warn!("Null pointer passed into tapasco_get_memory_by_name() as the device");
+        update_last_error(Error::NullPointerTLKM {});
+        return ptr::null_mut();
+    }
+
+    if name.is_null() {
+        warn!("Null pointer passed into tapasco_get_memory_by_name() as the name");
+        update_last_error(Error::NullPointerTLKM {});
+        return ptr::null_mut();
+    }
+
+    let name_r = unsafe {
+        let s = CStr::from_ptr(name);
+        s.to_str().unwrap()
+    };
+
+    let tl = unsafe { &mut *dev };
+    match tl.memory(name_r).context(DeviceError) {
+        Ok(x) => Box::into_raw(Box::new(x)),
+        Err(e) => {
+            update_last_error(e);
+            ptr::null_mut()
+        }
+    }
+}
+
+#[no_mangle]
+pub extern "C" fn tapasco_memory_name(
+    mem: *mut TapascoOffchipMemory,
+    name: *mut c_char,
+    len: usize,
+) -> i32 {
+    if mem.is_null() {
+        warn!("Null pointer passed into tapasco_memory_name() as the memory");
+        update_last_error(Error::NullPointerTLKM {});
+        return -1;
+    }
+
+    let m = unsafe { &*mem };
+    let x = m.name();
+    if len <= x.len() {
+        update_last_error(Error::NameStringToShort { len: x.len() + 1 });
+        return -1;
+    }
+    let is = unsafe { slice::from_raw_parts_mut(name as *mut u8, len) };
+    is[..x.len()].copy_from_slice(x.as_bytes());
+    is[x.len()] = 0;
+    0
+}
+
 #[no_mangle]
 pub extern "C" fn tapasco_memory_destroy(t: *mut TapascoOffchipMemory) {
     unsafe {
@@ -771,6 +1444,36 @@ pub extern "C" fn tapasco_memory_allocate(
     }
 }
 
+/// Allocate memory and record `tag` as its origin, which is shown if the allocation leaks.
+#[no_mangle]
+pub extern "C" fn tapasco_memory_allocate_tagged(
+    mem: *mut TapascoOffchipMemory,
+    len: usize,
+    tag: *const c_char,
+) -> DeviceAddress {
+    if mem.is_null() || tag.is_null() {
+        warn!("Null pointer passed into tapasco_memory_allocate_tagged()");
+        update_last_error(Error::NullPointerTLKM {});
+        return DeviceAddress::MAX;
+    }
+
+    let tag = unsafe { CStr::from_ptr(tag) }.to_string_lossy();
+    let tl = unsafe { &mut *mem };
+    match tl
+        .allocator()
+        .lock()
+        .unwrap()
+        .allocate_tagged(len as u64, None, Some(&tag), None)
+        .context(AllocatorError)
+    {
+        Ok(x) => x,
+        Err(e) => {
+            update_last_error(e);
+            DeviceAddress::MAX
+        }
+    }
+}
+
 #[no_mangle]
 pub extern "C" fn tapasco_memory_allocate_fixed(
     mem: *mut TapascoOffchipMemory,
@@ -826,6 +1529,90 @@ pub extern "C" fn tapasco_memory_free(
     }
 }
 
+/// Free all allocations of the memory. Returns the number of freed allocations.
+#[no_mangle]
+pub extern "C" fn tapasco_memory_free_all(mem: *mut TapascoOffchipMemory) -> isize {
+    if mem.is_null() {
+        warn!("Null pointer passed into tapasco_memory_free_all() as the memory");
+        update_last_error(Error::NullPointerTLKM {});
+        return -1;
+    }
+
+    let tl = unsafe { &*mem };
+    match tl.free_all().context(DeviceError) {
+        Ok(x) => x as isize,
+        Err(e) => {
+            update_last_error(e);
+            -1
+        }
+    }
+}
+
+#[no_mangle]
+pub extern "C" fn tapasco_memory_stats(
+    mem: *mut TapascoOffchipMemory,
+    stats: *mut AllocatorStats,
+) -> isize {
+    if mem.is_null() || stats.is_null() {
+        warn!("Null pointer passed into tapasco_memory_stats()");
+        update_last_error(Error::NullPointerTLKM {});
+        return -1;
+    }
+
+    let tl = unsafe { &*mem };
+    match tl.stats().context(DeviceError) {
+        Ok(x) => {
+            unsafe { *stats = x };
+            0
+        }
+        Err(e) => {
+            update_last_error(e);
+            -1
+        }
+    }
+}
+
+/// Write the live allocations of the memory into `info`.
+///
+/// Returns the number of entries written. The number of allocations can be
+/// retrieved through `tapasco_memory_stats`. `info` may only be null if `len` is 0.
+#[no_mangle]
+pub extern "C" fn tapasco_memory_dump(
+    mem: *mut TapascoOffchipMemory,
+    info: *mut AllocationInfo,
+    len: usize,
+) -> isize {
+    if mem.is_null() {
+        warn!("Null pointer passed into tapasco_memory_dump() as the memory");
+        update_last_error(Error::NullPointerTLKM {});
+        return -1;
+    }
+    if info.is_null() && len > 0 {
+        warn!("Null pointer passed into tapasco_memory_dump() as the info");
+        update_last_error(Error::NullPointerTLKM {});
+        return -1;
+    }
+
+    let tl = unsafe { &*mem };
+    match tl.dump().context(DeviceError) {
+        Ok(x) => {
+            if len < x.len() {
+                update_last_error(Error::AllocationInfoToShort { len: x.len() });
+                return -1;
+            }
+            if x.len() > 0 {
+                let is = unsafe { slice::from_raw_parts_mut(info, len) };
+                is[..x.len()].copy_from_slice(&x);
+            }
+            x.len() as isize
+        }
+        Err(e) => {
+            update_last_error(e);
+            -1
+        }
+    }
+}
+
 ///////////////////////////////////
 // Status Information
 ///////////////////////////////////
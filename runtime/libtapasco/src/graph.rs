@@ -0,0 +1,664 @@
+/*
+ * Copyright (c) 2014-2020 Embedded Systems and Applications, TU Darmstadt.
+ *
+ * This file is part of TaPaSCo
+ * (see https://github.com/esa-tu-darmstadt/tapasco).
+ *
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU Lesser General Public License as published by
+ * the Free Software Foundation, either version 3 of the License, or
+ * (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU Lesser General Public License for more details.
+ *
+ * You should have received a copy of the GNU Lesser General Public License
+ * along with this program. If not, see <http://www.gnu.org/licenses/>.
+ */
+
+//! Execution of dependent PE launches and transfers.
+//!
+//! A [`Graph`] consists of nodes which launch a PE or transfer data between the host
+//! and a device buffer. Buffers connect the nodes: Every buffer is written by exactly one
+//! node and read by any number of nodes. Intermediate buffers stay on the device, they
+//! are allocated when their producer starts and freed once the last consumer finished.
+//!
+//! [`Graph`]: struct.Graph.html
+
+use crate::buffer::Allocation;
+use crate::device::Device;
+use crate::device::DeviceSize;
+use crate::device::OffchipMemory;
+use crate::device::PEParameter;
+use crate::job::Job;
+use crate::pe::PEId;
+use crate::scheduler::ReleaseSignal;
+use crossbeam::channel::unbounded;
+use snafu::ResultExt;
+use std::collections::HashMap;
+use std::collections::VecDeque;
+use std::sync::Arc;
+
+#[derive(Debug, Snafu)]
+pub enum Error {
+    #[snafu(display("Device Error: {}", source))]
+    DeviceError { source: crate::device::Error },
+
+    #[snafu(display("Scheduler Error: {}", source))]
+    SchedulerError { source: crate::scheduler::Error },
+
+    #[snafu(display("All PEs of type {} are faulty.", id))]
+    AllFaulty { id: PEId },
+
+    #[snafu(display("Job Error in node {}: {}", node, source))]
+    JobError {
+        source: crate::job::Error,
+        node: NodeId,
+    },
+
+    #[snafu(display("DMA Error in node {}: {}", node, source))]
+    DMAError {
+        source: crate::dma::Error,
+        node: NodeId,
+    },
+
+    #[snafu(display("Could not allocate buffer {}: {}", buffer, source))]
+    BufferError {
+        source: crate::buffer::Error,
+        buffer: BufferId,
+    },
+
+    #[snafu(display("Buffer {} does not exist.", buffer))]
+    UnknownBuffer { buffer: BufferId },
+
+    #[snafu(display("Buffer {} is already written by node {}.", buffer, node))]
+    MultipleProducers { buffer: BufferId, node: NodeId },
+
+    #[snafu(display("Buffer {} is read but never written.", buffer))]
+    NoProducer { buffer: BufferId },
+
+    #[snafu(display("Node {} reads and writes buffer {}.", node, buffer))]
+    ReadWrite { buffer: BufferId, node: NodeId },
+
+    #[snafu(display("Transfer of {} bytes exceeds buffer of {} bytes.", len, size))]
+    TransferTooLarge { len: usize, size: DeviceSize },
+
+    #[snafu(display("The graph contains a cycle."))]
+    Cycle {},
+
+    #[snafu(display("A graph execution thread panicked."))]
+    ThreadPanicked {},
+}
+
+type Result<T, E = Error> = std::result::Result<T, E>;
+
+pub type NodeId = usize;
+pub type BufferId = usize;
+
+/// Argument of a PE launch in a [`Graph`].
+///
+/// [`Graph`]: struct.Graph.html
+#[derive(Debug)]
+pub enum GraphArg {
+    /// Passed to the PE unchanged.
+    Param(PEParameter),
+    /// Address of a buffer the PE reads.
+    Input(BufferId),
+    /// Address of a buffer the PE writes.
+    Output(BufferId),
+}
+
+#[derive(Debug)]
+enum Operation {
+    Launch { id: PEId, args: Vec<GraphArg> },
+    Upload { data: Box<[u8]>, buffer: BufferId },
+    Download { buffer: BufferId },
+}
+
+#[derive(Debug)]
+struct Node {
+    inputs: Vec<BufferId>,
+    outputs: Vec<BufferId>,
+}
+
+#[derive(Debug)]
+struct Buffer {
+    memory: Arc<OffchipMemory>,
+    size: DeviceSize,
+    producer: Option<NodeId>,
+    consumers: Vec<NodeId>,
+}
+
+/// Result of a finished node.
+#[derive(Debug)]
+enum Outcome {
+    ReturnValue(u64),
+    Data(Box<[u8]>),
+    Done,
+}
+
+/// Dependency graph of PE launches and transfers, see the [module documentation].
+///
+/// [module documentation]: index.html
+#[derive(Debug, Default)]
+pub struct Graph {
+    ops: Vec<Operation>,
+    nodes: Vec<Node>,
+    buffers: Vec<Buffer>,
+}
+
+/// Return values and downloaded data of an executed [`Graph`].
+///
+/// [`Graph`]: struct.Graph.html
+#[derive(Debug, Default)]
+pub struct GraphResult {
+    return_values: HashMap<NodeId, u64>,
+    data: HashMap<NodeId, Box<[u8]>>,
+}
+
+impl GraphResult {
+    /// Return value of a PE launch.
+    pub fn return_value(&self, node: NodeId) -> Option<u64> {
+        self.return_values.get(&node).cloned()
+    }
+
+    /// Data of a download node.
+    pub fn data(&self, node: NodeId) -> Option<&[u8]> {
+        self.data.get(&node).map(|x| &x[..])
+    }
+
+    pub fn take_data(&mut self, node: NodeId) -> Option<Box<[u8]>> {
+        self.data.remove(&node)
+    }
+}
+
+impl Graph {
+    pub fn new() -> Graph {
+        Graph::default()
+    }
+
+    /// Declare a device buffer of `size` bytes on `memory`. No memory is allocated
+    /// before the graph is executed.
+    pub fn buffer(&mut self, memory: &Arc<OffchipMemory>, size: DeviceSize) -> BufferId {
+        self.buffers.push(Buffer {
+            memory: memory.clone(),
+            size: size,
+            producer: None,
+            consumers: Vec::new(),
+        });
+        self.buffers.len() - 1
+    }
+
+    /// Launch a PE of type `id` once all input buffers have been written.
+    pub fn launch(&mut self, id: PEId, args: Vec<GraphArg>) -> Result<NodeId> {
+        let mut inputs = Vec::new();
+        let mut outputs = Vec::new();
+        for arg in args.iter() {
+            match arg {
+                GraphArg::Input(b) => inputs.push(*b),
+                GraphArg::Output(b) => outputs.push(*b),
+                GraphArg::Param(_) => (),
+            }
+        }
+        self.add(Operation::Launch { id: id, args: args }, inputs, outputs)
+    }
+
+    /// Copy `data` to the start of `buffer`.
+    pub fn upload(&mut self, data: Box<[u8]>, buffer: BufferId) -> Result<NodeId> {
+        let size = self.get(buffer)?.size;
+        ensure!(
+            data.len() as DeviceSize <= size,
+            TransferTooLarge {
+                len: data.len(),
+                size: size
+            }
+        );
+        self.add(
+            Operation::Upload {
+                data: data,
+                buffer: buffer,
+            },
+            Vec::new(),
+            vec![buffer],
+        )
+    }
+
+    /// Copy the whole `buffer` back to the host once it has been written.
+    pub fn download(&mut self, buffer: BufferId) -> Result<NodeId> {
+        self.add(
+            Operation::Download { buffer: buffer },
+            vec![buffer],
+            Vec::new(),
+        )
+    }
+
+    fn get(&self, buffer: BufferId) -> Result<&Buffer> {
+        match self.buffers.get(buffer) {
+            Some(b) => Ok(b),
+            None => Err(Error::UnknownBuffer { buffer: buffer }),
+        }
+    }
+
+    fn add(
+        &mut self,
+        op: Operation,
+        mut inputs: Vec<BufferId>,
+        mut outputs: Vec<BufferId>,
+    ) -> Result<NodeId> {
+        let node = self.nodes.len();
+        inputs.sort();
+        inputs.dedup();
+        outputs.sort();
+        outputs.dedup();
+        for b in inputs.iter().chain(outputs.iter()) {
+            self.get(*b)?;
+        }
+        for b in outputs.iter() {
+            ensure!(
+                !inputs.contains(b),
+                ReadWrite {
+                    buffer: *b,
+                    node: node
+                }
+            );
+            if let Some(p) = self.buffers[*b].producer {
+                return Err(Error::MultipleProducers {
+                    buffer: *b,
+                    node: p,
+                });
+            }
+        }
+
+        for b in outputs.iter() {
+            self.buffers[*b].producer = Some(node);
+        }
+        for b in inputs.iter() {
+            self.buffers[*b].consumers.push(node);
+        }
+        self.ops.push(op);
+        self.nodes.push(Node {
+            inputs: inputs,
+            outputs: outputs,
+        });
+        Ok(node)
+    }
+
+    /// Number of unfinished producers every node waits for.
+    fn dependencies(&self) -> Result<Vec<usize>> {
+        let mut deps = Vec::new();
+        for n in self.nodes.iter() {
+            for b in n.inputs.iter() {
+                ensure!(
+                    self.buffers[*b].producer.is_some(),
+                    NoProducer { buffer: *b }
+                );
+            }
+            deps.push(n.inputs.len());
+        }
+
+        // Check that every node can become ready.
+        let mut pending = deps.clone();
+        let mut ready: Vec<NodeId> = (0..pending.len()).filter(|n| pending[*n] == 0).collect();
+        let mut done = 0;
+        while let Some(n) = ready.pop() {
+            done += 1;
+            for c in self.consumers(n) {
+                pending[c] -= 1;
+                if pending[c] == 0 {
+                    ready.push(c);
+                }
+            }
+        }
+        ensure!(done == self.nodes.len(), Cycle {});
+        Ok(deps)
+    }
+
+    /// Nodes reading a buffer written by `node`.
+    fn consumers(&self, node: NodeId) -> Vec<NodeId> {
+        self.nodes[node]
+            .outputs
+            .iter()
+            .flat_map(|b| self.buffers[*b].consumers.iter().cloned())
+            .collect()
+    }
+
+    /// Run all nodes on `device`.
+    ///
+    /// Nodes are started as soon as all their inputs have been written, independent
+    /// nodes run in parallel. PE launches are only started once a PE is free, so at
+    /// most one thread per PE is used for them. If a node fails, no further nodes are
+    /// started and the first error is returned once the running nodes have finished.
+    pub fn execute(mut self, device: &Device) -> Result<GraphResult> {
+        let mut pending = self.dependencies()?;
+        let mut readers: Vec<usize> = self.buffers.iter().map(|b| b.consumers.len()).collect();
+        let mut allocations: Vec<Option<Arc<Allocation>>> = vec![None; self.buffers.len()];
+        let mut ready: VecDeque<NodeId> = (0..pending.len()).filter(|n| pending[*n] == 0).collect();
+        let mut result = GraphResult::default();
+        let mut first_error = None;
+        let mut ops: Vec<Option<Operation>> = self.ops.drain(..).map(Some).collect();
+        let (finished, completions) = unbounded();
+        // Notified on PE releases and finished nodes.
+        let released = Arc::new(ReleaseSignal::default());
+        device.add_listener(&released).context(DeviceError)?;
+
+        let graph = &self;
+        crossbeam::scope(|s| -> Result<()> {
+            let mut running = 0;
+            loop {
+                let seen = released.generation().context(SchedulerError)?;
+                let mut blocked = VecDeque::new();
+                while first_error.is_none() {
+                    let n = match ready.pop_front() {
+                        Some(n) => n,
+                        None => break,
+                    };
+                    match graph.start(n, &mut ops, &mut allocations, device) {
+                        Ok(Some(task)) => {
+                            trace!("Starting graph node {}.", n);
+                            let finished = finished.clone();
+                            let released = released.clone();
+                            running += 1;
+                            s.spawn(move |_| {
+                                let _ = finished.send((n, task.run(n)));
+                                let _ = released.notify();
+                            });
+                        }
+                        Ok(None) => blocked.push_back(n),
+                        Err(e) => first_error = Some(e),
+                    }
+                }
+                ready.append(&mut blocked);
+
+                let (n, r) = match completions.try_recv() {
+                    Ok(x) => x,
+                    Err(_) => {
+                        if running == 0 {
+                            if first_error.is_some() || ready.is_empty() {
+                                break;
+                            }
+                            // Nothing left that could release a PE for the waiting launches.
+                            if let Err(e) = graph.check_healthy(&ready, &ops, device) {
+                                first_error = Some(e);
+                                break;
+                            }
+                        }
+                        released.wait(seen, None).context(SchedulerError)?;
+                        continue;
+                    }
+                };
+                running -= 1;
+                trace!("Graph node {} finished.", n);
+                match r {
+                    Ok(Outcome::ReturnValue(v)) => {
+                        result.return_values.insert(n, v);
+                    }
+                    Ok(Outcome::Data(d)) => {
+                        result.data.insert(n, d);
+                    }
+                    Ok(Outcome::Done) => (),
+                    Err(e) => {
+                        if first_error.is_none() {
+                            first_error = Some(e);
+                        }
+                    }
+                }
+
+                // Free buffers without further readers.
+                let node = &graph.nodes[n];
+                for b in node.inputs.iter() {
+                    readers[*b] -= 1;
+                    if readers[*b] == 0 {
+                        allocations[*b] = None;
+                    }
+                }
+                for b in node.outputs.iter() {
+                    if readers[*b] == 0 {
+                        allocations[*b] = None;
+                    }
+                }
+                for c in graph.consumers(n) {
+                    pending[c] -= 1;
+                    if pending[c] == 0 {
+                        ready.push_back(c);
+                    }
+                }
+            }
+            Ok(())
+        })
+        .map_err(|_| Error::ThreadPanicked {})??;
+
+        match first_error {
+            Some(e) => Err(e),
+            None => Ok(result),
+        }
+    }
+
+    /// Start node `n` unless it is a PE launch and no PE is free.
+    fn start(
+        &self,
+        n: NodeId,
+        ops: &mut [Option<Operation>],
+        allocations: &mut [Option<Arc<Allocation>>],
+        device: &Device,
+    ) -> Result<Option<Task>> {
+        let job = match &ops[n] {
+            Some(Operation::Launch { id, .. }) => match device.try_acquire_pe(*id) {
+                Ok(Some(job)) => Some(job),
+                Ok(None) => return Ok(None),
+                Err(e) => return Err(Error::DeviceError { source: e }),
+            },
+            _ => None,
+        };
+        let op = ops[n].take().unwrap();
+        self.prepare(n, op, job, allocations).map(Some)
+    }
+
+    /// Fail if a launch waiting in `ready` can never get a PE.
+    fn check_healthy(
+        &self,
+        ready: &VecDeque<NodeId>,
+        ops: &[Option<Operation>],
+        device: &Device,
+    ) -> Result<()> {
+        for n in ready.iter() {
+            if let Some(Operation::Launch { id, .. }) = &ops[*n] {
+                ensure!(
+                    device.healthy_pes(*id).context(DeviceError)? > 0,
+                    AllFaulty { id: *id }
+                );
+            }
+        }
+        Ok(())
+    }
+
+    /// Allocate the outputs of node `n` and resolve its buffer arguments.
+    fn prepare(
+        &self,
+        n: NodeId,
+        op: Operation,
+        job: Option<Job>,
+        allocations: &mut [Option<Arc<Allocation>>],
+    ) -> Result<Task> {
+        for b in self.nodes[n].outputs.iter() {
+            let buffer = &self.buffers[*b];
+            let a =
+                Allocation::new(&buffer.memory, buffer.size).context(BufferError { buffer: *b })?;
+            allocations[*b] = Some(Arc::new(a));
+        }
+        let alloc = |b: BufferId| allocations[b].clone().unwrap();
+        Ok(match op {
+            Operation::Launch { args, .. } => Task::Launch {
+                job: job.unwrap(),
+                args: args
+                    .into_iter()
+                    .map(|arg| match arg {
+                        GraphArg::Param(p) => p,
+                        GraphArg::Input(b) | GraphArg::Output(b) => PEParameter::Buffer(alloc(b)),
+                    })
+                    .collect(),
+            },
+            Operation::Upload { data, buffer } => Task::Upload {
+                data: data,
+                buffer: alloc(buffer),
+            },
+            Operation::Download { buffer } => Task::Download {
+                buffer: alloc(buffer),
+            },
+        })
+    }
+}
+
+/// Node operation with allocated buffers, executed on a separate thread.
+#[derive(Debug)]
+enum Task {
+    Launch {
+        job: Job,
+        args: Vec<PEParameter>,
+    },
+    Upload {
+        data: Box<[u8]>,
+        buffer: Arc<Allocation>,
+    },
+    Download {
+        buffer: Arc<Allocation>,
+    },
+}
+
+impl Task {
+    fn run(self, n: NodeId) -> Result<Outcome> {
+        match self {
+            Task::Launch { mut job, args } => {
+                job.start(args).context(JobError { node: n })?;
+                let (rv, _) = job.release(true, true).context(JobError { node: n })?;
+                Ok(Outcome::ReturnValue(rv))
+            }
+            Task::Upload { data, buffer } => {
+                buffer
+                    .memory()
+                    .dma()
+                    .copy_to(&data, *buffer.address())
+                    .context(DMAError { node: n })?;
+                Ok(Outcome::Done)
+            }
+            Task::Download { buffer } => {
+                let mut data = vec![0u8; *buffer.size() as usize].into_boxed_slice();
+                buffer
+                    .memory()
+                    .dma()
+                    .copy_from(*buffer.address(), &mut data)
+                    .context(DMAError { node: n })?;
+                Ok(Outcome::Data(data))
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod graph_tests {
+    use crate::device::PEParameter;
+    use crate::graph::{Graph, GraphArg};
+    use crate::sim::testing::Result;
+    use crate::sim::{PEContext, SimulatedDevice, SimulatedPE, SimulatedTLKM};
+    use crate::tlkm::{tlkm_access, TLKM};
+    use std::collections::HashMap;
+    use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+    use std::sync::Arc;
+    use std::thread;
+    use std::time::Duration;
+
+    #[test]
+    fn graph_executor() -> Result<()> {
+        let copy_increment = |pe: &mut PEContext| {
+            let len = pe.arg(2) as usize;
+            let mut data = vec![0u8; len];
+            pe.read_memory(pe.arg(0), &mut data);
+            data.iter_mut().for_each(|x| *x = x.wrapping_add(1));
+            pe.write_memory(pe.arg(1), &data);
+            len as u64
+        };
+        let copy = SimulatedPE::new(15, "sim:copy_increment", copy_increment);
+        let started = Arc::new(AtomicUsize::new(0));
+        let open = Arc::new(AtomicBool::new(false));
+        let (s, o) = (started.clone(), open.clone());
+        let gated = SimulatedPE::new(16, "sim:gated_copy", move |pe: &mut PEContext| {
+            s.fetch_add(1, Ordering::SeqCst);
+            while !o.load(Ordering::SeqCst) {
+                thread::sleep(Duration::from_millis(1));
+            }
+            copy_increment(pe)
+        });
+        let sim = SimulatedTLKM::new(vec![SimulatedDevice::new("pcie").pe(copy, 2).pe(gated, 1)]);
+        let tlkm = TLKM::with_driver(Arc::new(sim))?;
+        let mut dev = tlkm.device_alloc(0, &HashMap::new())?;
+        dev.change_access(tlkm_access::TlkmAccessExclusive)?;
+        let mem = dev.default_memory()?;
+        let len = 4096;
+        let copy_args = |from, to| {
+            vec![
+                GraphArg::Input(from),
+                GraphArg::Output(to),
+                GraphArg::Param(PEParameter::Single64(len as u64)),
+            ]
+        };
+
+        let mut graph = Graph::new();
+        let bufs: Vec<_> = (0..4).map(|_| graph.buffer(&mem, len as u64)).collect();
+        // Nodes may be added in any order.
+        let second = graph.launch(15, copy_args(bufs[1], bufs[2]))?;
+        let first = graph.launch(15, copy_args(bufs[0], bufs[1]))?;
+        let side = graph.launch(15, copy_args(bufs[0], bufs[3]))?;
+        graph.upload(vec![7u8; len].into_boxed_slice(), bufs[0])?;
+        let chained = graph.download(bufs[2])?;
+        let direct = graph.download(bufs[3])?;
+        assert!(graph
+            .upload(vec![0u8; len].into_boxed_slice(), bufs[0])
+            .is_err());
+
+        let mut result = graph.execute(&dev)?;
+        for n in &[first, second, side] {
+            assert_eq!(result.return_value(*n), Some(len as u64));
+        }
+        assert!(result.data(chained).unwrap().iter().all(|x| *x == 9));
+        assert!(result.take_data(direct).unwrap().iter().all(|x| *x == 8));
+        assert_eq!(mem.stats()?.allocations, 0);
+
+        // Intermediate buffers are freed as soon as they have been read, launches
+        // without a free PE do not start a thread waiting for one.
+        let mut graph = Graph::new();
+        let bufs: Vec<_> = (0..3).map(|_| graph.buffer(&mem, len as u64)).collect();
+        graph.upload(vec![7u8; len].into_boxed_slice(), bufs[0])?;
+        graph.launch(15, copy_args(bufs[0], bufs[1]))?;
+        graph.launch(15, copy_args(bufs[1], bufs[2]))?;
+        let downloads = (0..4)
+            .map(|_| {
+                let out = graph.buffer(&mem, len as u64);
+                graph.launch(16, copy_args(bufs[2], out))?;
+                graph.download(out)
+            })
+            .collect::<std::result::Result<Vec<_>, _>>()?;
+        let dev = Arc::new(dev);
+        let d = dev.clone();
+        let t = thread::spawn(move || graph.execute(&d).map_err(|e| e.to_string()));
+        while started.load(Ordering::SeqCst) == 0 {
+            thread::yield_now();
+        }
+        assert_eq!(mem.stats()?.allocations, 2);
+        assert_eq!(dev.waiting(16)?, 0);
+        open.store(true, Ordering::SeqCst);
+        let result = t.join().unwrap()?;
+        for n in downloads {
+            assert!(result.data(n).unwrap().iter().all(|x| *x == 10));
+        }
+        assert_eq!(started.load(Ordering::SeqCst), 4);
+        assert_eq!(mem.stats()?.allocations, 0);
+
+        let mut cyclic = Graph::new();
+        let (a, b) = (cyclic.buffer(&mem, 64), cyclic.buffer(&mem, 64));
+        cyclic.launch(15, copy_args(a, b))?;
+        cyclic.launch(15, copy_args(b, a))?;
+        assert!(cyclic.execute(&dev).is_err());
+        Ok(())
+    }
+}
@@ -18,16 +18,18 @@
  * along with this program. If not, see <http://www.gnu.org/licenses/>.
  */
 
-use crate::tlkm::tlkm_ioctl_reg_interrupt;
+use crate::reactor::Reactor;
 use crate::tlkm::tlkm_register_interrupt;
+use crate::tlkm::DeviceDriver;
+use nix::poll::{poll, PollFd, PollFlags};
 use nix::sys::eventfd::eventfd;
 use nix::sys::eventfd::EfdFlags;
 use nix::unistd::close;
 use nix::unistd::read;
 use snafu::ResultExt;
-use std::fs::File;
 use std::os::unix::io::RawFd;
-use std::os::unix::prelude::*;
+use std::task::{Context, Poll, Waker};
+use std::time::{Duration, Instant};
 
 #[derive(Debug, Snafu)]
 pub enum Error {
@@ -39,6 +41,12 @@ pub enum Error {
 
     #[snafu(display("Could not register eventfd with driver: {}", source))]
     ErrorEventFDRegister { source: nix::Error },
+
+    #[snafu(display("Error waiting for interrupt eventfd: {}", source))]
+    ErrorEventFDPoll { source: nix::Error },
+
+    #[snafu(display("Could not register eventfd with reactor: {}", source))]
+    ErrorReactor { source: crate::reactor::Error },
 }
 
 type Result<T, E = Error> = std::result::Result<T, E>;
@@ -59,7 +67,11 @@ impl Drop for Interrupt {
 /// Registers the eventfd with the driver and makes sure to release it after use.
 /// Supports blocking of the wait_for_interrupt method.
 impl Interrupt {
-    pub fn new(tlkm_file: &File, interrupt_id: usize, blocking: bool) -> Result<Interrupt> {
+    pub fn new(
+        tlkm_file: &dyn DeviceDriver,
+        interrupt_id: usize,
+        blocking: bool,
+    ) -> Result<Interrupt> {
         let fd = if blocking {
             eventfd(0, EfdFlags::empty()).context(ErrorEventFD)?
         } else {
@@ -70,10 +82,9 @@ impl Interrupt {
             pe_id: interrupt_id as i32,
         };
 
-        unsafe {
-            tlkm_ioctl_reg_interrupt(tlkm_file.as_raw_fd(), &mut ioctl_fd)
-                .context(ErrorEventFDRegister)?;
-        };
+        tlkm_file
+            .register_interrupt(&mut ioctl_fd)
+            .context(ErrorEventFDRegister)?;
 
         Ok(Interrupt { interrupt: fd })
     }
@@ -98,7 +109,7 @@ impl Interrupt {
                             if e_no_matched != nix::errno::Errno::EAGAIN {
                                 r.context(ErrorEventFDRead)?;
                             } else {
-                                std::thread::yield_now();
+                                self.wait_readable(-1)?;
                             }
                         }
                         None => {
@@ -110,6 +121,66 @@ impl Interrupt {
         }
     }
 
+    /// Wait for an interrupt for at most `timeout`.
+    ///
+    /// Returns the number of interrupts or `None` if none occured in time.
+    pub fn wait_for_interrupt_timeout(&self, timeout: Duration) -> Result<Option<u64>> {
+        let deadline = Instant::now() + timeout;
+        loop {
+            match self.check_for_interrupt()? {
+                0 => (),
+                n => return Ok(Some(n)),
+            }
+            let now = Instant::now();
+            if now >= deadline {
+                return Ok(None);
+            }
+            // Round up to avoid spinning on sub-millisecond remainders.
+            let ms = (deadline - now).as_micros().saturating_add(999) / 1000;
+            self.wait_readable(std::cmp::min(ms, i32::MAX as u128) as i32)?;
+        }
+    }
+
+    /// Sleep until the eventfd becomes readable or `timeout_ms` expired. Waits
+    /// indefinitely for a negative timeout.
+    fn wait_readable(&self, timeout_ms: i32) -> Result<()> {
+        let mut fds = [PollFd::new(self.interrupt, PollFlags::POLLIN)];
+        match poll(&mut fds, timeout_ms) {
+            Ok(_) => Ok(()),
+            Err(nix::Error::Sys(nix::errno::Errno::EINTR)) => Ok(()),
+            Err(e) => Err(Error::ErrorEventFDPoll { source: e }),
+        }
+    }
+
+    /// Asynchronous version of `wait_for_interrupt`.
+    ///
+    /// Returns the number of interrupts if any occured, otherwise `cx` is woken
+    /// through the `reactor` once the next interrupt arrives.
+    pub fn poll_interrupt(&self, reactor: &Reactor, cx: &mut Context) -> Poll<Result<u64>> {
+        match self.check_for_interrupt() {
+            Ok(0) => (),
+            x => return Poll::Ready(x),
+        }
+        if let Err(e) = reactor.register(self.interrupt, cx.waker()) {
+            return Poll::Ready(Err(Error::ErrorReactor { source: e }));
+        }
+        Poll::Pending
+    }
+
+    /// Wake `waker` through the `reactor` once the next interrupt arrives.
+    ///
+    /// Unlike `poll_interrupt` the eventfd is not read.
+    pub fn register_waker(&self, reactor: &Reactor, waker: &Waker) -> Result<()> {
+        reactor
+            .register(self.interrupt, waker)
+            .context(ErrorReactor)
+    }
+
+    /// Remove a pending registration of `poll_interrupt`.
+    pub fn cancel_poll(&self, reactor: &Reactor) -> Result<()> {
+        reactor.deregister(self.interrupt).context(ErrorReactor)
+    }
+
     /// Check if any interrupts have occured
     ///
     /// Returns the number of interrupts that have occured since the last time
@@ -20,12 +20,21 @@
 
 use crate::device::DataTransferAlloc;
 use crate::device::DataTransferPrealloc;
+use crate::device::DeviceAddress;
+use crate::device::OffchipMemory;
 use crate::device::PEParameter;
 use crate::pe::CopyBack;
+use crate::pe::PEId;
 use crate::pe::PE;
 use crate::scheduler::Scheduler;
 use snafu::ResultExt;
+use std::future::Future;
+use std::mem;
+use std::pin::Pin;
+use std::sync::atomic::{AtomicU64, Ordering};
 use std::sync::Arc;
+use std::task::{Context, Poll};
+use std::time::Duration;
 
 impl<T> From<std::sync::PoisonError<T>> for Error {
     fn from(_error: std::sync::PoisonError<T>) -> Self {
@@ -59,12 +68,43 @@ pub enum Error {
     ))]
     UnsupportedTransferParameter { arg: PEParameter },
 
+    #[snafu(display(
+        "Parameter {} has not been converted before the register write stage.",
+        index
+    ))]
+    UnconvertedParameter { index: usize },
+
     #[snafu(display("Scheduler Error: {}", source))]
     SchedulerError { source: crate::scheduler::Error },
 
     #[snafu(display("Local memory requested on PE without local memory"))]
     NoLocalMemory {},
 
+    #[snafu(display(
+        "Could not start job, {} buffers returned: {}",
+        buffers.len(),
+        source
+    ))]
+    StartFailed {
+        source: Box<Error>,
+        buffers: Vec<Box<[u8]>>,
+    },
+
+    #[snafu(display(
+        "Job {} did not finish within {:?}, {} buffers returned.",
+        id,
+        timeout,
+        buffers.len()
+    ))]
+    Timeout {
+        id: u64,
+        timeout: Duration,
+        buffers: Vec<Box<[u8]>>,
+    },
+
+    #[snafu(display("This Job does not contain a PE which could be started."))]
+    NoPEtoStart {},
+
     #[snafu(display("This Job does not contain a PE which could be released."))]
     NoPEtoRelease {},
 
@@ -78,17 +118,37 @@ type Result<T, E = Error> = std::result::Result<T, E>;
 /// Deals with data transfer parameter handling.
 #[derive(Debug)]
 pub struct Job {
+    id: u64,
     pe: Option<PE>,
     scheduler: Arc<Scheduler>,
 }
 
+static NEXT_JOB_ID: AtomicU64 = AtomicU64::new(0);
+
 /// Release the PE if it's no longer needed.
+///
+/// Waits at most `job.drop_timeout_ms` for the PE to finish. Errors are logged. If the
+/// PE could not be released it is taken out of service.
 impl Drop for Job {
     fn drop(&mut self) {
         if self.pe.is_some() {
-            match self.release(true, false) {
+            let timeout = self.scheduler.drop_timeout();
+            match self.release_timeout(true, false, timeout) {
                 Ok(_) => (),
-                Err(e) => panic!("{}", e),
+                Err(Error::Timeout { .. }) => {
+                    error!(
+                        "Dropped job {} did not finish within {:?}.",
+                        self.id, timeout
+                    )
+                }
+                Err(e) => {
+                    error!("Could not release job {}: {}", self.id, e);
+                    if let Some(pe) = self.pe.take() {
+                        if let Err(e) = self.scheduler.quarantine_pe(pe) {
+                            error!("Could not quarantine PE of job {}: {}", self.id, e);
+                        }
+                    }
+                }
             }
         }
     }
@@ -102,129 +162,187 @@ impl Job {
     /// [`acquire_pe`]: ../device/struct.Device.html#method.acquire_pe
     pub fn new(pe: PE, scheduler: &Arc<Scheduler>) -> Job {
         Job {
+            id: NEXT_JOB_ID.fetch_add(1, Ordering::Relaxed),
             pe: Some(pe),
             scheduler: scheduler.clone(),
         }
     }
 
+    /// Unique ID of the job, e.g. used to attribute memory allocations.
+    pub fn id(&self) -> u64 {
+        self.id
+    }
+
+    /// Type of the PE executing this job, e.g. after `Device::acquire_any`.
+    pub fn type_id(&self) -> Option<PEId> {
+        self.pe.as_ref().map(|pe| *pe.type_id())
+    }
+
+    /// Instance of the PE executing this job, see `Device::acquire_pe_instance`.
+    pub fn slot(&self) -> Option<usize> {
+        self.pe.as_ref().map(|pe| *pe.id())
+    }
+
     /// Fetches the correct local memory and changes `DataTransferLocal` into `DataTransferAlloc`.
-    fn handle_local_memories(&self, args: Vec<PEParameter>) -> Result<Vec<PEParameter>> {
+    fn handle_local_memories(&self, args: &mut Vec<PEParameter>) -> Result<()> {
         trace!("Handling local memory parameters.");
-        let new_params = args
-            .into_iter()
-            .map(|arg| match arg {
-                PEParameter::DataTransferLocal(x) => {
-                    let m = match self.pe.as_ref().unwrap().local_memory() {
-                        Some(m) => m,
-                        None => return Err(Error::NoLocalMemory {}),
-                    };
-                    Ok(PEParameter::DataTransferAlloc(DataTransferAlloc {
+        for arg in args.iter_mut() {
+            if let PEParameter::DataTransferLocal(_) = arg {
+                let m = match self.pe.as_ref().unwrap().local_memory() {
+                    Some(m) => m.clone(),
+                    None => return Err(Error::NoLocalMemory {}),
+                };
+                if let PEParameter::DataTransferLocal(x) =
+                    mem::replace(arg, PEParameter::Single64(0))
+                {
+                    *arg = PEParameter::DataTransferAlloc(DataTransferAlloc {
                         data: x.data,
                         from_device: x.from_device,
                         to_device: x.to_device,
-                        memory: m.clone(),
+                        memory: m,
                         free: x.free,
                         fixed: x.fixed,
-                    }))
+                    });
                 }
-                _ => Ok(arg),
-            })
-            .collect();
+            }
+        }
         trace!("All local memory parameters handled.");
-        new_params
+        Ok(())
     }
 
-    //TODO: Check performance as this does not happen inplace but creates a new Vec
     /// Allocates memory area on the provided memories which transforms `DataTransferAlloc` into `DataTransferPrealloc`.
-    fn handle_allocates(&self, args: Vec<PEParameter>) -> Result<Vec<PEParameter>> {
+    ///
+    /// The allocations are recorded in `allocated` so they can be freed if the launch fails.
+    fn handle_allocates(
+        &self,
+        args: &mut Vec<PEParameter>,
+        allocated: &mut Vec<(DeviceAddress, Arc<OffchipMemory>)>,
+    ) -> Result<()> {
         trace!("Handling allocate parameters.");
-        let new_params = args
-            .into_iter()
-            .map(|arg| match arg {
-                PEParameter::DataTransferAlloc(x) => {
-                    let a = match x.fixed {
-                        Some(offset) => x
-                            .memory
-                            .allocator()
-                            .lock()?
-                            .allocate_fixed(x.data.len() as u64, offset)
-                            .context(AllocatorError)?,
-                        None => x
-                            .memory
-                            .allocator()
-                            .lock()?
-                            .allocate(x.data.len() as u64)
-                            .context(AllocatorError)?,
-                    };
-
-                    Ok(PEParameter::DataTransferPrealloc(DataTransferPrealloc {
+        for arg in args.iter_mut() {
+            if let PEParameter::DataTransferAlloc(x) = arg {
+                let a = x
+                    .memory
+                    .allocator()
+                    .lock()?
+                    .allocate_tagged(x.data.len() as u64, x.fixed, None, Some(self.id))
+                    .context(AllocatorError)?;
+                allocated.push((a, x.memory.clone()));
+
+                if let PEParameter::DataTransferAlloc(x) =
+                    mem::replace(arg, PEParameter::Single64(0))
+                {
+                    *arg = PEParameter::DataTransferPrealloc(DataTransferPrealloc {
                         data: x.data,
                         device_address: a,
                         from_device: x.from_device,
                         to_device: x.to_device,
                         memory: x.memory,
                         free: x.free,
-                    }))
+                    });
                 }
-                _ => Ok(arg),
-            })
-            .collect();
-
+            }
+        }
         trace!("All allocate parameters handled.");
-        new_params
+        Ok(())
     }
 
-    /// Move data in `DataTransferPrealloc` to the device if necessary and prepare the copy back operations
-    /// to be used after job execution. Converts the `DataTransferPrealloc` into `DeviceAddress`.
-    fn handle_transfers_to_device(
-        &mut self,
-        args: Vec<PEParameter>,
-    ) -> Result<(Vec<PEParameter>, Vec<Box<[u8]>>)> {
-        trace!("Handling allocate parameters.");
+    /// Move data in `DataTransferPrealloc` to the device if necessary.
+    fn handle_transfers_to_device(&self, args: &[PEParameter]) -> Result<()> {
+        trace!("Handling transfer to parameters.");
+        for arg in args {
+            if let PEParameter::DataTransferPrealloc(x) = arg {
+                if x.to_device {
+                    x.memory
+                        .dma()
+                        .copy_to(&x.data[..], x.device_address)
+                        .context(DMAError)?;
+                }
+            }
+        }
+        trace!("All transfer to parameters handled.");
+        Ok(())
+    }
+
+    /// Write the register values of the parameters to the PE.
+    fn set_args(&self, args: &[PEParameter]) -> Result<()> {
+        trace!("Setting arguments.");
+        let pe = self.pe.as_ref().unwrap();
+        for (i, arg) in args.iter().enumerate() {
+            trace!("Setting argument {} => {:?}.", i, arg);
+            let value = match arg {
+                PEParameter::Single32(x) => PEParameter::Single32(*x),
+                PEParameter::Single64(x) => PEParameter::Single64(*x),
+                PEParameter::DeviceAddress(x) => PEParameter::Single64(*x),
+                PEParameter::DataTransferPrealloc(x) => PEParameter::Single64(x.device_address),
+                PEParameter::Buffer(x) => PEParameter::Single64(*x.address()),
+                PEParameter::DataTransferLocal(_) | PEParameter::DataTransferAlloc(_) => {
+                    return Err(Error::UnconvertedParameter { index: i })
+                }
+            };
+            pe.set_arg(i, value).context(PEError)?;
+        }
+        trace!("Arguments set.");
+        Ok(())
+    }
+
+    /// Prepare the copy back operations to be used after job execution.
+    ///
+    /// Returns the memories that are not marked for copy back.
+    fn register_copybacks(&mut self, args: Vec<PEParameter>) -> Vec<Box<[u8]>> {
+        let pe = self.pe.as_mut().unwrap();
         let mut unused_mem = Vec::new();
-        let new_params = args
-            .into_iter()
-            .try_fold(Vec::new(), |mut xs, arg| match arg {
+        for arg in args {
+            match arg {
                 PEParameter::DataTransferPrealloc(x) => {
-                    if x.to_device {
-                        x.memory
-                            .dma()
-                            .copy_to(&x.data[..], x.device_address)
-                            .context(DMAError)?;
-                    }
-
-                    xs.push(PEParameter::DeviceAddress(x.device_address));
                     if x.from_device {
-                        self.pe
-                            .as_mut()
-                            .unwrap()
-                            .add_copyback(CopyBack::Transfer(x));
+                        pe.add_copyback(CopyBack::Transfer(x));
                     } else {
                         if x.free {
-                            self.pe
-                                .as_mut()
-                                .unwrap()
-                                .add_copyback(CopyBack::Free(x.device_address, x.memory.clone()));
+                            pe.add_copyback(CopyBack::Free(x.device_address, x.memory.clone()));
                         }
                         unused_mem.push(x.data);
                     }
-
-                    Ok(xs)
-                }
-                _ => {
-                    xs.push(arg);
-                    Ok(xs)
                 }
-            });
-        trace!("All transfer to parameters handled.");
-        match new_params {
-            Ok(x) => Ok((x, unused_mem)),
-            Err(e) => Err(e),
+                PEParameter::Buffer(x) => pe.add_copyback(CopyBack::Keep(x)),
+                _ => (),
+            }
+        }
+        unused_mem
+    }
+
+    /// Undo a failed launch: Frees the allocations made for it and returns the host
+    /// buffers contained in the parameters.
+    fn rollback(
+        &self,
+        args: Vec<PEParameter>,
+        allocated: Vec<(DeviceAddress, Arc<OffchipMemory>)>,
+    ) -> Vec<Box<[u8]>> {
+        for (a, m) in allocated {
+            trace!("Rolling back allocation 0x{:x} on {}.", a, m.name());
+            let r = match m.allocator().lock() {
+                Ok(mut x) => x.free(a).context(AllocatorError),
+                Err(e) => Err(e.into()),
+            };
+            if let Err(e) = r {
+                error!("Could not free 0x{:x} after failed launch: {}", a, e);
+            }
         }
+        args.into_iter()
+            .filter_map(|arg| match arg {
+                PEParameter::DataTransferLocal(x) => Some(x.data),
+                PEParameter::DataTransferAlloc(x) => Some(x.data),
+                PEParameter::DataTransferPrealloc(x) => Some(x.data),
+                _ => None,
+            })
+            .collect()
     }
 
     /// Start PE execution with the given parameters. This function does not block.
     ///
+    /// The launch is all-or-nothing: If any step fails, the memory allocated for the launch
+    /// is freed and the host buffers of the parameters are returned in `StartFailed`.
+    ///
     /// # Arguments
     ///  * args: A list of PE parameters. As the DMA engine requires complete access, ownership transfer is required.
     /// # Returns
@@ -236,36 +354,41 @@ impl Job {
             self.pe,
             args
         );
-        let alloc_args = self.handle_local_memories(args)?;
-        trace!("Handled local parameters => {:?}.", alloc_args);
-        let local_args = self.handle_allocates(alloc_args)?;
-        trace!("Handled allocates => {:?}.", local_args);
-        let (trans_args, unused_mem) = self.handle_transfers_to_device(local_args)?;
-        trace!("Handled transfers => {:?}.", trans_args);
-        trace!("Setting arguments.");
-        for (i, arg) in trans_args.into_iter().enumerate() {
-            trace!("Setting argument {} => {:?}.", i, arg);
-            match arg {
-                PEParameter::Single32(_) => {
-                    self.pe.as_ref().unwrap().set_arg(i, arg).context(PEError)?
-                }
-                PEParameter::Single64(_) => {
-                    self.pe.as_ref().unwrap().set_arg(i, arg).context(PEError)?
-                }
-                PEParameter::DeviceAddress(x) => self
-                    .pe
-                    .as_ref()
-                    .unwrap()
-                    .set_arg(i, PEParameter::Single64(x))
-                    .context(PEError)?,
-                _ => return Err(Error::UnsupportedRegisterParameter { arg: arg }),
-            };
+        if self.pe.is_none() {
+            return Err(Error::StartFailed {
+                source: Box::new(Error::NoPEtoStart {}),
+                buffers: self.rollback(args, Vec::new()),
+            });
         }
-        trace!("Arguments set.");
+        let mut args = args;
+        let mut allocated = Vec::new();
+        match self.try_start(&mut args, &mut allocated) {
+            Ok(()) => Ok(self.register_copybacks(args)),
+            Err(e) => {
+                warn!("Starting job {} failed, rolling back: {}", self.id, e);
+                Err(Error::StartFailed {
+                    source: Box::new(e),
+                    buffers: self.rollback(args, allocated),
+                })
+            }
+        }
+    }
+
+    fn try_start(
+        &mut self,
+        args: &mut Vec<PEParameter>,
+        allocated: &mut Vec<(DeviceAddress, Arc<OffchipMemory>)>,
+    ) -> Result<()> {
+        self.handle_local_memories(args)?;
+        trace!("Handled local parameters => {:?}.", args);
+        self.handle_allocates(args, allocated)?;
+        trace!("Handled allocates => {:?}.", args);
+        self.handle_transfers_to_device(args)?;
+        self.set_args(args)?;
         trace!("Starting PE {} execution.", self.pe.as_ref().unwrap().id());
         self.pe.as_mut().unwrap().start().context(PEError)?;
         trace!("PE {} started.", self.pe.as_ref().unwrap().id());
-        Ok(unused_mem)
+        Ok(())
     }
 
     /// Wait for job completion and handle copy back if necessary.
@@ -323,6 +446,7 @@ impl Job {
                             CopyBack::Free(addr, mem) => {
                                 mem.allocator().lock()?.free(addr).context(AllocatorError)?;
                             }
+                            CopyBack::Keep(_) => (),
                         }
                     }
 
@@ -335,6 +459,94 @@ impl Job {
         }
     }
 
+    /// Abort the job without waiting for the PE to finish.
+    ///
+    /// If the PE is still running, the platform is asked to reset it. The PE is returned
+    /// to the scheduler if it is idle afterwards, otherwise it is marked as faulty.
+    /// Data is not copied back. Device memory allocated for the job is freed once the
+    /// PE is idle, for faulty PEs this happens in [`Scheduler.reset_faulty_pe`].
+    ///
+    /// # Returns
+    ///  * The host buffers that were marked for copy back, unchanged.
+    ///
+    /// [`Scheduler.reset_faulty_pe`]: ../scheduler/struct.Scheduler.html#method.reset_faulty_pe
+    pub fn abort(&mut self) -> Result<Vec<Box<[u8]>>> {
+        let mut pe = match self.pe.take() {
+            Some(pe) => pe,
+            None => return Err(Error::NoPEtoRelease {}),
+        };
+        trace!("Aborting job {} on PE {}.", self.id, pe.id());
+        if let Err(e) = pe.cancel_completion() {
+            warn!("Could not cancel completion wait: {}", e);
+        }
+        let buffers = pe.retain_copyback();
+
+        let recovered = match self.scheduler.recover_pe(&mut pe) {
+            Ok(x) => x,
+            Err(e) => {
+                warn!("Could not recover PE {}: {}", pe.id(), e);
+                false
+            }
+        };
+        if recovered {
+            let freed = pe.free_retained().context(PEError);
+            self.scheduler.release_pe(pe).context(SchedulerError)?;
+            freed?;
+        } else {
+            self.scheduler.quarantine_pe(pe).context(SchedulerError)?;
+        }
+        Ok(buffers)
+    }
+
+    /// Like [`release`] but gives up if the PE does not finish within `timeout`.
+    ///
+    /// On expiry the PE is marked as faulty and will not be handed out again until it
+    /// is reset through [`Scheduler.reset_faulty_pe`], which also frees the device
+    /// memory allocated for the job. The copy back buffers are returned unchanged in
+    /// `Error::Timeout`.
+    ///
+    /// [`release`]: #method.release
+    /// [`Scheduler.reset_faulty_pe`]: ../scheduler/struct.Scheduler.html#method.reset_faulty_pe
+    pub fn release_timeout(
+        &mut self,
+        release_pe: bool,
+        return_value: bool,
+        timeout: Duration,
+    ) -> Result<(u64, Vec<Box<[u8]>>)> {
+        let done = match self.pe.as_mut() {
+            Some(pe) => pe.wait_for_completion_timeout(timeout).context(PEError)?,
+            None => return Err(Error::NoPEtoRelease {}),
+        };
+        if done {
+            return self.release(release_pe, return_value);
+        }
+
+        let mut pe = self.pe.take().unwrap();
+        warn!(
+            "Job {} on PE {} missed its deadline of {:?}.",
+            self.id,
+            pe.id(),
+            timeout
+        );
+        pe.update_stats(|s| s.timeouts += 1);
+        let buffers = pe.retain_copyback();
+        self.scheduler.quarantine_pe(pe).context(SchedulerError)?;
+        Err(Error::Timeout {
+            id: self.id,
+            timeout: timeout,
+            buffers: buffers,
+        })
+    }
+
+    /// Wait asynchronously for the job to finish.
+    ///
+    /// The returned future behaves like `release(true, true)` but does not block
+    /// while the PE is running. Completion is signaled through the reactor of the
+    /// device, so any executor can be used to drive the future.
+    pub fn completion(&mut self) -> JobCompletion<'_> {
+        JobCompletion { job: self }
+    }
+
     pub fn enable_debug(&mut self) -> Result<()> {
         match &mut self.pe {
             Some(x) => x.enable_debug().context(PEError)?,
@@ -343,3 +555,253 @@ impl Job {
         Ok(())
     }
 }
+
+/// Future returned by [`Job.completion`].
+///
+/// [`Job.completion`]: struct.Job.html#method.completion
+#[derive(Debug)]
+pub struct JobCompletion<'a> {
+    job: &'a mut Job,
+}
+
+impl Future for JobCompletion<'_> {
+    type Output = Result<(u64, Vec<Box<[u8]>>)>;
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context) -> Poll<Self::Output> {
+        let job = &mut *self.job;
+        let done = match job.pe.as_mut() {
+            Some(pe) => pe.poll_completion(cx).map(|r| r.context(PEError)),
+            None => Poll::Ready(Err(Error::NoPEtoRelease {})),
+        };
+        match done {
+            Poll::Ready(Ok(())) => Poll::Ready(job.release(true, true)),
+            Poll::Ready(Err(e)) => Poll::Ready(Err(e)),
+            Poll::Pending => Poll::Pending,
+        }
+    }
+}
+
+impl Drop for JobCompletion<'_> {
+    fn drop(&mut self) {
+        if let Some(pe) = self.job.pe.as_ref() {
+            if let Err(e) = pe.cancel_completion() {
+                warn!("Could not cancel completion wait: {}", e);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod job_tests {
+    use crate::device::{DataTransferAlloc, PEParameter};
+    use crate::scheduler::PEHealth;
+    use crate::sim::testing::{block_on, init, FlagWaker, Result};
+    use crate::sim::{PEContext, SimulatedDevice, SimulatedPE, SimulatedTLKM};
+    use crate::tlkm::{tlkm_access, TLKM};
+    use std::collections::HashMap;
+    use std::future::Future;
+    use std::pin::Pin;
+    use std::sync::atomic::{AtomicBool, Ordering};
+    use std::sync::Arc;
+    use std::task::{Context, Waker};
+    use std::thread;
+    use std::time::Duration;
+
+    #[test]
+    fn async_completion() -> Result<()> {
+        let slow = SimulatedPE::new(12, "sim:slow", |pe: &mut PEContext| {
+            thread::sleep(Duration::from_millis(50));
+            pe.arg(0) * 2
+        });
+        let sim = SimulatedTLKM::new(vec![SimulatedDevice::new("pcie").pe(slow, 2)]);
+        let tlkm = TLKM::with_driver(Arc::new(sim))?;
+        let mut dev = tlkm.device_alloc(0, &HashMap::new())?;
+        dev.change_access(tlkm_access::TlkmAccessExclusive)?;
+        let mut a = dev.acquire_pe(12)?;
+        let mut b = dev.acquire_pe(12)?;
+        a.start(vec![PEParameter::Single64(21)])?;
+        b.start(vec![PEParameter::Single64(5)])?;
+        let (ra, rb) = block_on(async { (a.completion().await, b.completion().await) });
+        assert_eq!(ra?.0, 42);
+        assert_eq!(rb?.0, 10);
+        Ok(())
+    }
+
+    #[test]
+    fn start_rollback() -> Result<()> {
+        let tlkm = init()?;
+        let mut dev = tlkm.device_alloc(0, &HashMap::new())?;
+        dev.change_access(tlkm_access::TlkmAccessExclusive)?;
+        let mem = dev.default_memory()?;
+        let taken = mem.allocator().lock().unwrap().allocate(64)?;
+        let transfer = |data: Box<[u8]>, fixed| {
+            PEParameter::DataTransferAlloc(DataTransferAlloc {
+                data: data,
+                from_device: true,
+                to_device: true,
+                free: true,
+                memory: mem.clone(),
+                fixed: fixed,
+            })
+        };
+
+        let mut job = dev.acquire_pe(14)?;
+        match job.start(vec![
+            transfer(vec![1u8; 512].into_boxed_slice(), None),
+            transfer(vec![2u8; 64].into_boxed_slice(), Some(taken)),
+        ]) {
+            Err(crate::job::Error::StartFailed { buffers, .. }) => {
+                assert_eq!(buffers.len(), 2);
+                assert!(buffers[0].iter().all(|x| *x == 1));
+                assert_eq!(buffers[1].len(), 64);
+            }
+            r => panic!("Expected failed start, got {:?}", r),
+        }
+        assert_eq!(mem.stats()?.allocations, 1);
+
+        job.start(vec![
+            transfer(vec![1u8; 512].into_boxed_slice(), None),
+            PEParameter::Single64(512),
+        ])?;
+        let (_, out) = job.release(true, true)?;
+        assert!(out[0].iter().all(|x| *x == 2));
+        assert_eq!(mem.stats()?.allocations, 1);
+        Ok(())
+    }
+
+    #[test]
+    fn job_abort() -> Result<()> {
+        let open = Arc::new(AtomicBool::new(false));
+        let gate = open.clone();
+        let gated = SimulatedPE::new(12, "sim:gated", move |_: &mut PEContext| {
+            while !gate.load(Ordering::SeqCst) {
+                thread::sleep(Duration::from_millis(1));
+            }
+            0
+        });
+        let sim = SimulatedTLKM::new(vec![SimulatedDevice::new("pcie").pe(gated, 1)]);
+        let mut tlkm = TLKM::with_driver(Arc::new(sim))?;
+        tlkm.set_config("job.drop_timeout_ms", "10")?;
+        let mut dev = tlkm.device_alloc(0, &HashMap::new())?;
+        dev.change_access(tlkm_access::TlkmAccessExclusive)?;
+        let mem = dev.default_memory()?;
+
+        // Finished PEs go back to the scheduler.
+        let mut job = dev.acquire_pe(12)?;
+        job.start(vec![])?;
+        let woken = Arc::new(FlagWaker(AtomicBool::new(false)));
+        {
+            let waker = Waker::from(woken.clone());
+            let mut completion = job.completion();
+            let mut cx = Context::from_waker(&waker);
+            assert!(Pin::new(&mut completion).poll(&mut cx).is_pending());
+            open.store(true, Ordering::SeqCst);
+            while !woken.0.load(Ordering::SeqCst) {
+                thread::yield_now();
+            }
+        }
+        assert!(job.abort()?.is_empty());
+        assert!(job.abort().is_err());
+
+        // Running PEs can not be reset in the simulation and are taken out of service.
+        open.store(false, Ordering::SeqCst);
+        let mut job = dev.acquire_pe(12)?;
+        job.start(vec![PEParameter::DataTransferAlloc(DataTransferAlloc {
+            data: vec![7u8; 64].into_boxed_slice(),
+            from_device: true,
+            to_device: false,
+            free: true,
+            memory: mem.clone(),
+            fixed: None,
+        })])?;
+        let buffers = job.abort()?;
+        assert_eq!(buffers.len(), 1);
+        assert!(buffers[0].iter().all(|x| *x == 7));
+        // The PE is still running and keeps its memory until it is reset.
+        assert_eq!(mem.stats()?.allocations, 1);
+        assert!(dev.try_acquire_pe(12)?.is_none());
+        drop(job);
+        open.store(true, Ordering::SeqCst);
+        while dev.reset_faulty_pe(0).is_err() {
+            thread::sleep(Duration::from_millis(1));
+        }
+        assert_eq!(mem.stats()?.allocations, 0);
+
+        // Dropping a job waits only for a limited time for the PE.
+        open.store(false, Ordering::SeqCst);
+        let mut job = dev.acquire_pe(12)?;
+        job.start(vec![])?;
+        drop(job);
+        assert_eq!(dev.pe_health()?, vec![PEHealth::Faulty]);
+        open.store(true, Ordering::SeqCst);
+        while dev.reset_faulty_pe(0).is_err() {
+            thread::sleep(Duration::from_millis(1));
+        }
+        let mut job = dev.acquire_pe(12)?;
+        job.start(vec![])?;
+        drop(job);
+        assert_eq!(dev.pe_health()?, vec![PEHealth::Healthy]);
+        Ok(())
+    }
+
+    #[test]
+    fn release_timeout() -> Result<()> {
+        let open = Arc::new(AtomicBool::new(false));
+        let gate = open.clone();
+        // Jobs with an argument of 1 wait until `open` is set.
+        let gated = SimulatedPE::new(12, "sim:gated", move |pe: &mut PEContext| {
+            while pe.arg(0) == 1 && !gate.load(Ordering::SeqCst) {
+                thread::sleep(Duration::from_millis(1));
+            }
+            0
+        });
+        let sim = SimulatedTLKM::new(vec![SimulatedDevice::new("pcie").pe(gated, 1)]);
+        let tlkm = TLKM::with_driver(Arc::new(sim))?;
+        let mut dev = tlkm.device_alloc(0, &HashMap::new())?;
+        dev.change_access(tlkm_access::TlkmAccessExclusive)?;
+        let mem = dev.default_memory()?;
+
+        let mut job = dev.acquire_pe(12)?;
+        job.start(vec![PEParameter::Single64(0)])?;
+        job.release_timeout(true, true, Duration::from_secs(5))?;
+        assert_eq!(dev.pe_health()?, vec![PEHealth::Healthy]);
+
+        let mut job = dev.acquire_pe(12)?;
+        job.start(vec![
+            PEParameter::Single64(1),
+            PEParameter::DataTransferAlloc(DataTransferAlloc {
+                data: vec![7u8; 64].into_boxed_slice(),
+                from_device: true,
+                to_device: false,
+                free: true,
+                memory: mem.clone(),
+                fixed: None,
+            }),
+        ])?;
+        match job.release_timeout(true, true, Duration::from_millis(20)) {
+            Err(crate::job::Error::Timeout { buffers, .. }) => {
+                assert_eq!(buffers.len(), 1);
+                assert!(buffers[0].iter().all(|x| *x == 7));
+            }
+            r => panic!("Expected timeout, got {:?}", r),
+        }
+        assert_eq!(mem.stats()?.allocations, 1);
+        assert_eq!(dev.pe_health()?, vec![PEHealth::Faulty]);
+        assert_eq!(dev.faulty_pes()?, vec![0]);
+        assert!(dev.try_acquire_pe(12)?.is_none());
+        assert!(dev.reset_faulty_pe(0).is_err());
+
+        // Once the PE has finished it can be put back into service.
+        open.store(true, Ordering::SeqCst);
+        while dev.reset_faulty_pe(0).is_err() {
+            thread::sleep(Duration::from_millis(1));
+        }
+        assert_eq!(mem.stats()?.allocations, 0);
+        assert!(dev.reset_faulty_pe(0).is_err());
+        assert_eq!(dev.pe_health()?, vec![PEHealth::Healthy]);
+        let mut job = dev.acquire_pe(12)?;
+        job.start(vec![PEParameter::Single64(0)])?;
+        job.release(true, true)?;
+        Ok(())
+    }
+}
@@ -36,13 +36,20 @@ extern crate lockfree;
 extern crate volatile;
 
 pub mod allocator;
+pub mod buffer;
 pub mod debug;
 pub mod device;
+pub mod dispatcher;
 pub mod dma;
 pub mod dma_user_space;
 pub mod ffi;
+pub mod graph;
 pub mod interrupt;
 pub mod job;
 pub mod pe;
+pub mod platform;
+pub mod pool;
+pub mod reactor;
 pub mod scheduler;
+pub mod sim;
 pub mod tlkm;
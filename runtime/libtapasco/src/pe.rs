@@ -18,6 +18,7 @@
  * along with this program. If not, see <http://www.gnu.org/licenses/>.
  */
 
+use crate::buffer::Allocation;
 use crate::debug::DebugControl;
 use crate::device::DataTransferPrealloc;
 use crate::device::DeviceAddress;
@@ -25,10 +26,14 @@ use crate::device::DeviceSize;
 use crate::device::OffchipMemory;
 use crate::device::PEParameter;
 use crate::interrupt::Interrupt;
+use crate::reactor::Reactor;
+use crate::tlkm::DeviceDriver;
 use memmap::MmapMut;
 use snafu::ResultExt;
-use std::fs::File;
 use std::sync::Arc;
+use std::sync::Mutex;
+use std::task::{Context, Poll, Wake, Waker};
+use std::time::{Duration, Instant};
 use volatile::Volatile;
 
 #[derive(Debug, Snafu)]
@@ -71,6 +76,21 @@ pub enum Error {
         source: crate::debug::Error,
         id: usize,
     },
+
+    #[snafu(display("Could not free device memory of PE {}: {}", id, source))]
+    AllocatorError {
+        source: crate::allocator::Error,
+        id: usize,
+    },
+
+    #[snafu(display("Mutex has been poisoned"))]
+    MutexError {},
+}
+
+impl<T> From<std::sync::PoisonError<T>> for Error {
+    fn from(_error: std::sync::PoisonError<T>) -> Self {
+        Error::MutexError {}
+    }
 }
 
 type Result<T, E = Error> = std::result::Result<T, E>;
@@ -79,10 +99,150 @@ type Result<T, E = Error> = std::result::Result<T, E>;
 pub enum CopyBack {
     Transfer(DataTransferPrealloc),
     Free(DeviceAddress, Arc<OffchipMemory>),
+    Keep(Arc<Allocation>),
 }
 
 pub type PEId = usize;
 
+const WAIT_BUCKETS: usize = 32;
+
+/// Histogram of durations with logarithmic buckets.
+///
+/// Bucket `i` counts durations below `2^i` microseconds, the last bucket also holds
+/// all longer durations.
+#[derive(Debug, Copy, Clone, Default, PartialEq)]
+pub struct Histogram {
+    buckets: [u64; WAIT_BUCKETS],
+    count: u64,
+    total: Duration,
+}
+
+impl Histogram {
+    pub fn record(&mut self, d: Duration) {
+        let us = d.as_micros();
+        let bucket = (128 - us.leading_zeros()) as usize;
+        self.buckets[std::cmp::min(bucket, WAIT_BUCKETS - 1)] += 1;
+        self.count += 1;
+        self.total += d;
+    }
+
+    pub fn merge(&mut self, other: &Histogram) {
+        for (a, b) in self.buckets.iter_mut().zip(other.buckets.iter()) {
+            *a += b;
+        }
+        self.count += other.count;
+        self.total += other.total;
+    }
+
+    /// Number of recorded durations.
+    pub fn count(&self) -> u64 {
+        self.count
+    }
+
+    pub fn mean(&self) -> Duration {
+        if self.count == 0 {
+            Duration::from_secs(0)
+        } else {
+            Duration::from_nanos((self.total.as_nanos() / self.count as u128) as u64)
+        }
+    }
+
+    /// Upper bound of the duration below which `p` percent of the recorded durations lie.
+    pub fn percentile(&self, p: f64) -> Duration {
+        let rank = (self.count as f64 * p / 100.0).ceil() as u64;
+        let mut seen = 0;
+        for (i, n) in self.buckets.iter().enumerate() {
+            seen += n;
+            if *n > 0 && seen >= rank {
+                return Duration::from_micros(1 << i);
+            }
+        }
+        Duration::from_secs(0)
+    }
+}
+
+/// Utilization of a PE, or of all PEs of a type when aggregated.
+#[derive(Debug, Copy, Clone, Default, PartialEq)]
+pub struct PEStats {
+    /// Number of completed runs.
+    pub jobs: u64,
+    /// Time between starting the PE and its interrupt.
+    pub busy: Duration,
+    /// Time spent by requests waiting for the PE.
+    pub wait: Histogram,
+    /// Number of release deadlines missed.
+    pub timeouts: u64,
+    /// Number of times the PE has been taken out of service.
+    pub faults: u64,
+}
+
+impl PEStats {
+    pub fn merge(&mut self, other: &PEStats) {
+        self.jobs += other.jobs;
+        self.busy += other.busy;
+        self.wait.merge(&other.wait);
+        self.timeouts += other.timeouts;
+        self.faults += other.faults;
+    }
+
+    /// Flat representation used by the C interface.
+    pub fn summary(&self) -> PEStatsSummary {
+        PEStatsSummary {
+            jobs: self.jobs,
+            busy_ns: self.busy.as_nanos() as u64,
+            waits: self.wait.count(),
+            wait_mean_ns: self.wait.mean().as_nanos() as u64,
+            wait_p50_ns: self.wait.percentile(50.0).as_nanos() as u64,
+            wait_p90_ns: self.wait.percentile(90.0).as_nanos() as u64,
+            wait_p99_ns: self.wait.percentile(99.0).as_nanos() as u64,
+            timeouts: self.timeouts,
+            faults: self.faults,
+        }
+    }
+}
+
+/// Summary of [`PEStats`] with wait percentiles, see [`PEStats.summary`].
+///
+/// [`PEStats`]: struct.PEStats.html
+/// [`PEStats.summary`]: struct.PEStats.html#method.summary
+#[repr(C)]
+#[derive(Debug, Copy, Clone, Default, PartialEq)]
+pub struct PEStatsSummary {
+    pub jobs: u64,
+    pub busy_ns: u64,
+    pub waits: u64,
+    pub wait_mean_ns: u64,
+    pub wait_p50_ns: u64,
+    pub wait_p90_ns: u64,
+    pub wait_p99_ns: u64,
+    pub timeouts: u64,
+    pub faults: u64,
+}
+
+/// Records when the reactor first saw the interrupt of a running PE.
+#[derive(Debug, Default)]
+struct CompletionClock {
+    seen: Mutex<Option<Instant>>,
+}
+
+impl CompletionClock {
+    fn take(&self) -> Option<Instant> {
+        self.seen.lock().ok().and_then(|mut s| s.take())
+    }
+}
+
+impl Wake for CompletionClock {
+    fn wake(self: Arc<Self>) {
+        self.wake_by_ref();
+    }
+
+    fn wake_by_ref(self: &Arc<Self>) {
+        if let Ok(mut s) = self.seen.lock() {
+            s.get_or_insert_with(Instant::now);
+        }
+    }
+}
+
 /// Representation of a TaPaSCo PE
 ///
 /// Supports starting and releasing a PE as well as
@@ -101,6 +261,7 @@ pub struct PE {
     #[get = "pub"]
     active: bool,
     copy_back: Option<Vec<CopyBack>>,
+    retained: Vec<CopyBack>,
     memory: Arc<MmapMut>,
 
     #[set = "pub"]
@@ -108,6 +269,13 @@ pub struct PE {
     local_memory: Option<Arc<OffchipMemory>>,
 
     interrupt: Interrupt,
+    reactor: Arc<Reactor>,
+
+    started: Option<Instant>,
+    clock: Arc<CompletionClock>,
+    clock_waker: Waker,
+    #[get = "pub"]
+    stats: Arc<Mutex<PEStats>>,
 
     debug: Box<dyn DebugControl + Sync + Send>,
 }
@@ -120,10 +288,12 @@ impl PE {
         size: DeviceSize,
         name: String,
         memory: Arc<MmapMut>,
-        completion: &File,
+        completion: &dyn DeviceDriver,
         interrupt_id: usize,
+        reactor: &Arc<Reactor>,
         debug: Box<dyn DebugControl + Sync + Send>,
     ) -> Result<PE> {
+        let clock = Arc::new(CompletionClock::default());
         Ok(PE {
             id: id,
             type_id: type_id,
@@ -132,9 +302,15 @@ impl PE {
             name: name,
             active: false,
             copy_back: None,
+            retained: Vec::new(),
             memory: memory,
             local_memory: None,
             interrupt: Interrupt::new(completion, interrupt_id, false).context(ErrorInterrupt)?,
+            reactor: reactor.clone(),
+            started: None,
+            clock_waker: Waker::from(clock.clone()),
+            clock: clock,
+            stats: Arc::new(Mutex::new(PEStats::default())),
             debug: debug,
         })
     }
@@ -142,7 +318,10 @@ impl PE {
     pub fn start(&mut self) -> Result<()> {
         ensure!(!self.active, PEAlreadyActive { id: self.id });
         trace!("Starting PE {}.", self.id);
+        self.clock.take();
+        self.watch_completion();
         let offset = self.offset as isize;
+        self.started = Some(Instant::now());
         unsafe {
             let ptr = self.memory.as_ptr().offset(offset);
             let volatile_ptr = ptr as *mut Volatile<u32>;
@@ -152,6 +331,43 @@ impl PE {
         Ok(())
     }
 
+    /// Let the reactor record when the interrupt arrives, independent of when the
+    /// completion is handled.
+    fn watch_completion(&self) {
+        if let Err(e) = self
+            .interrupt
+            .register_waker(&self.reactor, &self.clock_waker)
+        {
+            warn!("Could not watch interrupt of PE {}: {}", self.id, e);
+        }
+    }
+
+    /// Mark the PE as idle and account the run time up to the interrupt. Only
+    /// `completed` runs are counted as jobs, not aborted or reset ones.
+    fn finished(&mut self, completed: bool) {
+        self.active = false;
+        if let Some(t) = self.started.take() {
+            let end = match self.clock.take() {
+                Some(x) if x >= t => x,
+                _ => Instant::now(),
+            };
+            self.update_stats(|s| {
+                if completed {
+                    s.jobs += 1;
+                }
+                s.busy += end - t;
+            });
+        }
+    }
+
+    /// Modify the statistics of this PE. Poisoned statistics are skipped.
+    pub fn update_stats<F: FnOnce(&mut PEStats)>(&self, f: F) {
+        match self.stats.lock() {
+            Ok(mut s) => f(&mut s),
+            Err(_) => warn!("Statistics of PE {} are poisoned.", self.id),
+        }
+    }
+
     pub fn release(&mut self, return_value: bool) -> Result<(u64, Option<Vec<CopyBack>>)> {
         trace!(
             "Waiting for PE {} to complete processing (interrupt signal).",
@@ -163,6 +379,98 @@ impl PE {
         Ok((rv, self.get_copyback()))
     }
 
+    /// Asynchronous version of the completion handling in `release`.
+    ///
+    /// Returns `Ready` once the PE is idle. The PE is deactivated in the same way
+    /// as during `release`, which afterwards returns without waiting.
+    pub fn poll_completion(&mut self, cx: &mut Context) -> Poll<Result<()>> {
+        if !self.active {
+            return Poll::Ready(Ok(()));
+        }
+        match self.interrupt.poll_interrupt(&self.reactor, cx) {
+            Poll::Ready(Ok(_)) => {
+                trace!("Cleaning up PE {} after asynchronous completion.", self.id);
+                self.finished(true);
+                Poll::Ready(self.reset_interrupt(true))
+            }
+            Poll::Ready(Err(e)) => Poll::Ready(Err(Error::ErrorInterrupt { source: e })),
+            Poll::Pending => Poll::Pending,
+        }
+    }
+
+    /// Stop waking the context passed to the last `poll_completion` call.
+    pub fn cancel_completion(&self) -> Result<()> {
+        self.interrupt
+            .cancel_poll(&self.reactor)
+            .context(ErrorInterrupt)?;
+        if self.active {
+            self.watch_completion();
+        }
+        Ok(())
+    }
+
+    /// Wait at most `timeout` for the PE to finish. Deactivates the PE if it is done.
+    ///
+    /// Returns `false` if the PE is still running.
+    pub fn wait_for_completion_timeout(&mut self, timeout: Duration) -> Result<bool> {
+        if self.active {
+            match self
+                .interrupt
+                .wait_for_interrupt_timeout(timeout)
+                .context(ErrorInterrupt)?
+            {
+                Some(_) => {
+                    trace!("Cleaning up PE {} after release.", self.id);
+                    self.finished(true);
+                    self.reset_interrupt(true)?;
+                }
+                None => {
+                    trace!("PE {} did not finish within {:?}.", self.id, timeout);
+                    return Ok(false);
+                }
+            }
+        }
+        Ok(true)
+    }
+
+    /// Check for completion without waiting. Deactivates the PE if it is done.
+    pub fn try_complete(&mut self) -> Result<bool> {
+        self.check_completion(true)
+    }
+
+    /// Like `try_complete` for runs that have been aborted, which are not counted
+    /// as jobs.
+    pub fn try_complete_aborted(&mut self) -> Result<bool> {
+        self.check_completion(false)
+    }
+
+    fn check_completion(&mut self, completed: bool) -> Result<bool> {
+        if self.active {
+            if self
+                .interrupt
+                .check_for_interrupt()
+                .context(ErrorInterrupt)?
+                == 0
+            {
+                return Ok(false);
+            }
+            trace!("Cleaning up PE {} after completion.", self.id);
+            self.finished(completed);
+            self.reset_interrupt(true)?;
+        }
+        Ok(true)
+    }
+
+    /// Mark the PE as idle after it has been reset, discarding pending interrupts.
+    /// The aborted run is not counted as a job.
+    pub fn deactivate(&mut self) -> Result<()> {
+        self.interrupt
+            .check_for_interrupt()
+            .context(ErrorInterrupt)?;
+        self.finished(false);
+        self.reset_interrupt(true)
+    }
+
     /// Waits for a PE interrupt and deactivates the PE afterwards
     fn wait_for_completion(&mut self) -> Result<()> {
         if self.active {
@@ -170,7 +478,7 @@ impl PE {
                 .wait_for_interrupt()
                 .context(ErrorInterrupt)?;
             trace!("Cleaning up PE {} after release.", self.id);
-            self.active = false;
+            self.finished(true);
             self.reset_interrupt(true)?;
         } else {
             trace!("Wait requested but {:?} is already idle.", self.id);
@@ -295,6 +603,49 @@ impl PE {
         self.copy_back.take()
     }
 
+    /// Drop the pending copy back operations, e.g. when the job has been aborted.
+    ///
+    /// Returns the host buffers unchanged. The device memory of the job stays allocated
+    /// as the PE might still access it, call [`free_retained`] once the PE is idle.
+    ///
+    /// [`free_retained`]: #method.free_retained
+    pub fn retain_copyback(&mut self) -> Vec<Box<[u8]>> {
+        let mut buffers = Vec::new();
+        for param in self.copy_back.take().unwrap_or_default() {
+            match param {
+                CopyBack::Transfer(transfer) => {
+                    if transfer.free {
+                        self.retained
+                            .push(CopyBack::Free(transfer.device_address, transfer.memory));
+                    }
+                    buffers.push(transfer.data);
+                }
+                x => self.retained.push(x),
+            }
+        }
+        buffers
+    }
+
+    /// Free the device memory kept by [`retain_copyback`].
+    ///
+    /// [`retain_copyback`]: #method.retain_copyback
+    pub fn free_retained(&mut self) -> Result<()> {
+        let mut freed = Ok(());
+        for param in self.retained.drain(..) {
+            if let CopyBack::Free(addr, mem) = param {
+                trace!("Freeing retained memory at 0x{:x} of PE {}.", addr, self.id);
+                let r = match mem.allocator().lock() {
+                    Ok(mut a) => a.free(addr).context(AllocatorError { id: self.id }),
+                    Err(_) => Err(Error::MutexError {}),
+                };
+                if freed.is_ok() {
+                    freed = r;
+                }
+            }
+        }
+        freed
+    }
+
     pub fn enable_debug(&mut self) -> Result<()> {
         self.debug
             .enable_debug()
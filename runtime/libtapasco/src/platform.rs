@@ -0,0 +1,475 @@
+/*
+ * Copyright (c) 2014-2020 Embedded Systems and Applications, TU Darmstadt.
+ *
+ * This file is part of TaPaSCo
+ * (see https://github.com/esa-tu-darmstadt/tapasco).
+ *
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU Lesser General Public License as published by
+ * the Free Software Foundation, either version 3 of the License, or
+ * (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU Lesser General Public License for more details.
+ *
+ * You should have received a copy of the GNU Lesser General Public License
+ * along with this program. If not, see <http://www.gnu.org/licenses/>.
+ */
+
+use crate::allocator::{Allocator, BuddyAllocator, DriverAllocator, GenericAllocator};
+use crate::device::status;
+use crate::device::OffchipMemory;
+use crate::dma::{DMAControl, DriverDMA, StripedDMA};
+use crate::dma_user_space::{BlueDMA, DmaEngine, UserSpaceDMA};
+use crate::pe::PE;
+use crate::reactor::Reactor;
+use crate::tlkm::DeviceDriver;
+use config::Config;
+use core::fmt::Debug;
+use memmap::MmapMut;
+use snafu::ResultExt;
+use std::collections::HashMap;
+use std::sync::Arc;
+
+#[derive(Debug, Snafu)]
+pub enum Error {
+    #[snafu(display("Could not find DMA engine {}.", name))]
+    DMAEngineMissing { name: String },
+
+    #[snafu(display("No DMA engine protocol known for component {}.", name))]
+    DMAEngineUnknown { name: String },
+
+    #[snafu(display("DMA Error: {}", source))]
+    DMAError { source: crate::dma::Error },
+
+    #[snafu(display("Allocator Error: {}", source))]
+    AllocatorError { source: crate::allocator::Error },
+
+    #[snafu(display("Could not parse configuration {}", source))]
+    ConfigError { source: config::ConfigError },
+
+    #[snafu(display("Unknown allocator {} for memory {}.", allocator, memory))]
+    AllocatorUnknown { allocator: String, memory: String },
+}
+type Result<T, E = Error> = std::result::Result<T, E>;
+
+/// Everything a [`PlatformDriver`] may use to set up a device.
+///
+/// [`PlatformDriver`]: trait.PlatformDriver.html
+#[derive(Debug)]
+pub struct PlatformContext<'a> {
+    /// The decoded status core of the device.
+    pub status: &'a status::Status,
+    /// Driver handle of the device, used for IOCTLs and memory mappings.
+    pub device: &'a Arc<dyn DeviceDriver>,
+    /// Mapping of the platform components, e.g. DMA engines.
+    pub platform: &'a Arc<MmapMut>,
+    /// The runtime configuration.
+    pub settings: &'a Arc<Config>,
+    /// Reactor used to wait for DMA interrupts asynchronously.
+    pub reactor: &'a Arc<Reactor>,
+}
+
+/// Platform specific part of the device initialization.
+///
+/// Drivers are selected by the device name reported by TLKM. The built-in drivers
+/// are returned by [`default_platforms`], additional drivers can be added with
+/// [`TLKM.register_platform`].
+///
+/// [`default_platforms`]: fn.default_platforms.html
+/// [`TLKM.register_platform`]: ../tlkm/struct.TLKM.html#method.register_platform
+pub trait PlatformDriver: Debug + Send + Sync {
+    /// Build the off-chip memories of the device together with the DMA engines
+    /// used to access them. The first memory is used as default memory.
+    fn memories(&self, ctx: &PlatformContext) -> Result<Vec<Arc<OffchipMemory>>>;
+
+    /// Interrupt ID of the first PE. Only used for bitstreams that do not contain
+    /// interrupt information in the status core.
+    fn pe_interrupt_base(&self) -> usize {
+        0
+    }
+
+    /// Reset a PE that does not finish, e.g. when its job is aborted.
+    ///
+    /// Returns `false` if the platform can not reset PEs, which is the default.
+    fn reset_pe(&self, _pe: &PE) -> Result<bool> {
+        Ok(false)
+    }
+}
+
+/// Returns the drivers for the platforms supported by TaPaSCo.
+pub fn default_platforms() -> HashMap<String, Arc<dyn PlatformDriver>> {
+    let mut m: HashMap<String, Arc<dyn PlatformDriver>> = HashMap::new();
+    m.insert("pcie".to_string(), Arc::new(PCIePlatform::new()));
+    m.insert("zynq".to_string(), Arc::new(ZynqPlatform {}));
+    m.insert("zynqmp".to_string(), Arc::new(ZynqPlatform {}));
+    m
+}
+
+/// Create the allocator for a memory as selected by the configuration.
+///
+/// `memory.<name>.allocator` selects the allocator of a single memory, `memory.allocator`
+/// the default for all memories. Supported are `generic` and `buddy`.
+pub fn memory_allocator(
+    settings: &Config,
+    name: &str,
+    base: u64,
+    size: u64,
+    alignment: u64,
+) -> Result<Box<dyn Allocator + Sync + Send>> {
+    let allocator = match settings.get_str(&format!("memory.{}.allocator", name.to_lowercase())) {
+        Ok(x) => x,
+        Err(_) => settings.get_str("memory.allocator").context(ConfigError)?,
+    };
+    trace!("Using {} allocator for memory {}.", allocator, name);
+    match allocator.as_str() {
+        "generic" => Ok(Box::new(
+            GenericAllocator::new(base, size, alignment).context(AllocatorError)?,
+        )),
+        "buddy" => Ok(Box::new(
+            BuddyAllocator::new(base, size, alignment).context(AllocatorError)?,
+        )),
+        _ => Err(Error::AllocatorUnknown {
+            allocator: allocator,
+            memory: name.to_string(),
+        }),
+    }
+}
+
+/// PCIe based platforms using the user space DMA engines.
+///
+/// The memories are taken from the status core. Older bitstreams lack this information
+/// and fall back to the default of 4GB at 0x0.
+///
+/// The register protocol of a DMA engine is selected by the name of its platform
+/// component. `PLATFORM_COMPONENT_DMA*` components use BlueDMA, further protocols can be
+/// added with [`with_dma_engine`].
+///
+/// [`with_dma_engine`]: #method.with_dma_engine
+#[derive(Debug)]
+pub struct PCIePlatform {
+    dma_engines: Vec<(String, Arc<dyn DmaEngine>)>,
+}
+
+impl Default for PCIePlatform {
+    fn default() -> Self {
+        PCIePlatform::new()
+    }
+}
+
+impl PCIePlatform {
+    pub fn new() -> PCIePlatform {
+        PCIePlatform {
+            dma_engines: vec![("PLATFORM_COMPONENT_DMA".to_string(), Arc::new(BlueDMA {}))],
+        }
+    }
+
+    /// Use `engine` for all platform components whose name starts with `prefix`.
+    ///
+    /// Takes precedence over previously added engines with a matching prefix.
+    pub fn with_dma_engine(mut self, prefix: &str, engine: Arc<dyn DmaEngine>) -> PCIePlatform {
+        self.dma_engines.insert(0, (prefix.to_string(), engine));
+        self
+    }
+
+    pub(crate) fn dma_engine(&self, component: &str) -> Result<Arc<dyn DmaEngine>> {
+        match self
+            .dma_engines
+            .iter()
+            .find(|(prefix, _)| component.starts_with(prefix.as_str()))
+        {
+            Some((_, engine)) => Ok(engine.clone()),
+            None => Err(Error::DMAEngineUnknown {
+                name: component.to_string(),
+            }),
+        }
+    }
+
+    /// Retrieve the DMA used to access a memory.
+    ///
+    /// `components` is a comma separated list of the DMA components attached to the
+    /// memory. If it is empty, all `PLATFORM_COMPONENT_DMA<n>` components are used.
+    /// Transfers are striped over the components if more than one is given.
+    fn memory_dma(
+        &self,
+        engines: &mut HashMap<String, Arc<dyn DMAControl + Sync + Send>>,
+        components: &str,
+        ctx: &PlatformContext,
+    ) -> Result<Arc<dyn DMAControl + Sync + Send>> {
+        let mut names: Vec<String> = if components.is_empty() {
+            ctx.status
+                .platform
+                .iter()
+                .filter(|c| {
+                    c.name.starts_with("PLATFORM_COMPONENT_DMA")
+                        && c.name["PLATFORM_COMPONENT_DMA".len()..]
+                            .chars()
+                            .all(|x| x.is_ascii_digit())
+                })
+                .map(|c| c.name.clone())
+                .collect()
+        } else {
+            components
+                .split(',')
+                .map(|x| x.trim().to_string())
+                .filter(|x| !x.is_empty())
+                .collect()
+        };
+        if names.is_empty() {
+            names.push("PLATFORM_COMPONENT_DMA0".to_string());
+        }
+
+        if names.len() == 1 {
+            return self.user_space_dma(engines, &names[0], ctx);
+        }
+
+        info!("Striping transfers over DMA engines {:?}.", names);
+        let mut dmas = Vec::new();
+        for n in &names {
+            dmas.push(self.user_space_dma(engines, n, ctx)?);
+        }
+        Ok(Arc::new(StripedDMA::new(
+            dmas,
+            ctx.settings
+                .get::<usize>("dma.stripe_size")
+                .context(ConfigError)?,
+        )))
+    }
+
+    /// Retrieve the user space DMA engine of the given platform component.
+    ///
+    /// Memories attached to the same DMA engine share a single instance, which is
+    /// created on first use and cached in `engines`.
+    fn user_space_dma(
+        &self,
+        engines: &mut HashMap<String, Arc<dyn DMAControl + Sync + Send>>,
+        component: &str,
+        ctx: &PlatformContext,
+    ) -> Result<Arc<dyn DMAControl + Sync + Send>> {
+        if let Some(dma) = engines.get(component) {
+            return Ok(dma.clone());
+        }
+
+        let mut dma_offset = 0;
+        let mut dma_interrupt_read = 0;
+        let mut dma_interrupt_write = 1;
+        for comp in &ctx.status.platform {
+            if comp.name == component {
+                dma_offset = comp.offset;
+                for v in &comp.interrupts {
+                    if v.name == "READ" {
+                        dma_interrupt_read = v.mapping as usize;
+                    } else if v.name == "WRITE" {
+                        dma_interrupt_write = v.mapping as usize;
+                    } else {
+                        trace!("Unknown DMA interrupt: {}.", v.name);
+                    }
+                }
+            }
+        }
+        if dma_offset == 0 {
+            trace!("Could not find DMA engine {}.", component);
+            return Err(Error::DMAEngineMissing {
+                name: component.to_string(),
+            });
+        }
+
+        let settings = ctx.settings;
+        let dma: Arc<dyn DMAControl + Sync + Send> = Arc::new(
+            UserSpaceDMA::new(
+                ctx.device,
+                self.dma_engine(component)?,
+                dma_offset as usize,
+                dma_interrupt_read,
+                dma_interrupt_write,
+                ctx.platform,
+                ctx.reactor,
+                settings
+                    .get::<usize>("dma.read_buffer_size")
+                    .context(ConfigError)?,
+                settings
+                    .get::<usize>("dma.read_buffers")
+                    .context(ConfigError)?,
+                settings
+                    .get::<usize>("dma.write_buffer_size")
+                    .context(ConfigError)?,
+                settings
+                    .get::<usize>("dma.write_buffers")
+                    .context(ConfigError)?,
+            )
+            .context(DMAError)?,
+        );
+        engines.insert(component.to_string(), dma.clone());
+        Ok(dma)
+    }
+}
+
+impl PlatformDriver for PCIePlatform {
+    fn memories(&self, ctx: &PlatformContext) -> Result<Vec<Arc<OffchipMemory>>> {
+        let mut dma_engines: HashMap<String, Arc<dyn DMAControl + Sync + Send>> = HashMap::new();
+        let mut memories = Vec::new();
+
+        if ctx.status.memories.is_empty() {
+            info!("Using static memory allocation due to lack of dynamic data in the status core.");
+            info!("Allocating the default of 4GB at 0x0 for a PCIe platform");
+            memories.push(Arc::new(OffchipMemory::new(
+                "default".to_string(),
+                memory_allocator(ctx.settings, "default", 0, 4 * 1024 * 1024 * 1024, 64)?,
+                self.memory_dma(&mut dma_engines, "", ctx)?,
+            )));
+        } else {
+            for m in &ctx.status.memories {
+                let dma_name = if m.dma.is_empty() {
+                    "all DMA engines"
+                } else {
+                    &m.dma
+                };
+                let alignment = if m.alignment == 0 { 64 } else { m.alignment };
+                info!(
+                    "Found memory {} with {} bytes at 0x{:x} (alignment {}) using {}.",
+                    m.name, m.size, m.base, alignment, dma_name
+                );
+                memories.push(Arc::new(OffchipMemory::new(
+                    m.name.clone(),
+                    memory_allocator(ctx.settings, &m.name, m.base, m.size, alignment)?,
+                    self.memory_dma(&mut dma_engines, &m.dma, ctx)?,
+                )));
+            }
+        }
+
+        Ok(memories)
+    }
+
+    fn pe_interrupt_base(&self) -> usize {
+        4
+    }
+}
+
+/// Zynq and ZynqMP based platforms. Memory is allocated and copied by the driver.
+#[derive(Debug)]
+pub struct ZynqPlatform {}
+
+impl PlatformDriver for ZynqPlatform {
+    fn memories(&self, ctx: &PlatformContext) -> Result<Vec<Arc<OffchipMemory>>> {
+        info!("Using driver allocation for Zynq/ZynqMP based platform.");
+        Ok(vec![Arc::new(OffchipMemory::new(
+            "default".to_string(),
+            Box::new(DriverAllocator::new(ctx.device).context(AllocatorError)?),
+            Arc::new(DriverDMA::new(ctx.device)),
+        ))])
+    }
+}
+
+#[cfg(test)]
+mod platform_tests {
+    use crate::device::{DeviceAddress, DeviceSize};
+    use crate::dma_user_space::{BlueDMA, DmaEngine};
+    use crate::platform::PCIePlatform;
+    use crate::sim::testing::Result;
+    use crate::sim::{PEContext, SimulatedDevice, SimulatedPE, SimulatedTLKM};
+    use crate::tlkm::TLKM;
+    use memmap::MmapMut;
+    use std::collections::HashMap;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::Arc;
+
+    #[test]
+    fn custom_platform() -> Result<()> {
+        let sim = SimulatedTLKM::new(vec![SimulatedDevice::new("custom")
+            .pe(SimulatedPE::new(14, "counter", |_: &mut PEContext| 0), 1)
+            .memory("DDR", 0, 1024 * 1024 * 1024)]);
+        let mut tlkm = TLKM::with_driver(Arc::new(sim))?;
+        assert!(tlkm.device_alloc(0, &HashMap::new()).is_err());
+        tlkm.register_platform("custom", Arc::new(PCIePlatform::new()));
+        let dev = tlkm.device_alloc(0, &HashMap::new())?;
+        assert_eq!(dev.default_memory()?.name(), "DDR");
+        Ok(())
+    }
+
+    /// BlueDMA counting the transfers scheduled on it.
+    #[derive(Debug)]
+    struct CountingDMA {
+        transfers: AtomicUsize,
+    }
+
+    impl CountingDMA {
+        fn new() -> Arc<CountingDMA> {
+            Arc::new(CountingDMA {
+                transfers: AtomicUsize::new(0),
+            })
+        }
+
+        /// Address to compare the engine with the ones chosen by a platform.
+        fn addr(&self) -> usize {
+            self as *const CountingDMA as usize
+        }
+    }
+
+    impl DmaEngine for CountingDMA {
+        fn schedule_transfer(
+            &self,
+            memory: &MmapMut,
+            offset: usize,
+            addr_host: u64,
+            addr_device: DeviceAddress,
+            size: DeviceSize,
+            from_device: bool,
+        ) -> std::result::Result<(), crate::dma::Error> {
+            self.transfers.fetch_add(1, Ordering::SeqCst);
+            BlueDMA {}.schedule_transfer(memory, offset, addr_host, addr_device, size, from_device)
+        }
+    }
+
+    #[test]
+    fn dma_engine_selection() -> Result<()> {
+        let all = CountingDMA::new();
+        let second = CountingDMA::new();
+        let platform = PCIePlatform::default()
+            .with_dma_engine("PLATFORM_COMPONENT_DMA", all.clone())
+            .with_dma_engine("PLATFORM_COMPONENT_DMA1", second.clone());
+        let engine = |c: &str| {
+            platform
+                .dma_engine(c)
+                .map(|e| Arc::as_ptr(&e) as *const CountingDMA as usize)
+        };
+        assert_eq!(engine("PLATFORM_COMPONENT_DMA0")?, all.addr());
+        assert_eq!(engine("PLATFORM_COMPONENT_DMA1")?, second.addr());
+        assert_eq!(
+            format!(
+                "{:?}",
+                PCIePlatform::new().dma_engine("PLATFORM_COMPONENT_DMA0")?
+            ),
+            "BlueDMA"
+        );
+        match PCIePlatform::new().dma_engine("PLATFORM_COMPONENT_MSIX0") {
+            Err(crate::platform::Error::DMAEngineUnknown { name }) => {
+                assert_eq!(name, "PLATFORM_COMPONENT_MSIX0")
+            }
+            x => panic!("Unexpected DMA engine {:?}", x),
+        }
+
+        // Transfers striped over both engines use the selected protocols.
+        let sim = SimulatedTLKM::new(vec![SimulatedDevice::new("pcie")
+            .pe(SimulatedPE::new(14, "counter", |_: &mut PEContext| 0), 1)
+            .dma_engines(2)]);
+        let mut tlkm = TLKM::with_driver(Arc::new(sim))?;
+        tlkm.register_platform("pcie", Arc::new(platform));
+        let dev = tlkm.device_alloc(0, &HashMap::new())?;
+        let mem = dev.default_memory()?;
+        let data = vec![3u8; 9 * 1024 * 1024];
+        let addr = mem
+            .allocator()
+            .lock()
+            .unwrap()
+            .allocate(data.len() as u64)?;
+        mem.dma().copy_to(&data, addr)?;
+        let mut back = vec![0u8; data.len()];
+        mem.dma().copy_from(addr, &mut back)?;
+        assert!(data == back);
+        assert!(all.transfers.load(Ordering::SeqCst) > 0);
+        assert!(second.transfers.load(Ordering::SeqCst) > 0);
+        Ok(())
+    }
+}
@@ -0,0 +1,499 @@
+/*
+ * Copyright (c) 2014-2020 Embedded Systems and Applications, TU Darmstadt.
+ *
+ * This file is part of TaPaSCo
+ * (see https://github.com/esa-tu-darmstadt/tapasco).
+ *
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU Lesser General Public License as published by
+ * the Free Software Foundation, either version 3 of the License, or
+ * (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU Lesser General Public License for more details.
+ *
+ * You should have received a copy of the GNU Lesser General Public License
+ * along with this program. If not, see <http://www.gnu.org/licenses/>.
+ */
+
+//! Several devices used as a single resource.
+
+use crate::device::Device;
+use crate::device::OffchipMemory;
+use crate::device::PEParameter;
+use crate::dispatcher::{Dispatcher, Executor, JobHandle, Run};
+use crate::job::Job;
+use crate::pe::PEId;
+use crate::scheduler::{ReleaseSignal, SchedulerStats};
+use snafu::ResultExt;
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+#[derive(Debug, Snafu)]
+pub enum Error {
+    #[snafu(display("Device {} Error: {}", device, source))]
+    DeviceError {
+        source: crate::device::Error,
+        device: usize,
+    },
+
+    #[snafu(display("Job Error: {}", source))]
+    JobError { source: crate::job::Error },
+
+    #[snafu(display("Dispatcher Error: {}", source))]
+    DispatcherError { source: crate::dispatcher::Error },
+
+    #[snafu(display("A device pool requires at least one device."))]
+    NoDevices {},
+
+    #[snafu(display("Device {} is not part of the pool.", device))]
+    NoSuchDevice { device: usize },
+
+    #[snafu(display("No device in the pool has a PE with ID {}.", id))]
+    NoSuchPE { id: PEId },
+
+    #[snafu(display("All PEs of type {} in the pool are faulty.", id))]
+    AllFaulty { id: PEId },
+
+    #[snafu(display("PE Type {} unavailable.", id))]
+    PEUnavailable { id: PEId },
+
+    #[snafu(display("Scheduler Error: {}", source))]
+    SchedulerError { source: crate::scheduler::Error },
+
+    #[snafu(display(
+        "Parameter refers to memory {} which does not belong to device {}.",
+        memory,
+        device
+    ))]
+    ForeignMemory { memory: String, device: usize },
+
+    #[snafu(display("Mutex has been poisoned"))]
+    MutexError {},
+}
+
+type Result<T, E = Error> = std::result::Result<T, E>;
+
+impl<T> From<std::sync::PoisonError<T>> for Error {
+    fn from(_error: std::sync::PoisonError<T>) -> Self {
+        Error::MutexError {}
+    }
+}
+
+/// Load balances jobs over several devices, e.g. all cards returned by
+/// [`TLKM.device_enum`].
+///
+/// PEs are acquired from whichever member device has a free PE of the requested type,
+/// starting with a different device on every request. Members have to be in exclusive
+/// access mode to acquire PEs.
+///
+/// Device buffers of a job have to be placed in the memory of the device executing it:
+/// `DataTransferAlloc` parameters are moved to the memory of the same name on that
+/// device, or its default memory, by [`localize`]. This happens automatically for jobs
+/// passed to [`submit`].
+///
+/// [`TLKM.device_enum`]: ../tlkm/struct.TLKM.html#method.device_enum
+/// [`localize`]: #method.localize
+/// [`submit`]: #method.submit
+#[derive(Debug)]
+pub struct DevicePool {
+    /// Declared first to finish submitted jobs before the devices are dropped.
+    dispatcher: Mutex<Option<Arc<Dispatcher>>>,
+    members: Arc<Members>,
+}
+
+#[derive(Debug)]
+struct Members {
+    devices: Vec<Device>,
+    /// Device to try first on the next request.
+    next: AtomicUsize,
+    /// Notified by the schedulers of all devices whenever a PE might have become free.
+    released: Arc<ReleaseSignal>,
+}
+
+impl Members {
+    /// Devices with PEs of type `id`, rotated to spread requests over the pool.
+    fn candidates(&self, id: PEId) -> Result<Vec<usize>> {
+        let start = self.next.fetch_add(1, Ordering::Relaxed);
+        let n = self.devices.len();
+        let c: Vec<usize> = (0..n)
+            .map(|i| (start + i) % n)
+            .filter(|d| self.devices[*d].num_pes(id) > 0)
+            .collect();
+        ensure!(!c.is_empty(), NoSuchPE { id: id });
+        Ok(c)
+    }
+
+    fn check(&self, id: PEId) -> Result<()> {
+        ensure!(
+            self.devices.iter().any(|d| d.num_pes(id) > 0),
+            NoSuchPE { id: id }
+        );
+        Ok(())
+    }
+
+    fn try_acquire_pe(&self, id: PEId, candidates: &[usize]) -> Result<Option<(usize, Job)>> {
+        for d in candidates.iter().cloned() {
+            if let Some(job) = self.devices[d]
+                .try_acquire_pe(id)
+                .context(DeviceError { device: d })?
+            {
+                trace!("Acquired PE of type {} on device {}.", id, d);
+                return Ok(Some((d, job)));
+            }
+        }
+        Ok(None)
+    }
+
+    /// Fail if no candidate device has a PE of type `id` in service.
+    fn check_healthy(&self, id: PEId, candidates: &[usize]) -> Result<()> {
+        for d in candidates.iter() {
+            if self.devices[*d]
+                .healthy_pes(id)
+                .context(DeviceError { device: *d })?
+                > 0
+            {
+                return Ok(());
+            }
+        }
+        Err(Error::AllFaulty { id: id })
+    }
+
+    /// Try the candidate devices whenever a PE has been released until one is free or
+    /// `deadline` passed.
+    fn acquire_pe_until(
+        &self,
+        id: PEId,
+        deadline: Option<Instant>,
+    ) -> Result<Option<(usize, Job)>> {
+        let candidates = self.candidates(id)?;
+        loop {
+            let seen = self.released.generation().context(SchedulerError)?;
+            if let Some(x) = self.try_acquire_pe(id, &candidates)? {
+                return Ok(Some(x));
+            }
+            self.check_healthy(id, &candidates)?;
+            if let Some(d) = deadline {
+                if Instant::now() >= d {
+                    trace!("Timeout while waiting for PE of type {} in the pool.", id);
+                    return Ok(None);
+                }
+            }
+            self.released.wait(seen, deadline).context(SchedulerError)?;
+        }
+    }
+
+    fn localize(&self, device: usize, args: Vec<PEParameter>) -> Result<Vec<PEParameter>> {
+        let dev = &self.devices[device];
+        let local = |m: &Arc<OffchipMemory>| dev.memories().iter().any(|x| Arc::ptr_eq(x, m));
+        let foreign = |m: &Arc<OffchipMemory>| Error::ForeignMemory {
+            memory: m.name().to_string(),
+            device: device,
+        };
+        args.into_iter()
+            .map(|arg| match arg {
+                PEParameter::DataTransferAlloc(mut x) => {
+                    if !local(&x.memory) {
+                        if x.fixed.is_some() {
+                            return Err(foreign(&x.memory));
+                        }
+                        x.memory = match dev.memory(x.memory.name()) {
+                            Ok(m) => m,
+                            Err(_) => dev
+                                .default_memory()
+                                .context(DeviceError { device: device })?,
+                        };
+                    }
+                    Ok(PEParameter::DataTransferAlloc(x))
+                }
+                PEParameter::DataTransferPrealloc(x) if !local(&x.memory) => {
+                    Err(foreign(&x.memory))
+                }
+                PEParameter::Buffer(x) if !local(x.memory()) => Err(foreign(x.memory())),
+                x => Ok(x),
+            })
+            .collect()
+    }
+
+    /// Acquire a PE of type `id` on any device for the dispatcher.
+    fn runner(members: &Arc<Members>, id: PEId) -> Result<Option<Run>> {
+        let candidates = members.candidates(id)?;
+        let acquired = members.try_acquire_pe(id, &candidates)?;
+        if acquired.is_none() {
+            members.check_healthy(id, &candidates)?;
+        }
+        Ok(acquired.map(|(d, job)| {
+            let members = members.clone();
+            Box::new(move |args| {
+                let args = members.localize(d, args).map_err(dispatcher_error)?;
+                Dispatcher::execute(job, args)
+            }) as Run
+        }))
+    }
+}
+
+fn dispatcher_error(e: Error) -> crate::dispatcher::Error {
+    crate::dispatcher::Error::PoolError {
+        source: Box::new(e),
+    }
+}
+
+impl DevicePool {
+    pub fn new(devices: Vec<Device>) -> Result<DevicePool> {
+        ensure!(!devices.is_empty(), NoDevices {});
+        trace!("Creating pool of {} devices.", devices.len());
+        let released = Arc::new(ReleaseSignal::default());
+        for (i, dev) in devices.iter().enumerate() {
+            dev.add_listener(&released)
+                .context(DeviceError { device: i })?;
+        }
+        Ok(DevicePool {
+            dispatcher: Mutex::new(None),
+            members: Arc::new(Members {
+                devices: devices,
+                next: AtomicUsize::new(0),
+                released: released,
+            }),
+        })
+    }
+
+    /// Member devices, indexed by the device numbers used throughout the pool.
+    pub fn devices(&self) -> &[Device] {
+        &self.members.devices
+    }
+
+    /// Return the number of PEs of a given ID on all devices.
+    pub fn num_pes(&self, id: PEId) -> usize {
+        self.members.devices.iter().map(|d| d.num_pes(id)).sum()
+    }
+
+    /// Return the number of PEs of every type on all devices.
+    pub fn pe_types(&self) -> HashMap<PEId, usize> {
+        let mut types = HashMap::new();
+        for d in self.members.devices.iter() {
+            for (id, n) in d.pe_types() {
+                *types.entry(id).or_insert(0) += n;
+            }
+        }
+        types
+    }
+
+    /// Request a PE from any device, blocks until one is free.
+    ///
+    /// Returns the index of the chosen device together with the [`Job`]. Parameters
+    /// referring to device memory should be passed through [`localize`] before
+    /// starting the job.
+    ///
+    /// [`Job`]: ../job/struct.Job.html
+    /// [`localize`]: #method.localize
+    pub fn acquire_pe(&self, id: PEId) -> Result<(usize, Job)> {
+        match self.members.acquire_pe_until(id, None)? {
+            Some(x) => Ok(x),
+            None => Err(Error::PEUnavailable { id }),
+        }
+    }
+
+    /// Request a PE from any device, waiting at most `timeout` for one to become free.
+    pub fn acquire_pe_timeout(&self, id: PEId, timeout: Duration) -> Result<Option<(usize, Job)>> {
+        self.members
+            .acquire_pe_until(id, Some(Instant::now() + timeout))
+    }
+
+    /// Request a PE from any device without waiting.
+    pub fn try_acquire_pe(&self, id: PEId) -> Result<Option<(usize, Job)>> {
+        let candidates = self.members.candidates(id)?;
+        self.members.try_acquire_pe(id, &candidates)
+    }
+
+    /// Move the device memory used by `args` to `device`.
+    ///
+    /// `DataTransferAlloc` parameters targeting another device use the memory of the
+    /// same name on `device`, or its default memory. Preallocated memory can not be
+    /// moved and results in `ForeignMemory`, as do `DataTransferAlloc` parameters at a
+    /// fixed address.
+    pub fn localize(&self, device: usize, args: Vec<PEParameter>) -> Result<Vec<PEParameter>> {
+        ensure!(
+            device < self.members.devices.len(),
+            NoSuchDevice { device: device }
+        );
+        self.members.localize(device, args)
+    }
+
+    /// Queue a job for a PE of type `id` on any device and return immediately.
+    ///
+    /// Works like [`Device.submit`], the job is executed on the first device with a
+    /// free PE. Queue size and concurrent jobs are the sum of the `dispatcher` settings
+    /// of the members.
+    ///
+    /// [`Device.submit`]: ../device/struct.Device.html#method.submit
+    pub fn submit(&self, id: PEId, args: Vec<PEParameter>) -> Result<JobHandle> {
+        self.members.check(id)?;
+        trace!("Submitting job for PE type {} to the pool.", id);
+        self.dispatcher()?.submit(id, args).context(DispatcherError)
+    }
+
+    /// Same as [`submit`] but fails with `QueueFull` instead of waiting for room in
+    /// the queue.
+    ///
+    /// [`submit`]: #method.submit
+    pub fn try_submit(&self, id: PEId, args: Vec<PEParameter>) -> Result<JobHandle> {
+        self.members.check(id)?;
+        self.dispatcher()?
+            .try_submit(id, args)
+            .context(DispatcherError)
+    }
+
+    /// The dispatcher is started on the first submission.
+    fn dispatcher(&self) -> Result<Arc<Dispatcher>> {
+        let mut d = self.dispatcher.lock()?;
+        if let Some(x) = d.as_ref() {
+            return Ok(x.clone());
+        }
+        let mut queue_size = 0;
+        let mut in_flight = 0;
+        for (i, dev) in self.members.devices.iter().enumerate() {
+            let (q, f) = dev.dispatcher_config().context(DeviceError { device: i })?;
+            queue_size += q;
+            in_flight += f;
+        }
+        let members = self.members.clone();
+        let executor: Executor =
+            Arc::new(move |id| Members::runner(&members, id).map_err(dispatcher_error));
+        let x = Arc::new(
+            Dispatcher::with_executor(
+                executor,
+                self.members.released.clone(),
+                queue_size,
+                in_flight,
+            )
+            .context(DispatcherError)?,
+        );
+        *d = Some(x.clone());
+        Ok(x)
+    }
+
+    /// Utilization of all devices combined. PE instances are listed device by device,
+    /// PE types are merged over all devices.
+    pub fn stats(&self) -> Result<SchedulerStats> {
+        let mut stats = SchedulerStats::default();
+        for (i, d) in self.members.devices.iter().enumerate() {
+            let s = d.stats().context(DeviceError { device: i })?;
+            stats.pes.extend(s.pes);
+            for (id, t) in s.types.iter() {
+                stats.types.entry(*id).or_default().merge(t);
+            }
+        }
+        Ok(stats)
+    }
+
+    pub fn reset_stats(&self) -> Result<()> {
+        for (i, d) in self.members.devices.iter().enumerate() {
+            d.reset_stats().context(DeviceError { device: i })?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod pool_tests {
+    use crate::device::{DataTransferAlloc, PEParameter};
+    use crate::pool::DevicePool;
+    use crate::sim::testing::Result;
+    use crate::sim::{PEContext, SimulatedDevice, SimulatedPE, SimulatedTLKM};
+    use crate::tlkm::{tlkm_access, TLKM};
+    use std::collections::HashMap;
+    use std::sync::Arc;
+    use std::thread;
+    use std::time::Duration;
+
+    #[test]
+    fn device_pool() -> Result<()> {
+        let increment = || {
+            SimulatedPE::new(14, "sim:increment", |pe: &mut PEContext| {
+                let mut data = vec![0u8; pe.arg(1) as usize];
+                pe.read_memory(pe.arg(0), &mut data);
+                data.iter_mut().for_each(|x| *x = x.wrapping_add(1));
+                pe.write_memory(pe.arg(0), &data);
+                data.len() as u64
+            })
+        };
+        let add = SimulatedPE::new(11, "sim:add", |pe: &mut PEContext| pe.arg(0) + pe.arg(1));
+        let sim = SimulatedTLKM::new(vec![
+            SimulatedDevice::new("pcie").pe(increment(), 1),
+            SimulatedDevice::new("pcie").pe(increment(), 1).pe(add, 1),
+        ]);
+        let tlkm = TLKM::with_driver(Arc::new(sim))?;
+        let mut devices = tlkm.device_enum(&HashMap::new())?;
+        for d in devices.iter_mut() {
+            d.change_access(tlkm_access::TlkmAccessExclusive)?;
+        }
+        let pool = DevicePool::new(devices)?;
+        assert_eq!(pool.num_pes(14), 2);
+        assert_eq!(pool.pe_types()[&11], 1);
+        assert!(pool.submit(42, vec![]).is_err());
+
+        // One PE on every device.
+        let (a, job_a) = pool.acquire_pe(14)?;
+        let (b, job_b) = pool.acquire_pe(14)?;
+        assert_ne!(a, b);
+        assert!(pool.try_acquire_pe(14)?.is_none());
+        assert!(pool
+            .acquire_pe_timeout(14, Duration::from_millis(10))?
+            .is_none());
+        drop(job_a);
+        assert_eq!(pool.acquire_pe(14)?.0, a);
+        drop(job_b);
+
+        // Blocked requests are woken by a release on any device.
+        let pool = Arc::new(pool);
+        let (a, job_a) = pool.acquire_pe(14)?;
+        let (b, job_b) = pool.acquire_pe(14)?;
+        let p = pool.clone();
+        let t = thread::spawn(move || p.acquire_pe(14).map(|(d, _)| d).ok());
+        drop(job_b);
+        assert_eq!(t.join().unwrap(), Some(b));
+        drop(job_a);
+        assert_ne!(a, b);
+
+        // Buffers of device 0 are moved to the device running the job.
+        pool.reset_stats()?;
+        let mem = pool.devices()[0].default_memory()?;
+        let len = 4096;
+        let handles = (0..6)
+            .map(|i| {
+                pool.submit(
+                    14,
+                    vec![
+                        PEParameter::DataTransferAlloc(DataTransferAlloc {
+                            data: vec![i as u8; len].into_boxed_slice(),
+                            from_device: true,
+                            to_device: true,
+                            free: true,
+                            memory: mem.clone(),
+                            fixed: None,
+                        }),
+                        PEParameter::Single64(len as u64),
+                    ],
+                )
+            })
+            .collect::<std::result::Result<Vec<_>, _>>()?;
+        for (i, h) in handles.into_iter().enumerate() {
+            let (rv, buffers) = h.wait()?;
+            assert_eq!(rv, len as u64);
+            assert!(buffers[0].iter().all(|x| *x == i as u8 + 1));
+        }
+        let stats = pool.stats()?;
+        assert_eq!(stats.pes.len(), 3);
+        assert_eq!(stats.types[&14].jobs, 6);
+        assert!(stats.pes[0].jobs > 0 && stats.pes[1].jobs > 0);
+        for d in pool.devices() {
+            assert_eq!(d.default_memory()?.stats()?.allocations, 0);
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,244 @@
+/*
+ * Copyright (c) 2014-2020 Embedded Systems and Applications, TU Darmstadt.
+ *
+ * This file is part of TaPaSCo
+ * (see https://github.com/esa-tu-darmstadt/tapasco).
+ *
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU Lesser General Public License as published by
+ * the Free Software Foundation, either version 3 of the License, or
+ * (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU Lesser General Public License for more details.
+ *
+ * You should have received a copy of the GNU Lesser General Public License
+ * along with this program. If not, see <http://www.gnu.org/licenses/>.
+ */
+
+use nix::sys::epoll::{
+    epoll_create1, epoll_ctl, epoll_wait, EpollCreateFlags, EpollEvent, EpollFlags, EpollOp,
+};
+use nix::sys::eventfd::eventfd;
+use nix::sys::eventfd::EfdFlags;
+use nix::unistd::close;
+use nix::unistd::write;
+use snafu::ResultExt;
+use std::collections::HashMap;
+use std::os::unix::io::RawFd;
+use std::sync::Arc;
+use std::sync::Mutex;
+use std::task::Waker;
+use std::thread;
+
+#[derive(Debug, Snafu)]
+pub enum Error {
+    #[snafu(display("Could not create epoll instance: {}", source))]
+    EpollCreate { source: nix::Error },
+
+    #[snafu(display("Could not register fd {} with epoll: {}", fd, source))]
+    EpollRegister { source: nix::Error, fd: RawFd },
+
+    #[snafu(display("Could not create reactor thread: {}", source))]
+    ThreadCreate { source: std::io::Error },
+
+    #[snafu(display("Mutex has been poisoned"))]
+    MutexError {},
+}
+
+type Result<T, E = Error> = std::result::Result<T, E>;
+
+impl<T> From<std::sync::PoisonError<T>> for Error {
+    fn from(_error: std::sync::PoisonError<T>) -> Self {
+        Error::MutexError {}
+    }
+}
+
+#[derive(Debug)]
+struct ReactorInner {
+    epoll: RawFd,
+    shutdown: RawFd,
+    wakers: Mutex<HashMap<RawFd, Vec<Waker>>>,
+}
+
+/// Wakes futures waiting on eventfds, e.g. PE or DMA interrupts.
+///
+/// A single background thread waits on all registered eventfds using epoll.
+/// Registrations are one-shot: Once an eventfd becomes readable it is removed
+/// from the epoll set and all wakers registered for it are woken. The woken futures
+/// are expected to read the eventfd and register again if necessary.
+///
+/// Only uses `std::task::Waker` and is therefore independent of the async runtime.
+#[derive(Debug)]
+pub struct Reactor {
+    inner: Arc<ReactorInner>,
+    thread: Option<thread::JoinHandle<()>>,
+}
+
+impl Drop for Reactor {
+    fn drop(&mut self) {
+        trace!("Stopping reactor.");
+        let _ = write(self.inner.shutdown, &1u64.to_ne_bytes());
+        if let Some(t) = self.thread.take() {
+            let _ = t.join();
+        }
+        let _ = close(self.inner.shutdown);
+        let _ = close(self.inner.epoll);
+    }
+}
+
+impl Reactor {
+    pub fn new() -> Result<Reactor> {
+        let epoll = epoll_create1(EpollCreateFlags::EPOLL_CLOEXEC).context(EpollCreate)?;
+        let shutdown = match eventfd(0, EfdFlags::EFD_CLOEXEC) {
+            Ok(x) => x,
+            Err(e) => {
+                let _ = close(epoll);
+                return Err(Error::EpollCreate { source: e });
+            }
+        };
+        let inner = Arc::new(ReactorInner {
+            epoll: epoll,
+            shutdown: shutdown,
+            wakers: Mutex::new(HashMap::new()),
+        });
+
+        let mut ev = EpollEvent::new(EpollFlags::EPOLLIN, shutdown as u64);
+        if let Err(e) = epoll_ctl(epoll, EpollOp::EpollCtlAdd, shutdown, &mut ev) {
+            let _ = close(shutdown);
+            let _ = close(epoll);
+            return Err(Error::EpollRegister {
+                source: e,
+                fd: shutdown,
+            });
+        }
+
+        let thread_inner = inner.clone();
+        let thread = match thread::Builder::new()
+            .name("tapasco-reactor".to_string())
+            .spawn(move || Reactor::run(&thread_inner))
+        {
+            Ok(x) => x,
+            Err(e) => {
+                let _ = close(shutdown);
+                let _ = close(epoll);
+                return Err(Error::ThreadCreate { source: e });
+            }
+        };
+
+        Ok(Reactor {
+            inner: inner,
+            thread: Some(thread),
+        })
+    }
+
+    fn run(inner: &ReactorInner) {
+        let mut events = vec![EpollEvent::empty(); 64];
+        loop {
+            let n = match epoll_wait(inner.epoll, &mut events, -1) {
+                Ok(n) => n,
+                Err(nix::Error::Sys(nix::errno::Errno::EINTR)) => continue,
+                Err(e) => {
+                    error!("Reactor stopped after epoll failure: {}", e);
+                    return;
+                }
+            };
+            for ev in &events[..n] {
+                let fd = ev.data() as RawFd;
+                if fd == inner.shutdown {
+                    trace!("Reactor received shutdown request.");
+                    return;
+                }
+                let wakers = match inner.wakers.lock() {
+                    Ok(mut w) => {
+                        let wakers = w.remove(&fd);
+                        if wakers.is_some() {
+                            let _ = epoll_ctl(inner.epoll, EpollOp::EpollCtlDel, fd, None);
+                        }
+                        wakers.unwrap_or_default()
+                    }
+                    Err(_) => {
+                        error!("Reactor stopped after mutex has been poisoned.");
+                        return;
+                    }
+                };
+                trace!("Reactor waking up {} waiters on fd {}.", wakers.len(), fd);
+                for w in wakers {
+                    w.wake();
+                }
+            }
+        }
+    }
+
+    /// Wake `waker` once `fd` becomes readable.
+    ///
+    /// Several wakers may wait for the same fd, e.g. concurrent transfers on a DMA
+    /// engine. All of them are woken.
+    pub fn register(&self, fd: RawFd, waker: &Waker) -> Result<()> {
+        let mut wakers = self.inner.wakers.lock()?;
+        match wakers.get_mut(&fd) {
+            Some(w) => {
+                if !w.iter().any(|x| x.will_wake(waker)) {
+                    w.push(waker.clone());
+                }
+            }
+            None => {
+                let mut ev =
+                    EpollEvent::new(EpollFlags::EPOLLIN | EpollFlags::EPOLLONESHOT, fd as u64);
+                epoll_ctl(self.inner.epoll, EpollOp::EpollCtlAdd, fd, &mut ev)
+                    .context(EpollRegister { fd: fd })?;
+                wakers.insert(fd, vec![waker.clone()]);
+            }
+        }
+        Ok(())
+    }
+
+    /// Remove all pending registrations of `fd`, e.g. if the only future waiting for
+    /// it has been dropped.
+    pub fn deregister(&self, fd: RawFd) -> Result<()> {
+        let mut wakers = self.inner.wakers.lock()?;
+        if wakers.remove(&fd).is_some() {
+            let _ = epoll_ctl(self.inner.epoll, EpollOp::EpollCtlDel, fd, None);
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod reactor_tests {
+    use crate::reactor::Reactor;
+    use crate::sim::testing::{FlagWaker, Result};
+    use nix::sys::eventfd::{eventfd, EfdFlags};
+    use nix::unistd::{close, write};
+    use std::sync::atomic::{AtomicBool, Ordering};
+    use std::sync::Arc;
+    use std::task::Waker;
+    use std::thread;
+    use std::time::Duration;
+
+    #[test]
+    fn reactor_wakes_all() -> Result<()> {
+        let reactor = Reactor::new()?;
+        let fd = eventfd(0, EfdFlags::EFD_CLOEXEC | EfdFlags::EFD_NONBLOCK)?;
+        let flags: Vec<_> = (0..2)
+            .map(|_| Arc::new(FlagWaker(AtomicBool::new(false))))
+            .collect();
+        for f in flags.iter() {
+            reactor.register(fd, &Waker::from(f.clone()))?;
+        }
+        write(fd, &1u64.to_ne_bytes())?;
+        for _ in 0..1000 {
+            if flags.iter().all(|f| f.0.load(Ordering::SeqCst)) {
+                break;
+            }
+            thread::sleep(Duration::from_millis(1));
+        }
+        let woken = flags.iter().all(|f| f.0.load(Ordering::SeqCst));
+        drop(reactor);
+        close(fd)?;
+        assert!(woken);
+        Ok(())
+    }
+}
@@ -20,24 +20,33 @@
 
 use crate::debug::UnsupportedDebugGenerator;
 use crate::debug::{DebugGenerator, NonDebugGenerator};
+use crate::device::DeviceAddress;
 use crate::device::OffchipMemory;
 use crate::pe::PEId;
+use crate::pe::PEStats;
 use crate::pe::PE;
-use crossbeam::deque::{Injector, Steal};
-use lockfree::map::Map;
+use crate::platform::PlatformDriver;
+use crate::reactor::Reactor;
+use crate::tlkm::DeviceDriver;
 use memmap::MmapMut;
 use snafu::ResultExt;
+use std::cmp::Reverse;
+use std::collections::BTreeSet;
 use std::collections::HashMap;
 use std::collections::VecDeque;
-use std::fs::File;
+use std::fmt::Debug;
 use std::sync::Arc;
-use std::thread;
+use std::sync::{Condvar, Mutex, MutexGuard, Weak};
+use std::time::{Duration, Instant};
 
 #[derive(Debug, Snafu)]
 pub enum Error {
     #[snafu(display("PE Type {} unavailable.", id))]
     PEUnavailable { id: PEId },
 
+    #[snafu(display("No PE type given to choose from."))]
+    NoPETypes {},
+
     #[snafu(display("PE Type {} is unknown.", id))]
     NoSuchPE { id: PEId },
 
@@ -56,19 +65,416 @@ pub enum Error {
 
     #[snafu(display("Debug Error: {}", source))]
     DebugError { source: crate::debug::Error },
+
+    #[snafu(display("Platform Error: {}", source))]
+    PlatformError { source: crate::platform::Error },
+
+    #[snafu(display("Mutex has been poisoned"))]
+    MutexError {},
+
+    #[snafu(display("PE {} is not marked as faulty.", slot))]
+    PENotFaulty { slot: usize },
+
+    #[snafu(display("PE {} is still running and could not be reset.", slot))]
+    PEResetFailed { slot: usize },
+
+    #[snafu(display("All PEs of type {} are faulty and have to be reset first.", id))]
+    AllFaulty { id: PEId },
+
+    #[snafu(display("PE instance {} does not exist.", slot))]
+    NoSuchInstance { slot: usize },
+
+    #[snafu(display("PE instance {} is faulty and has to be reset first.", slot))]
+    InstanceFaulty { slot: usize },
+
+    #[snafu(display(
+        "Requested {} PEs of type {} but only {} are in service.",
+        count,
+        id,
+        available
+    ))]
+    NotEnoughPEs {
+        id: PEId,
+        count: usize,
+        available: usize,
+    },
+
+    #[snafu(display(
+        "Unknown scheduling policy {}, possible values are fifo, lifo, round-robin, lru and priority.",
+        policy
+    ))]
+    PolicyUnknown { policy: String },
 }
 
 type Result<T, E = Error> = std::result::Result<T, E>;
 
+/// Health of a PE instance as reported by [`Scheduler.pe_health`].
+///
+/// [`Scheduler.pe_health`]: struct.Scheduler.html#method.pe_health
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum PEHealth {
+    Healthy,
+    /// The PE did not finish in time or could not be recovered after an abort.
+    /// It is not handed out until it has been reset with `reset_faulty_pe`.
+    Faulty,
+}
+
+/// Description of a single PE instance in the bitstream.
+#[derive(Debug, Clone, Getters)]
+pub struct PEInstance {
+    /// Index of the instance as used by `PE.id`.
+    #[get = "pub"]
+    slot: usize,
+    #[get = "pub"]
+    type_id: PEId,
+    #[get = "pub"]
+    name: String,
+    #[get = "pub"]
+    offset: DeviceAddress,
+    #[get = "pub"]
+    interrupt: usize,
+    #[get = "pub"]
+    local_memory: Option<Arc<OffchipMemory>>,
+}
+
+impl<T> From<std::sync::PoisonError<T>> for Error {
+    fn from(_error: std::sync::PoisonError<T>) -> Self {
+        Error::MutexError {}
+    }
+}
+
+/// Options for [`Scheduler.acquire_pe_with`].
+///
+/// [`Scheduler.acquire_pe_with`]: struct.Scheduler.html#method.acquire_pe_with
+#[derive(Debug, Copy, Clone, Default, PartialEq)]
+pub struct AcquireOptions {
+    /// Requests with a higher priority are served first if the `priority` policy is
+    /// used. Ignored by all other policies.
+    pub priority: i32,
+    /// Give up after this time instead of blocking until a PE is available.
+    pub timeout: Option<Duration>,
+}
+
+/// Lets threads outside of the scheduler wait for PEs to become free, e.g. to try
+/// the schedulers of several devices in turn.
+///
+/// Every notification bumps a counter. Read it with [`generation`] before trying to
+/// acquire a PE and pass it to [`wait`] afterwards, so no release in between is missed.
+///
+/// [`generation`]: #method.generation
+/// [`wait`]: #method.wait
+#[derive(Debug, Default)]
+pub struct ReleaseSignal {
+    generation: Mutex<u64>,
+    changed: Condvar,
+}
+
+impl ReleaseSignal {
+    pub fn generation(&self) -> Result<u64> {
+        Ok(*self.generation.lock()?)
+    }
+
+    pub fn notify(&self) -> Result<()> {
+        let mut generation = self.generation.lock()?;
+        *generation = generation.wrapping_add(1);
+        self.changed.notify_all();
+        Ok(())
+    }
+
+    /// Block until the generation differs from `seen` or `deadline` passed.
+    pub fn wait(&self, seen: u64, deadline: Option<Instant>) -> Result<()> {
+        let mut generation = self.generation.lock()?;
+        while *generation == seen {
+            generation = match deadline {
+                Some(d) => {
+                    let now = Instant::now();
+                    if now >= d {
+                        break;
+                    }
+                    self.changed.wait_timeout(generation, d - now)?.0
+                }
+                None => self.changed.wait(generation)?,
+            };
+        }
+        Ok(())
+    }
+}
+
+/// Decides which idle PE of a type is handed out next.
+///
+/// Every PE type has its own instance. All methods are called with the lock of the
+/// type held.
+pub trait SchedulingPolicy: Debug + Send {
+    /// Add a PE that became idle.
+    fn push(&mut self, pe: PE);
+
+    /// Remove the PE that should serve the next request.
+    fn pop(&mut self) -> Option<PE>;
+
+    /// Remove the idle PE with the given `PE.id`.
+    fn take(&mut self, slot: usize) -> Option<PE>;
+
+    /// All idle PEs in no particular order.
+    fn idle(&self) -> Box<dyn Iterator<Item = &PE> + '_>;
+
+    fn len(&self) -> usize;
+
+    fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Serve blocked requests by their priority instead of their arrival.
+    fn prioritized(&self) -> bool {
+        false
+    }
+}
+
+fn take_from(pes: &mut VecDeque<PE>, slot: usize) -> Option<PE> {
+    match pes.iter().position(|pe| *pe.id() == slot) {
+        Some(i) => pes.remove(i),
+        None => None,
+    }
+}
+
+/// Hands out the PE that has been idle for the longest time.
+#[derive(Debug, Default)]
+pub struct FifoPolicy {
+    idle: VecDeque<PE>,
+}
+
+impl SchedulingPolicy for FifoPolicy {
+    fn push(&mut self, pe: PE) {
+        self.idle.push_back(pe);
+    }
+
+    fn pop(&mut self) -> Option<PE> {
+        self.idle.pop_front()
+    }
+
+    fn take(&mut self, slot: usize) -> Option<PE> {
+        take_from(&mut self.idle, slot)
+    }
+
+    fn idle(&self) -> Box<dyn Iterator<Item = &PE> + '_> {
+        Box::new(self.idle.iter())
+    }
+
+    fn len(&self) -> usize {
+        self.idle.len()
+    }
+}
+
+/// Hands out the most recently released PE, e.g. to reuse data in its local memory.
+#[derive(Debug, Default)]
+pub struct LifoPolicy {
+    idle: VecDeque<PE>,
+}
+
+impl SchedulingPolicy for LifoPolicy {
+    fn push(&mut self, pe: PE) {
+        self.idle.push_back(pe);
+    }
+
+    fn pop(&mut self) -> Option<PE> {
+        self.idle.pop_back()
+    }
+
+    fn take(&mut self, slot: usize) -> Option<PE> {
+        take_from(&mut self.idle, slot)
+    }
+
+    fn idle(&self) -> Box<dyn Iterator<Item = &PE> + '_> {
+        Box::new(self.idle.iter())
+    }
+
+    fn len(&self) -> usize {
+        self.idle.len()
+    }
+}
+
+/// Cycles through the instances in the order of their `PE.id`, skipping busy PEs.
+#[derive(Debug, Default)]
+pub struct RoundRobinPolicy {
+    idle: VecDeque<PE>,
+    next: usize,
+}
+
+impl SchedulingPolicy for RoundRobinPolicy {
+    fn push(&mut self, pe: PE) {
+        self.idle.push_back(pe);
+    }
+
+    fn pop(&mut self) -> Option<PE> {
+        let next = self.next;
+        let i = self
+            .idle
+            .iter()
+            .enumerate()
+            .min_by_key(|(_, pe)| (*pe.id() < next, *pe.id()))
+            .map(|(i, _)| i)?;
+        let pe = self.idle.remove(i)?;
+        self.next = pe.id() + 1;
+        Some(pe)
+    }
+
+    fn take(&mut self, slot: usize) -> Option<PE> {
+        take_from(&mut self.idle, slot)
+    }
+
+    fn idle(&self) -> Box<dyn Iterator<Item = &PE> + '_> {
+        Box::new(self.idle.iter())
+    }
+
+    fn len(&self) -> usize {
+        self.idle.len()
+    }
+}
+
+/// Hands out the PE that has not been handed out for the longest time. Spreads the load
+/// evenly even if jobs finish out of order.
+#[derive(Debug, Default)]
+pub struct LeastRecentlyUsedPolicy {
+    idle: VecDeque<PE>,
+    last_used: HashMap<usize, u64>,
+    clock: u64,
+}
+
+impl SchedulingPolicy for LeastRecentlyUsedPolicy {
+    fn push(&mut self, pe: PE) {
+        self.idle.push_back(pe);
+    }
+
+    fn pop(&mut self) -> Option<PE> {
+        let last_used = &self.last_used;
+        let i = self
+            .idle
+            .iter()
+            .enumerate()
+            .min_by_key(|(_, pe)| (last_used.get(pe.id()).cloned(), *pe.id()))
+            .map(|(i, _)| i)?;
+        let pe = self.idle.remove(i)?;
+        self.clock += 1;
+        self.last_used.insert(*pe.id(), self.clock);
+        Some(pe)
+    }
+
+    fn take(&mut self, slot: usize) -> Option<PE> {
+        let pe = take_from(&mut self.idle, slot)?;
+        self.clock += 1;
+        self.last_used.insert(slot, self.clock);
+        Some(pe)
+    }
+
+    fn idle(&self) -> Box<dyn Iterator<Item = &PE> + '_> {
+        Box::new(self.idle.iter())
+    }
+
+    fn len(&self) -> usize {
+        self.idle.len()
+    }
+}
+
+/// Serves blocked requests with a higher [`AcquireOptions.priority`] first. PEs are
+/// handed out like [`FifoPolicy`].
+///
+/// [`AcquireOptions.priority`]: struct.AcquireOptions.html#structfield.priority
+/// [`FifoPolicy`]: struct.FifoPolicy.html
+#[derive(Debug, Default)]
+pub struct PriorityPolicy {
+    fifo: FifoPolicy,
+}
+
+impl SchedulingPolicy for PriorityPolicy {
+    fn push(&mut self, pe: PE) {
+        self.fifo.push(pe)
+    }
+
+    fn pop(&mut self) -> Option<PE> {
+        self.fifo.pop()
+    }
+
+    fn take(&mut self, slot: usize) -> Option<PE> {
+        self.fifo.take(slot)
+    }
+
+    fn idle(&self) -> Box<dyn Iterator<Item = &PE> + '_> {
+        self.fifo.idle()
+    }
+
+    fn len(&self) -> usize {
+        self.fifo.len()
+    }
+
+    fn prioritized(&self) -> bool {
+        true
+    }
+}
+
+/// Create the policy configured as `scheduler.<id>.policy` or `scheduler.policy`.
+pub fn scheduling_policy(policy: &str) -> Result<Box<dyn SchedulingPolicy>> {
+    match policy {
+        "fifo" => Ok(Box::new(FifoPolicy::default())),
+        "lifo" => Ok(Box::new(LifoPolicy::default())),
+        "round-robin" => Ok(Box::new(RoundRobinPolicy::default())),
+        "lru" => Ok(Box::new(LeastRecentlyUsedPolicy::default())),
+        "priority" => Ok(Box::new(PriorityPolicy::default())),
+        _ => Err(Error::PolicyUnknown {
+            policy: policy.to_string(),
+        }),
+    }
+}
+
+/// Utilization of all PEs as returned by [`Scheduler.stats`].
+///
+/// [`Scheduler.stats`]: struct.Scheduler.html#method.stats
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct SchedulerStats {
+    /// Statistics of every PE instance, indexed by `PE.id`.
+    pub pes: Vec<PEStats>,
+    /// Statistics of all instances of a type combined.
+    pub types: HashMap<PEId, PEStats>,
+}
+
+/// Idle PEs and blocked requests of a single PE type.
+#[derive(Debug)]
+struct PEQueue {
+    state: Mutex<QueueState>,
+    available: Condvar,
+}
+
+#[derive(Debug)]
+struct QueueState {
+    idle: Box<dyn SchedulingPolicy>,
+    /// Blocked requests ordered by priority and arrival.
+    waiting: BTreeSet<(Reverse<i32>, u64)>,
+    next_ticket: u64,
+}
+
 /// Main method to retrieve a PE for execution
 ///
-/// Uses an unblocking Injector primitive usually used for job stealing.
-/// Retrieves PEs based on a first-come-first-serve basis.
+/// Idle PEs of every type are managed by a [`SchedulingPolicy`] which decides which PE
+/// is handed out next. Threads waiting for a PE type without free PEs are parked until
+/// a PE of that type is released and served in order of arrival, or by priority for
+/// policies that support it.
+///
+/// [`SchedulingPolicy`]: trait.SchedulingPolicy.html
 #[derive(Debug)]
 pub struct Scheduler {
-    pes: Map<PEId, Injector<PE>>,
+    pes: HashMap<PEId, PEQueue>,
     pes_overview: HashMap<PEId, usize>,
     pes_name: HashMap<PEId, String>,
+    /// Notified on every release, used by threads waiting for several PE types at once.
+    any_released: (Mutex<()>, Condvar),
+    platform: Arc<dyn PlatformDriver>,
+    faulty: Mutex<Vec<PE>>,
+    /// Time a dropped `Job` waits for its PE before taking it out of service.
+    drop_timeout: Duration,
+    /// Notified whenever a PE might have become free, see `add_listener`.
+    listeners: Mutex<Vec<Weak<ReleaseSignal>>>,
+    /// Every PE instance, indexed by `PE.id`.
+    instances: Vec<PEInstance>,
+    /// Shared with the PEs, indexed by `PE.id`.
+    stats: Vec<Arc<Mutex<PEStats>>>,
 }
 
 impl Scheduler {
@@ -76,19 +482,20 @@ impl Scheduler {
         pes: &Vec<crate::device::status::Pe>,
         mmap: &Arc<MmapMut>,
         mut local_memories: VecDeque<Arc<OffchipMemory>>,
-        completion: &File,
+        completion: &Arc<dyn DeviceDriver>,
         debug_impls: &HashMap<String, Box<dyn DebugGenerator + Sync + Send>>,
-        is_pcie: bool,
+        platform: &Arc<dyn PlatformDriver>,
+        reactor: &Arc<Reactor>,
+        policy: &str,
+        drop_timeout: Duration,
     ) -> Result<Scheduler> {
-        let pe_hashed: Map<PEId, Injector<PE>> = Map::new();
+        let mut pe_hashed: HashMap<PEId, Box<dyn SchedulingPolicy>> = HashMap::new();
         let mut pes_overview: HashMap<PEId, usize> = HashMap::new();
         let mut pes_name: HashMap<PEId, String> = HashMap::new();
 
-        let mut interrupt_id = 0;
-
-        if is_pcie {
-            interrupt_id = 4;
-        }
+        let mut interrupt_id = platform.pe_interrupt_base();
+        let mut instances = Vec::new();
+        let mut stats = Vec::new();
 
         for (i, pe) in pes.iter().enumerate() {
             let debug = match &pe.debug {
@@ -130,12 +537,14 @@ impl Scheduler {
                 pe.size,
                 pe.name.to_string(),
                 mmap.clone(),
-                &completion,
+                completion.as_ref(),
                 interrupt_id,
+                reactor,
                 debug,
             )
             .context(PEError)?;
 
+            let pe_interrupt = interrupt_id;
             interrupt_id += 1;
 
             if pe.local_memory.is_some() {
@@ -144,11 +553,21 @@ impl Scheduler {
                 interrupt_id += 1;
             }
 
-            match pe_hashed.get(&(pe.id as PEId)) {
-                Some(l) => l.val().push(the_pe),
+            instances.push(PEInstance {
+                slot: i,
+                type_id: pe.id as PEId,
+                name: pe.name.clone(),
+                offset: pe.offset,
+                interrupt: pe_interrupt,
+                local_memory: the_pe.local_memory().clone(),
+            });
+            stats.push(the_pe.stats().clone());
+
+            match pe_hashed.get_mut(&(pe.id as PEId)) {
+                Some(l) => l.push(the_pe),
                 None => {
                     trace!("New PE type found: {} ({}).", pe.name, pe.id);
-                    let v = Injector::new();
+                    let mut v = scheduling_policy(policy)?;
                     v.push(the_pe);
                     pe_hashed.insert(pe.id as PEId, v);
                     pes_name.insert(pe.id as PEId, pe.name.clone());
@@ -163,54 +582,556 @@ impl Scheduler {
             };
         }
 
+        trace!("Scheduling PEs using the {} policy.", policy);
+        let queues = pe_hashed
+            .into_iter()
+            .map(|(id, idle)| {
+                (
+                    id,
+                    PEQueue {
+                        state: Mutex::new(QueueState {
+                            idle: idle,
+                            waiting: BTreeSet::new(),
+                            next_ticket: 0,
+                        }),
+                        available: Condvar::new(),
+                    },
+                )
+            })
+            .collect();
+
         Ok(Scheduler {
-            pes: pe_hashed,
+            pes: queues,
             pes_overview: pes_overview,
             pes_name: pes_name,
+            any_released: (Mutex::new(()), Condvar::new()),
+            platform: platform.clone(),
+            faulty: Mutex::new(Vec::new()),
+            drop_timeout: drop_timeout,
+            listeners: Mutex::new(Vec::new()),
+            instances: instances,
+            stats: stats,
         })
     }
 
+    /// Retrieve a PE of the given type, blocks until one is available.
     pub fn acquire_pe(&self, id: PEId) -> Result<PE> {
+        match self.acquire_pe_until(id, 0, None)? {
+            Some(pe) => Ok(pe),
+            None => Err(Error::PEUnavailable { id }),
+        }
+    }
+
+    /// Retrieve a PE of the given type. Gives up after `timeout` and returns `None`.
+    pub fn acquire_pe_timeout(&self, id: PEId, timeout: Duration) -> Result<Option<PE>> {
+        self.acquire_pe_until(id, 0, Some(Instant::now() + timeout))
+    }
+
+    /// Retrieve a PE of the given type using a priority and an optional timeout.
+    ///
+    /// Returns `None` only if a timeout is given and no PE became available in time.
+    pub fn acquire_pe_with(&self, id: PEId, opts: &AcquireOptions) -> Result<Option<PE>> {
+        let deadline = opts.timeout.map(|t| Instant::now() + t);
+        self.acquire_pe_until(id, opts.priority, deadline)
+    }
+
+    /// Retrieve a PE of the given type if one is available right now and no other
+    /// request is waiting for it.
+    pub fn try_acquire_pe(&self, id: PEId) -> Result<Option<PE>> {
+        let mut state = self.queue(id)?.state.lock()?;
+        if state.waiting.is_empty() {
+            Ok(state
+                .idle
+                .pop()
+                .map(|pe| Scheduler::acquired(pe, Instant::now())))
+        } else {
+            Ok(None)
+        }
+    }
+
+    fn queue(&self, id: PEId) -> Result<&PEQueue> {
         match self.pes.get(&id) {
-            Some(l) => loop {
-                match l.val().steal() {
-                    Steal::Success(pe) => return Ok(pe),
-                    Steal::Empty => (),
-                    Steal::Retry => (),
+            Some(q) => Ok(q),
+            None => Err(Error::NoSuchPE { id }),
+        }
+    }
+
+    fn acquire_pe_until(
+        &self,
+        id: PEId,
+        priority: i32,
+        deadline: Option<Instant>,
+    ) -> Result<Option<PE>> {
+        let since = Instant::now();
+        let q = self.queue(id)?;
+        let mut state = q.state.lock()?;
+        if state.waiting.is_empty() {
+            if let Some(pe) = state.idle.pop() {
+                return Ok(Some(Scheduler::acquired(pe, since)));
+            }
+        }
+
+        let key = if state.idle.prioritized() {
+            priority
+        } else {
+            0
+        };
+        let ticket = (Reverse(key), state.next_ticket);
+        state.next_ticket += 1;
+        state.waiting.insert(ticket);
+        loop {
+            // Only the first request in line may take a PE. Releases notify while
+            // holding the lock, so no wakeup can get lost before the wait below.
+            if state.waiting.iter().next() == Some(&ticket) {
+                if let Some(pe) = state.idle.pop() {
+                    state.waiting.remove(&ticket);
+                    if !state.waiting.is_empty() && !state.idle.is_empty() {
+                        q.available.notify_all();
+                    }
+                    self.leave_queue(state)?;
+                    return Ok(Some(Scheduler::acquired(pe, since)));
                 }
-                thread::yield_now();
-            },
-            None => return Err(Error::NoSuchPE { id }),
+            }
+            // Quarantining a PE notifies all waiters, so nobody sleeps on a type
+            // without healthy PEs.
+            if self.healthy_pes(id)? == 0 {
+                state.waiting.remove(&ticket);
+                self.leave_queue(state)?;
+                return Err(Error::AllFaulty { id });
+            }
+            state = match deadline {
+                Some(d) => {
+                    let now = Instant::now();
+                    if now >= d {
+                        trace!("Timeout while waiting for PE of type {}.", id);
+                        state.waiting.remove(&ticket);
+                        // The next request might be first in line now.
+                        q.available.notify_all();
+                        self.leave_queue(state)?;
+                        return Ok(None);
+                    }
+                    q.available.wait_timeout(state, d - now)?.0
+                }
+                None => q.available.wait(state)?,
+            };
+        }
+    }
+
+    /// Requests for several PEs at once only take PEs of types without waiters. Wake
+    /// them up if the last waiter left while PEs are idle.
+    fn leave_queue(&self, state: MutexGuard<QueueState>) -> Result<()> {
+        let wake = state.waiting.is_empty() && !state.idle.is_empty();
+        // The lock of `any_released` is taken before the queue locks elsewhere.
+        drop(state);
+        if wake {
+            {
+                let (lock, released) = &self.any_released;
+                let _guard = lock.lock()?;
+                released.notify_all();
+            }
+            self.notify_listeners()?;
         }
+        Ok(())
+    }
+
+    /// Retrieve the PE instance `slot`, blocks until it is available.
+    pub fn acquire_pe_instance(&self, slot: usize) -> Result<PE> {
+        match self.acquire_pe_instance_until(slot, None)? {
+            Some(pe) => Ok(pe),
+            None => Err(Error::NoSuchInstance { slot }),
+        }
+    }
+
+    /// Retrieve the PE instance `slot`. Gives up after `timeout` and returns `None`.
+    pub fn acquire_pe_instance_timeout(
+        &self,
+        slot: usize,
+        timeout: Duration,
+    ) -> Result<Option<PE>> {
+        self.acquire_pe_instance_until(slot, Some(Instant::now() + timeout))
+    }
+
+    /// Retrieve the PE instance `slot` if it is available right now.
+    pub fn try_acquire_pe_instance(&self, slot: usize) -> Result<Option<PE>> {
+        self.acquire_pe_instance_until(slot, Some(Instant::now()))
+    }
+
+    /// Describe all instances of the given PE type.
+    pub fn pe_instances(&self, id: PEId) -> Result<Vec<PEInstance>> {
+        ensure!(self.pes_overview.contains_key(&id), NoSuchPE { id: id });
+        Ok(self
+            .instances
+            .iter()
+            .filter(|x| x.type_id == id)
+            .cloned()
+            .collect())
+    }
+
+    fn acquire_pe_instance_until(
+        &self,
+        slot: usize,
+        deadline: Option<Instant>,
+    ) -> Result<Option<PE>> {
+        let id = match self.instances.get(slot) {
+            Some(x) => x.type_id,
+            None => return Err(Error::NoSuchInstance { slot }),
+        };
+        let since = Instant::now();
+        let q = self.queue(id)?;
+
+        let mut state = q.state.lock()?;
+        loop {
+            if let Some(pe) = state.idle.take(slot) {
+                return Ok(Some(Scheduler::acquired(pe, since)));
+            }
+            ensure!(
+                !self.faulty.lock()?.iter().any(|pe| *pe.id() == slot),
+                InstanceFaulty { slot: slot }
+            );
+            state = match deadline {
+                Some(d) => {
+                    let now = Instant::now();
+                    if now >= d {
+                        trace!("Timeout while waiting for PE instance {}.", slot);
+                        return Ok(None);
+                    }
+                    q.available.wait_timeout(state, d - now)?.0
+                }
+                None => q.available.wait(state)?,
+            };
+        }
+    }
+
+    /// Retrieve `count` PEs for every given type at once, blocks until all are available.
+    ///
+    /// No PE is held while waiting, so threads requesting overlapping sets can not
+    /// deadlock each other. The PEs are returned in the order of the request. Requests
+    /// already waiting for one of the types are served first.
+    pub fn acquire_pes(&self, pes: &[(PEId, usize)]) -> Result<Vec<PE>> {
+        match self.acquire_pes_until(pes, None)? {
+            Some(pes) => Ok(pes),
+            None => Err(Error::PEUnavailable { id: pes[0].0 }),
+        }
+    }
+
+    /// Same as `acquire_pes` but gives up after `timeout` and returns `None`.
+    pub fn acquire_pes_timeout(
+        &self,
+        pes: &[(PEId, usize)],
+        timeout: Duration,
+    ) -> Result<Option<Vec<PE>>> {
+        self.acquire_pes_until(pes, Some(Instant::now() + timeout))
+    }
+
+    fn acquire_pes_until(
+        &self,
+        pes: &[(PEId, usize)],
+        deadline: Option<Instant>,
+    ) -> Result<Option<Vec<PE>>> {
+        let mut requested: HashMap<PEId, usize> = HashMap::new();
+        for (id, count) in pes {
+            self.queue(*id)?;
+            *requested.entry(*id).or_insert(0) += count;
+        }
+
+        let since = Instant::now();
+        let (lock, released) = &self.any_released;
+        let mut guard = lock.lock()?;
+        loop {
+            // PEs might be taken out of service while waiting.
+            for (id, count) in requested.iter() {
+                let available = self.healthy_pes(*id)?;
+                ensure!(
+                    *count <= available,
+                    NotEnoughPEs {
+                        id: *id,
+                        count: *count,
+                        available: available
+                    }
+                );
+            }
+            // Releases notify while holding the lock, so none can get lost between
+            // this attempt and the wait below.
+            if let Some(taken) = self.take_all(pes)? {
+                return Ok(Some(
+                    taken
+                        .into_iter()
+                        .map(|pe| Scheduler::acquired(pe, since))
+                        .collect(),
+                ));
+            }
+            guard = match deadline {
+                Some(d) => {
+                    let now = Instant::now();
+                    if now >= d {
+                        trace!("Timeout while waiting for PEs {:?}.", pes);
+                        return Ok(None);
+                    }
+                    released.wait_timeout(guard, d - now)?.0
+                }
+                None => released.wait(guard)?,
+            };
+        }
+    }
+
+    /// Retrieve a PE of any of the given types, blocks until one is available.
+    ///
+    /// Types are tried in the given order, so earlier types are preferred if several
+    /// PEs are free. Types with waiting requests are skipped until those are served.
+    pub fn acquire_any(&self, ids: &[PEId]) -> Result<PE> {
+        match self.acquire_any_until(ids, None)? {
+            Some(pe) => Ok(pe),
+            None => Err(Error::PEUnavailable { id: ids[0] }),
+        }
+    }
+
+    /// Same as `acquire_any` but gives up after `timeout` and returns `None`.
+    pub fn acquire_any_timeout(&self, ids: &[PEId], timeout: Duration) -> Result<Option<PE>> {
+        self.acquire_any_until(ids, Some(Instant::now() + timeout))
+    }
+
+    /// Retrieve a PE of any of the given types if one is available right now.
+    pub fn try_acquire_any(&self, ids: &[PEId]) -> Result<Option<PE>> {
+        self.acquire_any_until(ids, Some(Instant::now()))
+    }
+
+    fn acquire_any_until(&self, ids: &[PEId], deadline: Option<Instant>) -> Result<Option<PE>> {
+        let mut queues = Vec::new();
+        for id in ids {
+            queues.push(self.queue(*id)?);
+        }
+        ensure!(!queues.is_empty(), NoPETypes {});
+
+        let since = Instant::now();
+        let (lock, released) = &self.any_released;
+        let mut guard = lock.lock()?;
+        loop {
+            for q in queues.iter() {
+                let mut state = q.state.lock()?;
+                // Do not overtake requests waiting for this type.
+                if !state.waiting.is_empty() {
+                    continue;
+                }
+                if let Some(pe) = state.idle.pop() {
+                    return Ok(Some(Scheduler::acquired(pe, since)));
+                }
+            }
+            let mut healthy = 0;
+            for id in ids {
+                healthy += self.healthy_pes(*id)?;
+            }
+            ensure!(healthy > 0, AllFaulty { id: ids[0] });
+            guard = match deadline {
+                Some(d) => {
+                    let now = Instant::now();
+                    if now >= d {
+                        trace!("Timeout while waiting for any PE of {:?}.", ids);
+                        return Ok(None);
+                    }
+                    released.wait_timeout(guard, d - now)?.0
+                }
+                None => released.wait(guard)?,
+            };
+        }
+    }
+
+    /// Account the time a request waited for `pe`.
+    fn acquired(pe: PE, since: Instant) -> PE {
+        pe.update_stats(|s| s.wait.record(since.elapsed()));
+        pe
+    }
+
+    /// Take all requested PEs or none of them. Types with waiting requests count as
+    /// unavailable, so those are served first.
+    fn take_all(&self, pes: &[(PEId, usize)]) -> Result<Option<Vec<PE>>> {
+        let mut taken = Vec::new();
+        for (id, count) in pes {
+            let q = self.queue(*id)?;
+            let mut state = q.state.lock()?;
+            for _ in 0..*count {
+                let pe = if state.waiting.is_empty() {
+                    state.idle.pop()
+                } else {
+                    None
+                };
+                match pe {
+                    Some(pe) => taken.push(pe),
+                    None => {
+                        drop(state);
+                        trace!("PE type {} unavailable, returning {} PEs.", id, taken.len());
+                        self.put_back(taken)?;
+                        return Ok(None);
+                    }
+                }
+            }
+        }
+        Ok(Some(taken))
+    }
+
+    /// Return PEs that have been taken only temporarily. Single PE waiters might have
+    /// missed them in the meantime and are woken up.
+    fn put_back(&self, pes: Vec<PE>) -> Result<()> {
+        for pe in pes.into_iter() {
+            let q = self.queue(*pe.type_id())?;
+            q.state.lock()?.idle.push(pe);
+            q.available.notify_all();
+        }
+        self.notify_listeners()
+    }
+
+    /// Wake up all threads waiting for a PE of type `id` and all threads waiting for
+    /// one of several types. Waiters for a specific instance share the condition
+    /// variable, so a single wakeup could be lost.
+    fn notify_release(&self, id: PEId) -> Result<()> {
+        if let Some(q) = self.pes.get(&id) {
+            let _guard = q.state.lock()?;
+            q.available.notify_all();
+        }
+        {
+            let (lock, released) = &self.any_released;
+            let _guard = lock.lock()?;
+            released.notify_all();
+        }
+        self.notify_listeners()
+    }
+
+    /// Notify `signal` whenever a PE might have become free. The scheduler only keeps
+    /// a weak reference, dropping the signal unregisters it.
+    pub fn add_listener(&self, signal: &Arc<ReleaseSignal>) -> Result<()> {
+        self.listeners.lock()?.push(Arc::downgrade(signal));
+        Ok(())
+    }
+
+    fn notify_listeners(&self) -> Result<()> {
+        let mut listeners = self.listeners.lock()?;
+        listeners.retain(|l| l.strong_count() > 0);
+        for l in listeners.iter().filter_map(|l| l.upgrade()) {
+            l.notify()?;
+        }
+        Ok(())
     }
 
     pub fn release_pe(&self, pe: PE) -> Result<()> {
         ensure!(!pe.active(), PEStillActive { pe: pe });
 
-        match self.pes.get(&pe.type_id()) {
-            Some(l) => l.val().push(pe),
-            None => return Err(Error::NoSuchPE { id: *pe.type_id() }),
+        let id = *pe.type_id();
+        self.queue(id)?.state.lock()?.idle.push(pe);
+        self.notify_release(id)
+    }
+
+    /// Try to bring a PE that did not finish back into a usable state.
+    ///
+    /// Returns `true` if the PE is idle again, either because it finished in the meantime
+    /// or because the platform reset it.
+    pub fn recover_pe(&self, pe: &mut PE) -> Result<bool> {
+        if pe.try_complete_aborted().context(PEError)? {
+            return Ok(true);
         }
-        Ok(())
+        if self.platform.reset_pe(pe).context(PlatformError)? {
+            trace!("PE {} has been reset.", pe.id());
+            pe.deactivate().context(PEError)?;
+            return Ok(true);
+        }
+        Ok(false)
     }
 
-    pub fn reset_interrupts(&self) -> Result<()> {
-        for v in self.pes.iter() {
-            let mut remove_pes = Vec::new();
-            let mut maybe_pe = v.val().steal();
+    /// Time a dropped `Job` waits for its PE to finish.
+    pub fn drop_timeout(&self) -> Duration {
+        self.drop_timeout
+    }
+
+    /// Take a PE out of service, e.g. because it did not finish and could not be reset.
+    pub fn quarantine_pe(&self, pe: PE) -> Result<()> {
+        warn!("Marking PE {} (type {}) as faulty.", pe.id(), pe.type_id());
+        pe.update_stats(|s| s.faults += 1);
+        let id = *pe.type_id();
+        self.faulty.lock()?.push(pe);
+        // Threads waiting for this instance have to give up.
+        self.notify_release(id)
+    }
 
-            while let Steal::Success(pe) = maybe_pe {
-                pe.enable_interrupt().context(PEError)?;
-                if pe.interrupt_set().context(PEError)? {
-                    pe.reset_interrupt(true).context(PEError)?;
+    /// Health of all PE instances indexed by `PE.id`.
+    pub fn pe_health(&self) -> Result<Vec<PEHealth>> {
+        let mut health = vec![PEHealth::Healthy; self.instances.len()];
+        for pe in self.faulty.lock()?.iter() {
+            health[*pe.id()] = PEHealth::Faulty;
+        }
+        Ok(health)
+    }
+
+    /// IDs of the PE instances that are currently marked as faulty.
+    pub fn faulty_pes(&self) -> Result<Vec<usize>> {
+        let mut v: Vec<usize> = self.faulty.lock()?.iter().map(|pe| *pe.id()).collect();
+        v.sort();
+        Ok(v)
+    }
+
+    /// Put a faulty PE back into service.
+    ///
+    /// Fails if the PE is still running and the platform can not reset it. Device memory
+    /// left behind by the aborted job is freed once the PE is idle.
+    pub fn reset_faulty_pe(&self, slot: usize) -> Result<()> {
+        let mut pe = {
+            let mut faulty = self.faulty.lock()?;
+            match faulty.iter().position(|pe| *pe.id() == slot) {
+                Some(i) => faulty.remove(i),
+                None => return Err(Error::PENotFaulty { slot: slot }),
+            }
+        };
+        match self.recover_pe(&mut pe) {
+            Ok(true) => {
+                info!("PE {} is back in service.", slot);
+                if let Err(e) = pe.free_retained() {
+                    warn!("Could not free memory of PE {}: {}", slot, e);
                 }
-                remove_pes.push(pe);
-                maybe_pe = v.val().steal();
+                self.release_pe(pe)
             }
+            Ok(false) => {
+                self.faulty.lock()?.push(pe);
+                Err(Error::PEResetFailed { slot: slot })
+            }
+            Err(e) => {
+                self.faulty.lock()?.push(pe);
+                Err(e)
+            }
+        }
+    }
 
-            for pe in remove_pes.into_iter() {
-                v.val().push(pe);
+    /// Utilization of every PE and of every PE type.
+    pub fn stats(&self) -> Result<SchedulerStats> {
+        let mut stats = SchedulerStats::default();
+        for (instance, s) in self.instances.iter().zip(self.stats.iter()) {
+            let s = *s.lock()?;
+            stats
+                .types
+                .entry(instance.type_id)
+                .or_insert_with(PEStats::default)
+                .merge(&s);
+            stats.pes.push(s);
+        }
+        Ok(stats)
+    }
+
+    /// Clear the statistics of all PEs.
+    pub fn reset_stats(&self) -> Result<()> {
+        for s in self.stats.iter() {
+            *s.lock()? = PEStats::default();
+        }
+        Ok(())
+    }
+
+    pub fn reset_interrupts(&self) -> Result<()> {
+        for (id, q) in self.pes.iter() {
+            {
+                let state = q.state.lock()?;
+                for pe in state.idle.idle() {
+                    pe.enable_interrupt().context(PEError)?;
+                    if pe.interrupt_set().context(PEError)? {
+                        pe.reset_interrupt(true).context(PEError)?;
+                    }
+                }
             }
+            self.notify_release(*id)?;
         }
 
         Ok(())
@@ -223,6 +1144,27 @@ impl Scheduler {
         }
     }
 
+    /// Number of requests blocked waiting for a PE of the given type.
+    pub fn waiting(&self, id: PEId) -> Result<usize> {
+        Ok(self.queue(id)?.state.lock()?.waiting.len())
+    }
+
+    /// Number of PEs of the given type that have not been taken out of service.
+    pub fn healthy_pes(&self, id: PEId) -> Result<usize> {
+        let faulty = self
+            .faulty
+            .lock()?
+            .iter()
+            .filter(|pe| *pe.type_id() == id)
+            .count();
+        Ok(self.num_pes(id) - faulty)
+    }
+
+    /// Number of PEs of every type in the bitstream.
+    pub fn pe_types(&self) -> HashMap<PEId, usize> {
+        self.pes_overview.clone()
+    }
+
     pub fn get_pe_id(&self, name: &str) -> Result<PEId> {
         for (id, pe_name) in &self.pes_name {
             if name == pe_name {
@@ -235,3 +1177,451 @@ impl Scheduler {
         })
     }
 }
+
+#[cfg(test)]
+mod scheduler_tests {
+    use crate::device::{Device, PEParameter};
+    use crate::pe::PEStats;
+    use crate::scheduler::AcquireOptions;
+    use crate::sim::testing::{init, Result};
+    use crate::sim::{PEContext, SimulatedDevice, SimulatedPE, SimulatedTLKM};
+    use crate::tlkm::{tlkm_access, TLKM};
+    use std::collections::HashMap;
+    use std::sync::atomic::{AtomicBool, Ordering};
+    use std::sync::{Arc, Mutex};
+    use std::thread;
+    use std::time::Duration;
+
+    #[test]
+    fn blocking_acquire() -> Result<()> {
+        let tlkm = init()?;
+        let mut dev = tlkm.device_alloc(0, &HashMap::new())?;
+        dev.change_access(tlkm_access::TlkmAccessExclusive)?;
+        let mut job = dev.acquire_pe(11)?;
+        assert!(dev.try_acquire_pe(11)?.is_none());
+        assert!(dev
+            .acquire_pe_timeout(11, Duration::from_millis(20))?
+            .is_none());
+        let dev = Arc::new(dev);
+        let waiter = {
+            let dev = dev.clone();
+            thread::spawn(move || dev.acquire_pe_timeout(11, Duration::from_secs(10)))
+        };
+        thread::sleep(Duration::from_millis(20));
+        job.release(true, false)?;
+        let mut job = waiter.join().unwrap()?.expect("PE was not handed over");
+        job.start(vec![PEParameter::Single64(1), PEParameter::Single64(2)])?;
+        assert_eq!(job.release(true, true)?.0, 3);
+        assert!(dev.try_acquire_pe(11)?.is_some());
+        Ok(())
+    }
+
+    #[test]
+    fn all_faulty() -> Result<()> {
+        let done = Arc::new(AtomicBool::new(false));
+        let d = done.clone();
+        let stuck = SimulatedPE::new(12, "sim:stuck", move |_: &mut PEContext| {
+            while !d.load(Ordering::SeqCst) {
+                thread::sleep(Duration::from_millis(1));
+            }
+            0
+        });
+        let other = SimulatedPE::new(13, "sim:other", |_: &mut PEContext| 0);
+        let sim = SimulatedTLKM::new(vec![SimulatedDevice::new("pcie").pe(stuck, 1).pe(other, 1)]);
+        let tlkm = TLKM::with_driver(Arc::new(sim))?;
+        let mut dev = tlkm.device_alloc(0, &HashMap::new())?;
+        dev.change_access(tlkm_access::TlkmAccessExclusive)?;
+        let dev = Arc::new(dev);
+
+        // Waiters give up once the only instance is taken out of service.
+        let mut job = dev.acquire_pe(12)?;
+        job.start(vec![])?;
+        let d = dev.clone();
+        let t = thread::spawn(move || d.acquire_pe(12).map(|_| ()).is_err());
+        assert!(job
+            .release_timeout(true, true, Duration::from_millis(10))
+            .is_err());
+        assert!(t.join().unwrap());
+
+        assert!(dev.acquire_pe(12).is_err());
+        assert!(dev.acquire_pes(&[(12, 1), (13, 1)]).is_err());
+        assert!(dev.acquire_any(&[12]).is_err());
+        assert_eq!(dev.acquire_any(&[12, 13])?.type_id(), Some(13));
+        assert!(dev.submit(12, vec![]).is_err());
+
+        done.store(true, Ordering::SeqCst);
+        while dev.reset_faulty_pe(0).is_err() {
+            thread::sleep(Duration::from_millis(1));
+        }
+        let mut job = dev.acquire_pe(12)?;
+        job.start(vec![])?;
+        job.release(true, true)?;
+        Ok(())
+    }
+
+    #[test]
+    fn acquire_instance() -> Result<()> {
+        let slow = SimulatedPE::new(12, "sim:slow", |pe: &mut PEContext| {
+            thread::sleep(Duration::from_millis(pe.arg(0)));
+            0
+        });
+        let local =
+            SimulatedPE::new(13, "sim:local", |_: &mut PEContext| 0).with_local_memory(4096);
+        let sim = SimulatedTLKM::new(vec![SimulatedDevice::new("pcie").pe(slow, 2).pe(local, 1)]);
+        let tlkm = TLKM::with_driver(Arc::new(sim))?;
+        let mut dev = tlkm.device_alloc(0, &HashMap::new())?;
+        dev.change_access(tlkm_access::TlkmAccessExclusive)?;
+
+        let slow = dev.pe_instances(12)?;
+        assert_eq!(
+            slow.iter().map(|x| *x.slot()).collect::<Vec<_>>(),
+            vec![0, 1]
+        );
+        assert!(slow.iter().all(|x| x.local_memory().is_none()));
+        assert_ne!(slow[0].interrupt(), slow[1].interrupt());
+        let local = dev.pe_instances(13)?;
+        assert_eq!(local.len(), 1);
+        assert_eq!(*local[0].slot(), 2);
+        assert!(local[0].local_memory().is_some());
+        assert!(dev.pe_instances(42).is_err());
+        assert!(dev.acquire_pe_instance(3).is_err());
+
+        let mut job = dev.acquire_pe_instance(1)?;
+        assert_eq!(job.slot(), Some(1));
+        assert!(dev.try_acquire_pe_instance(1)?.is_none());
+        assert!(dev
+            .acquire_pe_instance_timeout(1, Duration::from_millis(20))?
+            .is_none());
+        let other = dev.try_acquire_pe(12)?.unwrap();
+        assert_eq!(other.slot(), Some(0));
+        drop(other);
+
+        job.start(vec![PEParameter::Single64(100)])?;
+        let t = thread::spawn(move || job.release(true, true).is_ok());
+        let job = dev
+            .acquire_pe_instance_timeout(1, Duration::from_secs(5))?
+            .unwrap();
+        assert_eq!(job.slot(), Some(1));
+        assert!(t.join().unwrap());
+        drop(job);
+
+        // Instances that are out of service can not be waited for.
+        let mut job = dev.acquire_pe_instance(1)?;
+        job.start(vec![PEParameter::Single64(300)])?;
+        assert!(job.abort()?.is_empty());
+        assert!(dev.acquire_pe_instance(1).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn gang_acquire() -> Result<()> {
+        let slow = SimulatedPE::new(12, "sim:slow", |pe: &mut PEContext| {
+            thread::sleep(Duration::from_millis(pe.arg(0)));
+            0
+        });
+        let other = SimulatedPE::new(13, "sim:other", |_: &mut PEContext| 0);
+        let sim = SimulatedTLKM::new(vec![SimulatedDevice::new("pcie").pe(slow, 2).pe(other, 1)]);
+        let tlkm = TLKM::with_driver(Arc::new(sim))?;
+        let mut dev = tlkm.device_alloc(0, &HashMap::new())?;
+        dev.change_access(tlkm_access::TlkmAccessExclusive)?;
+        let dev = Arc::new(dev);
+        let gang = [(12, 2), (13, 1)];
+
+        assert!(dev.acquire_pes(&[(12, 3)]).is_err());
+        assert!(dev.acquire_pes(&[(42, 1)]).is_err());
+
+        // Nothing is held while the request can not be satisfied.
+        let mut held = dev.acquire_pe(12)?;
+        assert!(dev
+            .acquire_pes_timeout(&gang, Duration::from_millis(20))?
+            .is_none());
+        assert!(dev.try_acquire_pe(13)?.is_some());
+
+        held.start(vec![PEParameter::Single64(50)])?;
+        let d = dev.clone();
+        let t = thread::spawn(move || d.acquire_pes(&gang).map(|jobs| jobs.len()).ok());
+        held.release(true, true)?;
+        assert_eq!(t.join().unwrap(), Some(3));
+
+        let jobs = dev.acquire_pes(&[(13, 1), (12, 1)])?;
+        assert_eq!(jobs[0].slot(), Some(2));
+        assert_ne!(jobs[1].slot(), Some(2));
+        drop(jobs);
+
+        // Overlapping requests in opposite order do not deadlock.
+        let threads: Vec<_> = vec![vec![(12, 1), (13, 1)], vec![(13, 1), (12, 2)]]
+            .into_iter()
+            .map(|request| {
+                let d = dev.clone();
+                thread::spawn(move || {
+                    (0..50).all(|_| {
+                        d.acquire_pes_timeout(&request, Duration::from_secs(10))
+                            .map(|x| x.is_some())
+                            .unwrap_or(false)
+                    })
+                })
+            })
+            .collect();
+        for t in threads {
+            assert!(t.join().unwrap());
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn acquire_any() -> Result<()> {
+        let fast = SimulatedPE::new(12, "sim:fast", |pe: &mut PEContext| {
+            thread::sleep(Duration::from_millis(pe.arg(0)));
+            1
+        });
+        let small = SimulatedPE::new(13, "sim:small", |_: &mut PEContext| 2);
+        let sim = SimulatedTLKM::new(vec![SimulatedDevice::new("pcie").pe(fast, 1).pe(small, 2)]);
+        let tlkm = TLKM::with_driver(Arc::new(sim))?;
+        let mut dev = tlkm.device_alloc(0, &HashMap::new())?;
+        dev.change_access(tlkm_access::TlkmAccessExclusive)?;
+        let dev = Arc::new(dev);
+
+        assert!(dev.acquire_any(&[]).is_err());
+        assert!(dev.acquire_any(&[12, 42]).is_err());
+
+        let mut a = dev.acquire_any(&[12, 13])?;
+        assert_eq!(a.type_id(), Some(12));
+        let b = dev.acquire_any(&[12, 13])?;
+        assert_eq!(b.type_id(), Some(13));
+        let c = dev.try_acquire_any(&[13, 12])?.unwrap();
+        assert_eq!(c.type_id(), Some(13));
+        assert!(dev.try_acquire_any(&[12, 13])?.is_none());
+        assert!(dev
+            .acquire_any_timeout(&[12, 13], Duration::from_millis(20))?
+            .is_none());
+
+        a.start(vec![PEParameter::Single64(50)])?;
+        let d = dev.clone();
+        let t = thread::spawn(move || d.acquire_any(&[13, 12]).ok().and_then(|j| j.type_id()));
+        assert_eq!(a.release(true, true)?.0, 1);
+        assert_eq!(t.join().unwrap(), Some(12));
+        drop(b);
+        drop(c);
+        Ok(())
+    }
+
+    #[test]
+    fn waiters_first() -> Result<()> {
+        let sim = SimulatedTLKM::new(vec![SimulatedDevice::new("pcie")
+            .pe(SimulatedPE::new(12, "sim:a", |_: &mut PEContext| 0), 1)
+            .pe(SimulatedPE::new(13, "sim:b", |_: &mut PEContext| 0), 1)]);
+        let tlkm = TLKM::with_driver(Arc::new(sim))?;
+        let mut dev = tlkm.device_alloc(0, &HashMap::new())?;
+        dev.change_access(tlkm_access::TlkmAccessExclusive)?;
+        let dev = Arc::new(dev);
+
+        // Requests for several PEs do not take a released PE from a queued request.
+        for _ in 0..20 {
+            let held = dev.acquire_pe(12)?;
+            let d = dev.clone();
+            let t = thread::spawn(move || d.acquire_pe_timeout(12, Duration::from_secs(10)));
+            while dev.waiting(12)? == 0 {
+                thread::yield_now();
+            }
+            drop(held);
+            assert!(dev.try_acquire_any(&[12])?.is_none());
+            assert!(dev
+                .acquire_pes_timeout(&[(13, 1), (12, 1)], Duration::from_millis(0))?
+                .is_none());
+            assert!(t.join().unwrap()?.is_some());
+        }
+        assert_eq!(dev.waiting(12)?, 0);
+        assert!(dev.try_acquire_any(&[12])?.is_some());
+        Ok(())
+    }
+
+    fn policy_device(policy: &str) -> Result<Device> {
+        let pe = SimulatedPE::new(12, "sim:slow", |pe: &mut PEContext| {
+            thread::sleep(Duration::from_millis(pe.arg(0)));
+            0
+        });
+        let sim = SimulatedTLKM::new(vec![SimulatedDevice::new("pcie").pe(pe, 3)]);
+        let mut tlkm = TLKM::with_driver(Arc::new(sim))?;
+        tlkm.set_config("scheduler.policy", policy)?;
+        let mut dev = tlkm.device_alloc(0, &HashMap::new())?;
+        dev.change_access(tlkm_access::TlkmAccessExclusive)?;
+        Ok(dev)
+    }
+
+    fn slots(dev: &Device) -> Result<Vec<Option<usize>>> {
+        let a = dev.acquire_pe(12)?;
+        let b = dev.acquire_pe(12)?;
+        drop(b);
+        drop(a);
+        let mut v = Vec::new();
+        for _ in 0..4 {
+            v.push(dev.acquire_pe(12)?.slot());
+        }
+        Ok(v)
+    }
+
+    #[test]
+    fn scheduling_policies() -> Result<()> {
+        assert_eq!(
+            slots(&policy_device("fifo")?)?,
+            vec![Some(2), Some(1), Some(0), Some(2)]
+        );
+        assert_eq!(
+            slots(&policy_device("lifo")?)?,
+            vec![Some(2), Some(2), Some(2), Some(2)]
+        );
+        assert_eq!(
+            slots(&policy_device("round-robin")?)?,
+            vec![Some(2), Some(0), Some(1), Some(2)]
+        );
+        assert_eq!(
+            slots(&policy_device("lru")?)?,
+            vec![Some(2), Some(0), Some(1), Some(2)]
+        );
+        assert!(policy_device("random").is_err());
+
+        // The policy can be overridden for a single device.
+        let pe = SimulatedPE::new(12, "sim:fast", |_: &mut PEContext| 0);
+        let sim = SimulatedTLKM::new(vec![
+            SimulatedDevice::new("pcie").pe(pe.clone(), 3),
+            SimulatedDevice::new("pcie").pe(pe, 3),
+        ]);
+        let mut tlkm = TLKM::with_driver(Arc::new(sim))?;
+        tlkm.set_config("scheduler.1.policy", "lifo")?;
+        let mut fifo = tlkm.device_alloc(0, &HashMap::new())?;
+        fifo.change_access(tlkm_access::TlkmAccessExclusive)?;
+        let mut lifo = tlkm.device_alloc(1, &HashMap::new())?;
+        lifo.change_access(tlkm_access::TlkmAccessExclusive)?;
+        assert_eq!(slots(&fifo)?, vec![Some(2), Some(1), Some(0), Some(2)]);
+        assert_eq!(slots(&lifo)?, vec![Some(2), Some(2), Some(2), Some(2)]);
+        Ok(())
+    }
+
+    #[test]
+    fn priority_policy() -> Result<()> {
+        let dev = Arc::new(policy_device("priority")?);
+        let order = Arc::new(Mutex::new(Vec::new()));
+        let busy: Vec<_> = (0..3)
+            .map(|_| dev.acquire_pe(12))
+            .collect::<std::result::Result<_, _>>()?;
+
+        // Queue all waiters before the first PE is released.
+        let waiters: Vec<_> = vec![-1, 0, 5]
+            .into_iter()
+            .enumerate()
+            .map(|(i, priority)| {
+                let d = dev.clone();
+                let o = order.clone();
+                let t = thread::spawn(move || {
+                    let opts = AcquireOptions {
+                        priority: priority,
+                        timeout: Some(Duration::from_secs(10)),
+                    };
+                    let job = d.acquire_pe_with(12, &opts).unwrap().unwrap();
+                    o.lock().unwrap().push(priority);
+                    drop(job);
+                });
+                while dev.waiting(12).unwrap() <= i {
+                    thread::yield_now();
+                }
+                t
+            })
+            .collect();
+
+        // Hand out a single PE, the waiters get it one after another.
+        let mut busy = busy;
+        drop(busy.pop());
+        for t in waiters {
+            t.join().unwrap();
+        }
+        assert_eq!(*order.lock().unwrap(), vec![5, 0, -1]);
+        drop(busy);
+        assert!(dev
+            .acquire_pe_with(
+                12,
+                &AcquireOptions {
+                    priority: 0,
+                    timeout: Some(Duration::from_millis(10)),
+                }
+            )?
+            .is_some());
+        Ok(())
+    }
+
+    #[test]
+    fn utilization_stats() -> Result<()> {
+        let sleep = SimulatedPE::new(12, "sim:sleep", |pe: &mut PEContext| {
+            thread::sleep(Duration::from_millis(pe.arg(0)));
+            0
+        });
+        let open = Arc::new(AtomicBool::new(false));
+        let gate = open.clone();
+        let gated = SimulatedPE::new(13, "sim:gated", move |_: &mut PEContext| {
+            while !gate.load(Ordering::SeqCst) {
+                thread::sleep(Duration::from_millis(1));
+            }
+            0
+        });
+        let sim = SimulatedTLKM::new(vec![SimulatedDevice::new("pcie").pe(sleep, 2).pe(gated, 1)]);
+        let tlkm = TLKM::with_driver(Arc::new(sim))?;
+        let mut dev = tlkm.device_alloc(0, &HashMap::new())?;
+        dev.change_access(tlkm_access::TlkmAccessExclusive)?;
+        let dev = Arc::new(dev);
+
+        for _ in 0..3 {
+            let mut job = dev.acquire_pe_instance(0)?;
+            job.start(vec![PEParameter::Single64(10)])?;
+            job.release(true, true)?;
+        }
+
+        // Busy time ends with the interrupt, not when the job is released.
+        let mut job = dev.acquire_pe_instance(1)?;
+        job.start(vec![PEParameter::Single64(0)])?;
+        thread::sleep(Duration::from_millis(100));
+        job.release(true, true)?;
+
+        // A request waiting for a PE is accounted once it got one.
+        let held = dev.acquire_pe(12)?;
+        let other = dev.acquire_pe(12)?;
+        let d = dev.clone();
+        let t = thread::spawn(move || d.acquire_pe(12).map(|_| ()).is_ok());
+        while dev.waiting(12)? == 0 {
+            thread::yield_now();
+        }
+        thread::sleep(Duration::from_millis(10));
+        drop(held);
+        assert!(t.join().unwrap());
+        drop(other);
+
+        // Runs finishing only after a timeout are not counted as jobs.
+        let mut job = dev.acquire_pe(13)?;
+        job.start(vec![])?;
+        assert!(job
+            .release_timeout(true, true, Duration::from_millis(1))
+            .is_err());
+        open.store(true, Ordering::SeqCst);
+        while dev.reset_faulty_pe(2).is_err() {
+            thread::yield_now();
+        }
+
+        let stats = dev.stats()?;
+        assert_eq!(stats.pes[0].jobs, 3);
+        assert!(stats.pes[0].busy >= Duration::from_millis(30));
+        assert_eq!(stats.pes[1].jobs, 1);
+        assert!(stats.pes[1].busy < Duration::from_millis(100));
+        assert_eq!(stats.pes[2].jobs, 0);
+        assert_eq!(stats.pes[2].timeouts, 1);
+        assert_eq!(stats.pes[2].faults, 1);
+        let all = stats.types[&12];
+        assert_eq!(all.jobs, 4);
+        assert_eq!(all.wait.count(), 7);
+        assert!(all.wait.percentile(100.0) >= Duration::from_millis(10));
+        assert!(all.wait.percentile(50.0) < Duration::from_millis(1));
+        assert_eq!(all.summary().jobs, 4);
+        assert_eq!(stats.types[&13].timeouts, 1);
+
+        dev.reset_stats()?;
+        assert_eq!(dev.stats()?.types[&12], PEStats::default());
+        Ok(())
+    }
+}
@@ -0,0 +1,1038 @@
+/*
+ * Copyright (c) 2014-2020 Embedded Systems and Applications, TU Darmstadt.
+ *
+ * This file is part of TaPaSCo
+ * (see https://github.com/esa-tu-darmstadt/tapasco).
+ *
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU Lesser General Public License as published by
+ * the Free Software Foundation, either version 3 of the License, or
+ * (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU Lesser General Public License for more details.
+ *
+ * You should have received a copy of the GNU Lesser General Public License
+ * along with this program. If not, see <http://www.gnu.org/licenses/>.
+ */
+
+//! In-process simulation of TLKM and a TaPaSCo device.
+//!
+//! The simulation implements the [`Driver`] and [`DeviceDriver`] traits and
+//! can be passed to [`TLKM::with_driver`]. The rest of the runtime (`Device`,
+//! `Scheduler`, `Job`, `UserSpaceDMA`, ...) runs unchanged on top of it:
+//!
+//! * The status core is generated from the PEs given to the simulated device.
+//! * The architecture and platform regions are RAM backed and shared between
+//!   the runtime and the simulation.
+//! * A background thread watches the PE control registers and executes the
+//!   [`PEBehaviour`] of a PE once it is started. Completion is signaled through
+//!   the eventfd registered for the PE interrupt.
+//! * A BlueDMA compatible engine copies between the DMA bounce buffers and a
+//!   sparse, RAM backed off-chip memory.
+//!
+//! [`Driver`]: ../tlkm/trait.Driver.html
+//! [`DeviceDriver`]: ../tlkm/trait.DeviceDriver.html
+//! [`TLKM::with_driver`]: ../tlkm/struct.TLKM.html#method.with_driver
+//! [`PEBehaviour`]: trait.PEBehaviour.html
+
+use crate::allocator::{Allocator, GenericAllocator};
+use crate::device::status;
+use crate::device::{DeviceAddress, DeviceSize};
+use crate::pe::PEId;
+use crate::tlkm::{
+    tlkm_copy_cmd_from, tlkm_copy_cmd_to, tlkm_dma_buffer_allocate, tlkm_dma_buffer_op,
+    tlkm_ioctl_device_cmd, tlkm_ioctl_enum_devices_cmd, tlkm_ioctl_version_cmd, tlkm_mm_cmd,
+    tlkm_register_interrupt,
+};
+use crate::tlkm::{DeviceDriver, DeviceId, Driver, TLKM_DEVNAME_SZ, TLKM_DEVS_SZ};
+use memmap::{Mmap, MmapMut, MmapOptions};
+use nix::errno::Errno;
+use nix::sys::memfd::{memfd_create, MemFdCreateFlag};
+use nix::unistd::{close, dup, write};
+use prost::Message;
+use std::collections::HashMap;
+use std::ffi::CString;
+use std::fs::File;
+use std::os::unix::io::{FromRawFd, RawFd};
+use std::path::Path;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::{Arc, Condvar, Mutex, Weak};
+use std::thread;
+use std::time::Duration;
+use volatile::Volatile;
+
+const STATUS_SIZE: usize = 8192;
+const PE_SIZE: u64 = 0x1000;
+const DMA_OFFSET: u64 = 0x1000;
+const DMA_SIZE: u64 = 0x1000;
+const MAX_DMA_ENGINES: usize = 2;
+const PE_INTERRUPT_BASE: u64 = 4;
+const DMA_BUFFER_BASE: u64 = 0x8000_0000_0000;
+const DMA_BUFFER_STRIDE: u64 = 0x1_0000_0000;
+const MEMORY_PAGE_SIZE: u64 = 64 * 1024;
+const DEFAULT_MEMORY_SIZE: DeviceSize = 4 * 1024 * 1024 * 1024;
+
+/// Behaviour model of a simulated PE.
+///
+/// `run` is called in a separate thread every time the PE is started. The value
+/// returned is written to the return value register before the interrupt is raised.
+/// Closures of the form `Fn(&mut PEContext) -> u64` implement this trait.
+pub trait PEBehaviour: Send + Sync {
+    fn run(&self, pe: &mut PEContext) -> u64;
+}
+
+impl<F> PEBehaviour for F
+where
+    F: Fn(&mut PEContext) -> u64 + Send + Sync,
+{
+    fn run(&self, pe: &mut PEContext) -> u64 {
+        self(pe)
+    }
+}
+
+/// Description of a simulated PE.
+#[derive(Clone)]
+pub struct SimulatedPE {
+    id: PEId,
+    name: String,
+    local_memory: Option<DeviceSize>,
+    behaviour: Arc<dyn PEBehaviour>,
+}
+
+impl std::fmt::Debug for SimulatedPE {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("SimulatedPE")
+            .field("id", &self.id)
+            .field("name", &self.name)
+            .field("local_memory", &self.local_memory)
+            .finish()
+    }
+}
+
+impl SimulatedPE {
+    pub fn new<B: PEBehaviour + 'static>(id: PEId, name: &str, behaviour: B) -> SimulatedPE {
+        SimulatedPE {
+            id: id,
+            name: name.to_string(),
+            local_memory: None,
+            behaviour: Arc::new(behaviour),
+        }
+    }
+
+    /// Attach a PE local memory of the given size.
+    pub fn with_local_memory(mut self, size: DeviceSize) -> SimulatedPE {
+        self.local_memory = Some(size);
+        self
+    }
+}
+
+/// Description of a simulated device as returned by the device enumeration.
+///
+/// The name selects the platform the runtime assumes, e.g. `pcie` uses the user space DMA
+/// with the simulated BlueDMA engine while `zynq` uses the driver allocation and copy IOCTLs.
+#[derive(Debug, Clone)]
+pub struct SimulatedDevice {
+    name: String,
+    vendor: u32,
+    product: u32,
+    memory_size: DeviceSize,
+    memories: Vec<status::Memory>,
+    dma_engines: usize,
+    pes: Vec<SimulatedPE>,
+}
+
+impl SimulatedDevice {
+    pub fn new(name: &str) -> SimulatedDevice {
+        SimulatedDevice {
+            name: name.to_string(),
+            vendor: 0x10ee,
+            product: 0x7038,
+            memory_size: DEFAULT_MEMORY_SIZE,
+            memories: Vec::new(),
+            dma_engines: 1,
+            pes: Vec::new(),
+        }
+    }
+
+    /// Add `count` instances of the given PE.
+    pub fn pe(mut self, pe: SimulatedPE, count: usize) -> SimulatedDevice {
+        for _ in 0..count {
+            self.pes.push(pe.clone());
+        }
+        self
+    }
+
+    /// Size of the off-chip memory used for driver based allocations.
+    pub fn memory_size(mut self, size: DeviceSize) -> SimulatedDevice {
+        self.memory_size = size;
+        self
+    }
+
+    /// Number of simulated DMA engines, at most two as the interrupts below the PE
+    /// interrupts are reserved for them. The engines are named
+    /// `PLATFORM_COMPONENT_DMA0`, `PLATFORM_COMPONENT_DMA1` and share the off-chip memory.
+    pub fn dma_engines(mut self, count: usize) -> SimulatedDevice {
+        self.dma_engines = std::cmp::max(1, std::cmp::min(count, MAX_DMA_ENGINES));
+        self
+    }
+
+    /// Announce an off-chip memory in the status core. All memories are backed by the
+    /// same sparse memory and use the simulated DMA engine. Without any announced memory
+    /// the runtime falls back to its static default.
+    pub fn memory(mut self, name: &str, base: DeviceAddress, size: DeviceSize) -> SimulatedDevice {
+        self.memories.push(status::Memory {
+            name: name.to_string(),
+            base: base,
+            size: size,
+            alignment: 0,
+            dma: "".to_string(),
+        });
+        self
+    }
+}
+
+/// Simulated replacement of the TLKM main chardev.
+///
+/// Pass to [`TLKM::with_driver`] to use it instead of `/dev/tlkm`.
+///
+/// [`TLKM::with_driver`]: ../tlkm/struct.TLKM.html#method.with_driver
+#[derive(Debug)]
+pub struct SimulatedTLKM {
+    devices: Vec<SimulatedDevice>,
+    opened: Mutex<HashMap<DeviceId, Weak<SimulatedDeviceDriver>>>,
+}
+
+impl SimulatedTLKM {
+    pub fn new(devices: Vec<SimulatedDevice>) -> SimulatedTLKM {
+        SimulatedTLKM {
+            devices: devices,
+            opened: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Maximum number of DMA commands pending at the same time on device `id`.
+    pub fn dma_overlap(&self, id: DeviceId) -> usize {
+        self.opened
+            .lock()
+            .unwrap()
+            .get(&id)
+            .and_then(|d| d.upgrade())
+            .map_or(0, |d| d.state.dma_overlap.load(Ordering::Relaxed))
+    }
+
+    fn check_device(&self, id: DeviceId) -> nix::Result<()> {
+        if (id as usize) < self.devices.len() {
+            Ok(())
+        } else {
+            Err(nix::Error::Sys(Errno::ENODEV))
+        }
+    }
+}
+
+impl Driver for SimulatedTLKM {
+    fn version(&self, cmd: &mut tlkm_ioctl_version_cmd) -> nix::Result<()> {
+        let v = b"simulation";
+        cmd.version[..v.len()].copy_from_slice(v);
+        Ok(())
+    }
+
+    fn enum_devices(&self, cmd: &mut tlkm_ioctl_enum_devices_cmd) -> nix::Result<()> {
+        cmd.num_devs = std::cmp::min(self.devices.len(), TLKM_DEVS_SZ);
+        for (i, d) in self.devices.iter().take(TLKM_DEVS_SZ).enumerate() {
+            cmd.devs[i].dev_id = i as DeviceId;
+            cmd.devs[i].vendor_id = d.vendor;
+            cmd.devs[i].product_id = d.product;
+            let len = std::cmp::min(d.name.len(), TLKM_DEVNAME_SZ - 1);
+            cmd.devs[i].name[..len].copy_from_slice(&d.name.as_bytes()[..len]);
+        }
+        Ok(())
+    }
+
+    fn create_device(&self, cmd: &mut tlkm_ioctl_device_cmd) -> nix::Result<()> {
+        trace!(
+            "Simulation: Device {} in mode {:?}.",
+            cmd.dev_id,
+            cmd.access
+        );
+        self.check_device(cmd.dev_id)
+    }
+
+    fn destroy_device(&self, cmd: &mut tlkm_ioctl_device_cmd) -> nix::Result<()> {
+        self.check_device(cmd.dev_id)
+    }
+
+    fn open_device(&self, id: DeviceId, _path: &Path) -> std::io::Result<Arc<dyn DeviceDriver>> {
+        let desc = match self.devices.get(id as usize) {
+            Some(d) => d,
+            None => return Err(std::io::Error::from_raw_os_error(libc::ENODEV)),
+        };
+
+        let mut opened = self.opened.lock().unwrap();
+        if let Some(d) = opened.get(&id).and_then(|x| x.upgrade()) {
+            return Ok(d);
+        }
+        let d = Arc::new(SimulatedDeviceDriver::new(desc)?);
+        opened.insert(id, Arc::downgrade(&d));
+        Ok(d)
+    }
+}
+
+/// RAM backed memory region that is shared between the runtime and the simulation.
+#[derive(Debug)]
+struct Region {
+    file: File,
+    map: MmapMut,
+}
+
+impl Region {
+    fn new(name: &str, size: usize) -> std::io::Result<Region> {
+        let fd = memfd_create(&CString::new(name).unwrap(), MemFdCreateFlag::MFD_CLOEXEC)
+            .map_err(|e| std::io::Error::new(std::io::ErrorKind::Other, e))?;
+        let file = unsafe { File::from_raw_fd(fd) };
+        file.set_len(size as u64)?;
+        let map = unsafe { MmapOptions::new().len(size).map_mut(&file)? };
+        Ok(Region {
+            file: file,
+            map: map,
+        })
+    }
+
+    fn size(&self) -> usize {
+        self.map.len()
+    }
+
+    fn read_u32(&self, offset: u64) -> u32 {
+        unsafe {
+            let ptr = self.map.as_ptr().offset(offset as isize);
+            (*(ptr as *const Volatile<u32>)).read()
+        }
+    }
+
+    fn write_u32(&self, offset: u64, v: u32) {
+        unsafe {
+            let ptr = self.map.as_ptr().offset(offset as isize);
+            (*(ptr as *mut Volatile<u32>)).write(v);
+        }
+    }
+
+    fn read_u64(&self, offset: u64) -> u64 {
+        unsafe {
+            let ptr = self.map.as_ptr().offset(offset as isize);
+            (*(ptr as *const Volatile<u64>)).read()
+        }
+    }
+
+    fn write_u64(&self, offset: u64, v: u64) {
+        unsafe {
+            let ptr = self.map.as_ptr().offset(offset as isize);
+            (*(ptr as *mut Volatile<u64>)).write(v);
+        }
+    }
+
+    /// Same as `DirectDMA`: The region is shared and accessed without a lock.
+    #[allow(clippy::mut_from_ref)]
+    unsafe fn slice_mut(&self, offset: u64, len: usize) -> &mut [u8] {
+        let p = self.map.as_ptr().offset(offset as isize) as *mut u8;
+        std::slice::from_raw_parts_mut(p, len)
+    }
+}
+
+/// Sparse off-chip memory. Pages are only allocated once they are written.
+#[derive(Debug, Default)]
+struct Memory {
+    pages: Mutex<HashMap<u64, Box<[u8]>>>,
+}
+
+impl Memory {
+    fn write(&self, addr: DeviceAddress, data: &[u8]) {
+        let mut pages = self.pages.lock().unwrap();
+        let mut done = 0;
+        while done < data.len() {
+            let a = addr + done as u64;
+            let offset = (a % MEMORY_PAGE_SIZE) as usize;
+            let n = std::cmp::min(data.len() - done, MEMORY_PAGE_SIZE as usize - offset);
+            let page = pages
+                .entry(a / MEMORY_PAGE_SIZE)
+                .or_insert_with(|| vec![0; MEMORY_PAGE_SIZE as usize].into_boxed_slice());
+            page[offset..offset + n].copy_from_slice(&data[done..done + n]);
+            done += n;
+        }
+    }
+
+    fn read(&self, addr: DeviceAddress, data: &mut [u8]) {
+        let pages = self.pages.lock().unwrap();
+        let mut done = 0;
+        while done < data.len() {
+            let a = addr + done as u64;
+            let offset = (a % MEMORY_PAGE_SIZE) as usize;
+            let n = std::cmp::min(data.len() - done, MEMORY_PAGE_SIZE as usize - offset);
+            match pages.get(&(a / MEMORY_PAGE_SIZE)) {
+                Some(page) => data[done..done + n].copy_from_slice(&page[offset..offset + n]),
+                None => data[done..done + n].iter_mut().for_each(|x| *x = 0),
+            }
+            done += n;
+        }
+    }
+}
+
+#[derive(Debug)]
+struct DMABuffer {
+    region: Region,
+    addr: u64,
+    /// Engine using the buffer, `None` until the engine registers its interrupts.
+    engine: Option<usize>,
+}
+
+struct PEState {
+    offset: u64,
+    local_memory: Option<(u64, usize)>,
+    interrupt: usize,
+    running: AtomicBool,
+    behaviour: Arc<dyn PEBehaviour>,
+}
+
+/// Simulated BlueDMA engine.
+///
+/// The engine accepts a single command at a time. Handing a buffer over to the device
+/// (`dma_buffer_to_dev`) is always followed by exactly one command on the engine owning
+/// the buffer, hence the hand over blocks until the previous command of that engine has
+/// been consumed. This serializes the register writes of concurrent transfers on the
+/// same engine while different engines work in parallel.
+///
+/// The runtime allocates the buffers of an engine right before registering its
+/// interrupts, which is used to assign the buffers to their engine.
+#[derive(Debug, Default)]
+struct DMAEngine {
+    pending: Mutex<bool>,
+    consumed: Condvar,
+}
+
+impl DMAEngine {
+    fn reserve(&self) {
+        let mut pending = self.pending.lock().unwrap();
+        while *pending {
+            pending = self.consumed.wait(pending).unwrap();
+        }
+        *pending = true;
+    }
+
+    fn release(&self) {
+        *self.pending.lock().unwrap() = false;
+        self.consumed.notify_one();
+    }
+}
+
+struct SimState {
+    status: Region,
+    arch: Region,
+    platform: Region,
+    pes: Vec<PEState>,
+    dma: Vec<DMAEngine>,
+    dma_engines: usize,
+    /// Highest number of DMA engines seen with a pending command at the same time.
+    dma_overlap: AtomicUsize,
+    memory: Memory,
+    allocator: Mutex<GenericAllocator>,
+    dma_buffers: Mutex<HashMap<usize, DMABuffer>>,
+    dma_buffer_id: Mutex<usize>,
+    interrupts: Mutex<HashMap<usize, RawFd>>,
+}
+
+impl Drop for SimState {
+    fn drop(&mut self) {
+        for (_, fd) in self.interrupts.lock().unwrap().drain() {
+            let _ = close(fd);
+        }
+    }
+}
+
+impl SimState {
+    fn new(desc: &SimulatedDevice) -> std::io::Result<SimState> {
+        let mut pes = Vec::new();
+        let mut status_pes = Vec::new();
+        let mut offset = 0;
+        for (i, pe) in desc.pes.iter().enumerate() {
+            let interrupt = PE_INTERRUPT_BASE + i as u64;
+            let pe_offset = offset;
+            offset += PE_SIZE;
+            let local_memory = match pe.local_memory {
+                Some(size) => {
+                    let base = offset;
+                    offset += (size + PE_SIZE - 1) & !(PE_SIZE - 1);
+                    Some(status::MemoryArea {
+                        base: base,
+                        size: size,
+                    })
+                }
+                None => None,
+            };
+            pes.push(PEState {
+                offset: pe_offset,
+                local_memory: local_memory.as_ref().map(|l| (l.base, l.size as usize)),
+                interrupt: interrupt as usize,
+                running: AtomicBool::new(false),
+                behaviour: pe.behaviour.clone(),
+            });
+            status_pes.push(status::Pe {
+                name: pe.name.clone(),
+                id: pe.id as u32,
+                offset: pe_offset,
+                size: PE_SIZE,
+                local_memory: local_memory,
+                debug: None,
+                interrupts: vec![status::Interrupt {
+                    mapping: interrupt,
+                    name: "0".to_string(),
+                }],
+            });
+        }
+        let arch_size = std::cmp::max(offset, PE_SIZE);
+        let platform_size = DMA_OFFSET + desc.dma_engines as u64 * DMA_SIZE;
+
+        let s = status::Status {
+            timestamp: chrono::Utc::now().timestamp() as u64,
+            arch_base: Some(status::MemoryArea {
+                base: 0,
+                size: arch_size,
+            }),
+            platform_base: Some(status::MemoryArea {
+                base: 0,
+                size: platform_size,
+            }),
+            pe: status_pes,
+            platform: (0..desc.dma_engines)
+                .map(|i| status::Platform {
+                    name: format!("PLATFORM_COMPONENT_DMA{}", i),
+                    offset: SimState::dma_offset(i),
+                    size: DMA_SIZE,
+                    interrupts: vec![
+                        status::Interrupt {
+                            mapping: 2 * i as u64,
+                            name: "READ".to_string(),
+                        },
+                        status::Interrupt {
+                            mapping: 2 * i as u64 + 1,
+                            name: "WRITE".to_string(),
+                        },
+                    ],
+                })
+                .collect(),
+            memories: desc.memories.clone(),
+            clocks: ["Design", "Memory", "Host"]
+                .iter()
+                .map(|x| status::Clock {
+                    name: x.to_string(),
+                    frequency_mhz: 100,
+                })
+                .collect(),
+            versions: vec![status::Version {
+                software: "TaPaSCo Simulation".to_string(),
+                year: 2020,
+                release: 1,
+                extra_version: "".to_string(),
+            }],
+        };
+
+        let mut encoded = Vec::new();
+        s.encode_length_delimited(&mut encoded)
+            .map_err(|e| std::io::Error::new(std::io::ErrorKind::Other, e))?;
+        if encoded.len() > STATUS_SIZE {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::Other,
+                "Simulated status core exceeds 8192 bytes.",
+            ));
+        }
+        let mut status_region = Region::new("tapasco_sim_status", STATUS_SIZE)?;
+        status_region.map[..encoded.len()].copy_from_slice(&encoded);
+
+        Ok(SimState {
+            status: status_region,
+            arch: Region::new("tapasco_sim_arch", arch_size as usize)?,
+            platform: Region::new("tapasco_sim_platform", platform_size as usize)?,
+            dma_engines: desc.dma_engines,
+            pes: pes,
+            dma: (0..desc.dma_engines)
+                .map(|_| DMAEngine::default())
+                .collect(),
+            dma_overlap: AtomicUsize::new(0),
+            memory: Memory::default(),
+            allocator: Mutex::new(
+                GenericAllocator::new(0, desc.memory_size, 64)
+                    .map_err(|e| std::io::Error::new(std::io::ErrorKind::Other, e.to_string()))?,
+            ),
+            dma_buffers: Mutex::new(HashMap::new()),
+            dma_buffer_id: Mutex::new(0),
+            interrupts: Mutex::new(HashMap::new()),
+        })
+    }
+
+    fn raise_interrupt(&self, id: usize) {
+        match self.interrupts.lock().unwrap().get(&id) {
+            Some(fd) => {
+                if let Err(e) = write(*fd, &1u64.to_ne_bytes()) {
+                    error!("Simulation: Could not raise interrupt {}: {}", id, e);
+                }
+            }
+            None => trace!("Simulation: Interrupt {} is not registered.", id),
+        }
+    }
+
+    /// Check all PEs for a start request and launch the behaviour of started PEs.
+    fn check_pes(state: &Arc<SimState>) -> bool {
+        let mut started = false;
+        for (i, pe) in state.pes.iter().enumerate() {
+            if !pe.running.load(Ordering::Acquire) && state.arch.read_u32(pe.offset) & 1 == 1 {
+                trace!("Simulation: PE {} started.", i);
+                pe.running.store(true, Ordering::Release);
+                started = true;
+                let s = state.clone();
+                thread::spawn(move || s.execute_pe(i));
+            }
+        }
+        started
+    }
+
+    fn execute_pe(&self, i: usize) {
+        let pe = &self.pes[i];
+        let rv = {
+            let mut ctx = PEContext {
+                state: self,
+                pe: pe,
+            };
+            pe.behaviour.run(&mut ctx)
+        };
+        trace!("Simulation: PE {} done, returning {}.", i, rv);
+        self.arch.write_u64(pe.offset + 0x10, rv);
+        self.arch.write_u32(pe.offset, 0);
+        pe.running.store(false, Ordering::Release);
+        self.raise_interrupt(pe.interrupt);
+    }
+
+    fn dma_offset(engine: usize) -> u64 {
+        DMA_OFFSET + engine as u64 * DMA_SIZE
+    }
+
+    /// Execute the pending BlueDMA commands of all engines.
+    fn check_dmas(&self) -> bool {
+        let mut busy = false;
+        for i in 0..self.dma_engines {
+            busy |= self.check_dma(i);
+        }
+        busy
+    }
+
+    /// Execute a pending BlueDMA command of the given engine.
+    fn check_dma(&self, engine: usize) -> bool {
+        let base = SimState::dma_offset(engine);
+        let cmd = self.platform.read_u64(base + 0x20);
+        if cmd == 0 {
+            return false;
+        }
+        std::sync::atomic::fence(Ordering::SeqCst);
+        let pending = (0..self.dma_engines)
+            .filter(|e| self.platform.read_u64(SimState::dma_offset(*e) + 0x20) != 0)
+            .count();
+        self.dma_overlap.fetch_max(pending, Ordering::Relaxed);
+        let addr_host = self.platform.read_u64(base);
+        let addr_device = self.platform.read_u64(base + 0x08);
+        let size = self.platform.read_u64(base + 0x10) as usize;
+        let from_device = cmd == 0x10001000;
+        trace!(
+            "Simulation: DMA{} 0x{:x} {} 0x{:x} ({} Bytes).",
+            engine,
+            addr_host,
+            if from_device { "<-" } else { "->" },
+            addr_device,
+            size
+        );
+
+        {
+            let mut buffers = self.dma_buffers.lock().unwrap();
+            match buffers.values_mut().find(|b| {
+                b.addr <= addr_host && addr_host + size as u64 <= b.addr + b.region.size() as u64
+            }) {
+                Some(b) => {
+                    let offset = (addr_host - b.addr) as usize;
+                    if from_device {
+                        self.memory
+                            .read(addr_device, &mut b.region.map[offset..offset + size]);
+                    } else {
+                        self.memory
+                            .write(addr_device, &b.region.map[offset..offset + size]);
+                    }
+                }
+                None => error!(
+                    "Simulation: DMA host address 0x{:x} does not belong to a DMA buffer.",
+                    addr_host
+                ),
+            }
+        }
+
+        self.platform.write_u64(base + 0x20, 0);
+        self.dma[engine].release();
+        self.raise_interrupt(2 * engine + if from_device { 0 } else { 1 });
+        true
+    }
+
+    fn run(state: Arc<SimState>, running: Arc<AtomicBool>) {
+        trace!("Simulation: Device thread started.");
+        while running.load(Ordering::Acquire) {
+            let pes = SimState::check_pes(&state);
+            let dma = state.check_dmas();
+            if !pes && !dma {
+                thread::sleep(Duration::from_micros(20));
+            }
+        }
+        trace!("Simulation: Device thread stopped.");
+    }
+}
+
+/// Access to the simulated hardware while a [`PEBehaviour`] is executed.
+///
+/// [`PEBehaviour`]: trait.PEBehaviour.html
+pub struct PEContext<'a> {
+    state: &'a SimState,
+    pe: &'a PEState,
+}
+
+impl<'a> PEContext<'a> {
+    /// Read the 64 bit value of argument register `n`.
+    pub fn arg(&self, n: usize) -> u64 {
+        self.state
+            .arch
+            .read_u64(self.pe.offset + 0x20 + n as u64 * 0x10)
+    }
+
+    /// Read from the off-chip memory of the device.
+    pub fn read_memory(&self, addr: DeviceAddress, data: &mut [u8]) {
+        self.state.memory.read(addr, data)
+    }
+
+    /// Write to the off-chip memory of the device.
+    pub fn write_memory(&self, addr: DeviceAddress, data: &[u8]) {
+        self.state.memory.write(addr, data)
+    }
+
+    /// The PE local memory if the PE has one. Addresses used for local memory
+    /// parameters are offsets into this slice.
+    pub fn local_memory(&mut self) -> Option<&mut [u8]> {
+        match self.pe.local_memory {
+            Some((base, size)) => Some(unsafe { self.state.arch.slice_mut(base, size) }),
+            None => None,
+        }
+    }
+}
+
+/// Simulated replacement of the device specific TLKM chardev.
+///
+/// Owns the background thread simulating the device which is stopped on drop.
+#[derive(Debug)]
+pub struct SimulatedDeviceDriver {
+    state: Arc<SimState>,
+    running: Arc<AtomicBool>,
+    thread: Option<thread::JoinHandle<()>>,
+}
+
+impl std::fmt::Debug for SimState {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("SimState")
+            .field("pes", &self.pes.len())
+            .field("arch", &self.arch.size())
+            .field("platform", &self.platform.size())
+            .finish()
+    }
+}
+
+impl Drop for SimulatedDeviceDriver {
+    fn drop(&mut self) {
+        self.running.store(false, Ordering::Release);
+        if let Some(t) = self.thread.take() {
+            let _ = t.join();
+        }
+    }
+}
+
+impl SimulatedDeviceDriver {
+    fn new(desc: &SimulatedDevice) -> std::io::Result<SimulatedDeviceDriver> {
+        let state = Arc::new(SimState::new(desc)?);
+        let running = Arc::new(AtomicBool::new(true));
+        let thread = {
+            let s = state.clone();
+            let r = running.clone();
+            thread::Builder::new()
+                .name("tapasco-sim".to_string())
+                .spawn(move || SimState::run(s, r))?
+        };
+        Ok(SimulatedDeviceDriver {
+            state: state,
+            running: running,
+            thread: Some(thread),
+        })
+    }
+
+    fn region_file(&self, offset: u64) -> std::io::Result<File> {
+        let f = match offset {
+            0 => self.state.status.file.try_clone()?,
+            4096 => self.state.arch.file.try_clone()?,
+            8192 => self.state.platform.file.try_clone()?,
+            x if x >= 4 * 4096 && x % 4096 == 0 => {
+                let id = (x / 4096 - 4) as usize;
+                match self.state.dma_buffers.lock().unwrap().get(&id) {
+                    Some(b) => b.region.file.try_clone()?,
+                    None => return Err(std::io::Error::from_raw_os_error(libc::EINVAL)),
+                }
+            }
+            _ => return Err(std::io::Error::from_raw_os_error(libc::EINVAL)),
+        };
+        Ok(f)
+    }
+}
+
+impl DeviceDriver for SimulatedDeviceDriver {
+    fn map(&self, offset: u64, len: usize) -> std::io::Result<Mmap> {
+        let f = self.region_file(offset)?;
+        unsafe { MmapOptions::new().len(len).map(&f) }
+    }
+
+    fn map_mut(&self, offset: u64, len: usize) -> std::io::Result<MmapMut> {
+        let f = self.region_file(offset)?;
+        unsafe { MmapOptions::new().len(len).map_mut(&f) }
+    }
+
+    fn alloc(&self, cmd: &mut tlkm_mm_cmd) -> nix::Result<()> {
+        cmd.dev_addr = self
+            .state
+            .allocator
+            .lock()
+            .unwrap()
+            .allocate(cmd.sz as DeviceSize)
+            .map_err(|_| nix::Error::Sys(Errno::ENOMEM))?;
+        Ok(())
+    }
+
+    fn free(&self, cmd: &mut tlkm_mm_cmd) -> nix::Result<()> {
+        self.state
+            .allocator
+            .lock()
+            .unwrap()
+            .free(cmd.dev_addr)
+            .map_err(|_| nix::Error::Sys(Errno::EINVAL))
+    }
+
+    fn copy_to(&self, cmd: &mut tlkm_copy_cmd_to) -> nix::Result<()> {
+        let data = unsafe { std::slice::from_raw_parts(cmd.user_addr, cmd.length) };
+        self.state.memory.write(cmd.dev_addr, data);
+        Ok(())
+    }
+
+    fn copy_from(&self, cmd: &mut tlkm_copy_cmd_from) -> nix::Result<()> {
+        let data = unsafe { std::slice::from_raw_parts_mut(cmd.user_addr, cmd.length) };
+        self.state.memory.read(cmd.dev_addr, data);
+        Ok(())
+    }
+
+    fn register_interrupt(&self, cmd: &mut tlkm_register_interrupt) -> nix::Result<()> {
+        let fd = dup(cmd.fd)?;
+        trace!(
+            "Simulation: Registered eventfd {} for interrupt {}.",
+            cmd.fd,
+            cmd.pe_id
+        );
+        if let Some(old) = self
+            .state
+            .interrupts
+            .lock()
+            .unwrap()
+            .insert(cmd.pe_id as usize, fd)
+        {
+            let _ = close(old);
+        }
+        let id = cmd.pe_id as usize;
+        if id < 2 * self.state.dma_engines {
+            for b in self.state.dma_buffers.lock().unwrap().values_mut() {
+                if b.engine.is_none() {
+                    b.engine = Some(id / 2);
+                }
+            }
+        }
+        Ok(())
+    }
+
+    fn dma_buffer_allocate(&self, cmd: &mut tlkm_dma_buffer_allocate) -> nix::Result<()> {
+        let id = {
+            let mut next = self.state.dma_buffer_id.lock().unwrap();
+            *next += 1;
+            *next - 1
+        };
+        let region = Region::new("tapasco_sim_dma_buffer", cmd.size)
+            .map_err(|_| nix::Error::Sys(Errno::ENOMEM))?;
+        let addr = DMA_BUFFER_BASE + id as u64 * DMA_BUFFER_STRIDE;
+        self.state.dma_buffers.lock().unwrap().insert(
+            id,
+            DMABuffer {
+                region: region,
+                addr: addr,
+                engine: None,
+            },
+        );
+        cmd.buffer_id = id;
+        cmd.addr = addr;
+        Ok(())
+    }
+
+    fn dma_buffer_free(&self, cmd: &mut tlkm_dma_buffer_op) -> nix::Result<()> {
+        match self
+            .state
+            .dma_buffers
+            .lock()
+            .unwrap()
+            .remove(&cmd.buffer_id)
+        {
+            Some(_) => Ok(()),
+            None => Err(nix::Error::Sys(Errno::EINVAL)),
+        }
+    }
+
+    fn dma_buffer_to_dev(&self, cmd: &mut tlkm_dma_buffer_op) -> nix::Result<()> {
+        let engine = match self.state.dma_buffers.lock().unwrap().get(&cmd.buffer_id) {
+            Some(b) => b.engine.unwrap_or(0),
+            None => return Err(nix::Error::Sys(Errno::EINVAL)),
+        };
+        self.state.dma[engine].reserve();
+        Ok(())
+    }
+
+    fn dma_buffer_from_dev(&self, _cmd: &mut tlkm_dma_buffer_op) -> nix::Result<()> {
+        Ok(())
+    }
+}
+
+/// Helpers shared by the tests of all modules.
+#[cfg(test)]
+pub(crate) mod testing {
+    use crate::sim::{PEContext, SimulatedDevice, SimulatedPE, SimulatedTLKM};
+    use crate::tlkm::TLKM;
+    use std::future::Future;
+    use std::sync::atomic::{AtomicBool, Ordering};
+    use std::sync::Arc;
+    use std::task::{Context, Poll, Wake, Waker};
+    use std::thread;
+
+    pub(crate) type Result<T> = std::result::Result<T, Box<dyn std::error::Error>>;
+
+    /// Simulated TLKM with two `sim:increment` PEs (14) and one `sim:add` PE (11).
+    pub(crate) fn init() -> Result<TLKM> {
+        let _ = env_logger::builder().is_test(true).try_init();
+        let increment = SimulatedPE::new(14, "sim:increment", |pe: &mut PEContext| {
+            let addr = pe.arg(0);
+            let len = pe.arg(1) as usize;
+            let mut data = vec![0u8; len];
+            pe.read_memory(addr, &mut data);
+            data.iter_mut().for_each(|x| *x = x.wrapping_add(1));
+            pe.write_memory(addr, &data);
+            len as u64
+        });
+        let add = SimulatedPE::new(11, "sim:add", |pe: &mut PEContext| pe.arg(0) + pe.arg(1));
+        let sim = SimulatedTLKM::new(vec![SimulatedDevice::new("pcie")
+            .pe(increment, 2)
+            .pe(add, 1)]);
+        Ok(TLKM::with_driver(Arc::new(sim))?)
+    }
+
+    struct ThreadWaker(thread::Thread);
+
+    impl Wake for ThreadWaker {
+        fn wake(self: Arc<Self>) {
+            self.0.unpark();
+        }
+    }
+
+    /// Minimal executor to drive futures without an async runtime.
+    pub(crate) fn block_on<F: Future>(f: F) -> F::Output {
+        let waker = Waker::from(Arc::new(ThreadWaker(thread::current())));
+        let mut cx = Context::from_waker(&waker);
+        let mut f = Box::pin(f);
+        loop {
+            match f.as_mut().poll(&mut cx) {
+                Poll::Ready(x) => return x,
+                Poll::Pending => thread::park(),
+            }
+        }
+    }
+
+    /// Waker recording that it has been woken.
+    pub(crate) struct FlagWaker(pub(crate) AtomicBool);
+
+    impl Wake for FlagWaker {
+        fn wake(self: Arc<Self>) {
+            self.0.store(true, Ordering::SeqCst);
+        }
+    }
+}
+
+#[cfg(test)]
+mod sim_tests {
+    use crate::device::{DataTransferAlloc, PEParameter};
+    use crate::sim::testing::{init, Result};
+    use crate::tlkm::tlkm_access;
+    use std::collections::HashMap;
+
+    #[test]
+    fn pe_return_value() -> Result<()> {
+        let tlkm = init()?;
+        let mut dev = tlkm.device_alloc(0, &HashMap::new())?;
+        dev.change_access(tlkm_access::TlkmAccessExclusive)?;
+        assert_eq!(dev.num_pes(11), 1);
+        assert_eq!(dev.num_pes(14), 2);
+        let mut job = dev.acquire_pe(11)?;
+        job.start(vec![PEParameter::Single64(40), PEParameter::Single64(2)])?;
+        let (rv, _) = job.release(true, true)?;
+        assert_eq!(rv, 42);
+        Ok(())
+    }
+
+    #[test]
+    fn dma_roundtrip() -> Result<()> {
+        let tlkm = init()?;
+        let dev = tlkm.device_alloc(0, &HashMap::new())?;
+        let mem = dev.default_memory()?;
+        let len = 1024 * 1024 + 123;
+        let data: Vec<u8> = (0..len).map(|x| (x % 251) as u8).collect();
+        let addr = mem.allocator().lock().unwrap().allocate(len as u64)?;
+        mem.dma().copy_to(&data, addr)?;
+        let mut back = vec![0u8; len];
+        mem.dma().copy_from(addr, &mut back)?;
+        assert!(data == back);
+        mem.allocator().lock().unwrap().free(addr)?;
+        Ok(())
+    }
+
+    #[test]
+    fn job_with_transfer() -> Result<()> {
+        let tlkm = init()?;
+        let mut dev = tlkm.device_alloc(0, &HashMap::new())?;
+        dev.change_access(tlkm_access::TlkmAccessExclusive)?;
+        let len = 300 * 1024;
+        let data = vec![41u8; len].into_boxed_slice();
+        let mut job = dev.acquire_pe(14)?;
+        job.start(vec![
+            PEParameter::DataTransferAlloc(DataTransferAlloc {
+                data: data,
+                from_device: true,
+                to_device: true,
+                free: true,
+                memory: dev.default_memory()?,
+                fixed: None,
+            }),
+            PEParameter::Single64(len as u64),
+        ])?;
+        let (rv, out) = job.release(true, true)?;
+        assert_eq!(rv, len as u64);
+        assert_eq!(out.len(), 1);
+        assert!(out[0].iter().all(|x| *x == 42));
+        Ok(())
+    }
+}
@@ -43,6 +43,7 @@
 #include <sstream>
 #include <stdexcept>
 #include <string>
+#include <vector>
 
 #include <tapasco_inner.hpp>
 
@@ -268,6 +269,14 @@ public:
     return a;
   }
 
+  DeviceAddress alloc(uint64_t len, const std::string &tag) {
+    DeviceAddress a = tapasco_memory_allocate_tagged(mem, len, tag.c_str());
+    if (a == (uint64_t)(int64_t)-1) {
+      handle_error();
+    }
+    return a;
+  }
+
   DeviceAddress alloc_fixed(uint64_t len, uint64_t offset) {
     DeviceAddress a = tapasco_memory_allocate_fixed(mem, len, offset);
     if (a == (uint64_t)(int64_t)-1) {
@@ -284,6 +293,14 @@ public:
     return 0;
   }
 
+  intptr_t free_all() {
+    intptr_t n = tapasco_memory_free_all(mem);
+    if (n == -1) {
+      handle_error();
+    }
+    return n;
+  }
+
   void free(DeviceAddress handle, size_t const len,
             tapasco_device_alloc_flag_t const flags) {
     this->free(handle);
@@ -315,6 +332,35 @@ public:
     return this->copy_from(src, dst, len);
   }
 
+  std::string name() {
+    char buf[256];
+    if (tapasco_memory_name(mem, buf, sizeof(buf)) == -1) {
+      handle_error();
+    }
+    return std::string(buf);
+  }
+
+  AllocatorStats stats() {
+    AllocatorStats s;
+    if (tapasco_memory_stats(mem, &s) == -1) {
+      handle_error();
+    }
+    return s;
+  }
+
+  std::vector<AllocationInfo> dump() {
+    std::vector<AllocationInfo> v(this->stats().allocations);
+    intptr_t n = tapasco_memory_dump(mem, v.data(), v.size());
+    if (n == -1) {
+      handle_error();
+      return {};
+    }
+    v.resize(n);
+    return v;
+  }
+
+  TapascoOffchipMemory *get_memory() { return this->mem; }
+
 private:
   TapascoOffchipMemory *mem;
 };
@@ -364,6 +410,33 @@ public:
     return TapascoMemory(mem);
   }
 
+  int num_memories() {
+    int cnt = tapasco_device_num_memories(this->device);
+    if (cnt < 0) {
+      tapasco::handle_error();
+    }
+    return cnt;
+  }
+
+  TapascoMemory memory(size_t idx) {
+    TapascoOffchipMemory *mem = tapasco_get_memory(this->device, idx);
+    if (mem == 0) {
+      handle_error();
+    }
+
+    return TapascoMemory(mem);
+  }
+
+  TapascoMemory memory(std::string name) {
+    TapascoOffchipMemory *mem =
+        tapasco_get_memory_by_name(this->device, name.c_str());
+    if (mem == 0) {
+      handle_error();
+    }
+
+    return TapascoMemory(mem);
+  }
+
   Job *acquire_pe(PEId pe_id) {
     Job *j = tapasco_device_acquire_pe(this->device, pe_id);
     if (j == 0) {
@@ -372,6 +445,45 @@ public:
     return j;
   }
 
+  PEStatsSummary pe_stats(size_t slot) {
+    PEStatsSummary s;
+    if (tapasco_device_pe_stats(this->device, slot, &s) == -1) {
+      handle_error();
+    }
+    return s;
+  }
+
+  PEStatsSummary type_stats(PEId id) {
+    PEStatsSummary s;
+    if (tapasco_device_type_stats(this->device, id, &s) == -1) {
+      handle_error();
+    }
+    return s;
+  }
+
+  void reset_stats() {
+    if (tapasco_device_reset_stats(this->device) == -1) {
+      handle_error();
+    }
+  }
+
+  std::vector<Job *> acquire_pes(std::vector<PEId> const &ids,
+                                 std::vector<size_t> const &counts) {
+    if (ids.size() != counts.size()) {
+      throw tapasco_error("Number of PE IDs and counts differ.");
+    }
+    size_t total = 0;
+    for (size_t c : counts) {
+      total += c;
+    }
+    std::vector<Job *> jobs(total);
+    if (tapasco_device_acquire_pes(this->device, ids.data(), counts.data(),
+                                   ids.size(), jobs.data(), jobs.size()) < 0) {
+      handle_error();
+    }
+    return jobs;
+  }
+
   float design_frequency() {
     return tapasco_device_design_frequency(this->device);
   }
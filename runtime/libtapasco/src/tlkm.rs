@@ -21,14 +21,20 @@
 use crate::debug::DebugGenerator;
 use crate::device::Error as DevError;
 use crate::device::{Device, DeviceAddress};
+use crate::platform::{default_platforms, PlatformDriver};
 use config::Config;
+use core::fmt::Debug;
 use libc::c_char;
+use memmap::Mmap;
+use memmap::MmapMut;
+use memmap::MmapOptions;
 use snafu::ResultExt;
 use std::collections::HashMap;
 use std::ffi::CString;
 use std::fs::File;
 use std::fs::OpenOptions;
 use std::os::unix::prelude::*;
+use std::path::Path;
 use std::path::PathBuf;
 use std::sync::Arc;
 
@@ -70,9 +76,9 @@ type Result<T, E = Error> = std::result::Result<T, E>;
 
 pub type DeviceId = u32;
 
-const TLKM_VERSION_SZ: usize = 30;
-const TLKM_DEVNAME_SZ: usize = 30;
-const TLKM_DEVS_SZ: usize = 10;
+pub const TLKM_VERSION_SZ: usize = 30;
+pub const TLKM_DEVNAME_SZ: usize = 30;
+pub const TLKM_DEVS_SZ: usize = 10;
 
 const TLKM_DEVICE_IOC_MAGIC: u8 = b'd';
 
@@ -152,7 +158,7 @@ const TLKM_IOCTL_VERSION: u8 = 1;
 #[repr(C)]
 #[derive(Default)]
 pub struct tlkm_ioctl_version_cmd {
-    version: [u8; TLKM_VERSION_SZ],
+    pub version: [u8; TLKM_VERSION_SZ],
 }
 
 ioctl_readwrite!(
@@ -167,17 +173,17 @@ const TLKM_IOCTL_ENUM_DEVICES: u8 = 2;
 #[repr(C)]
 #[derive(Default)]
 pub struct tlkm_device_info {
-    dev_id: DeviceId,
-    vendor_id: u32,
-    product_id: u32,
-    name: [u8; TLKM_DEVNAME_SZ],
+    pub dev_id: DeviceId,
+    pub vendor_id: u32,
+    pub product_id: u32,
+    pub name: [u8; TLKM_DEVNAME_SZ],
 }
 
 #[repr(C)]
 #[derive(Default)]
 pub struct tlkm_ioctl_enum_devices_cmd {
-    num_devs: usize,
-    devs: [tlkm_device_info; TLKM_DEVS_SZ],
+    pub num_devs: usize,
+    pub devs: [tlkm_device_info; TLKM_DEVS_SZ],
 }
 
 ioctl_readwrite!(
@@ -275,6 +281,112 @@ ioctl_readwrite!(
 
 // End of IOCTL definitions.
 
+/// Operations provided by the TLKM main chardev
+///
+/// Each method corresponds to one of the IOCTLs above and uses the same
+/// command structures. The implementation for `File` forwards the commands to
+/// the kernel module. Other implementations, e.g. the simulation in [`sim`],
+/// provide the same behaviour without any hardware or kernel module.
+///
+/// [`sim`]: ../sim/index.html
+pub trait Driver: Debug + Send + Sync {
+    fn version(&self, cmd: &mut tlkm_ioctl_version_cmd) -> nix::Result<()>;
+    fn enum_devices(&self, cmd: &mut tlkm_ioctl_enum_devices_cmd) -> nix::Result<()>;
+    fn create_device(&self, cmd: &mut tlkm_ioctl_device_cmd) -> nix::Result<()>;
+    fn destroy_device(&self, cmd: &mut tlkm_ioctl_device_cmd) -> nix::Result<()>;
+    /// Open the device specific driver for device `id`.
+    /// `path` is the configured device chardev, e.g. `/dev/tlkm_00`.
+    fn open_device(&self, id: DeviceId, path: &Path) -> std::io::Result<Arc<dyn DeviceDriver>>;
+}
+
+/// Operations provided by the device specific TLKM chardev
+///
+/// Besides the IOCTLs, the chardev is used to map the status core (offset 0),
+/// the architecture (offset 4096), the platform (offset 8192) and the DMA
+/// bounce buffers (offset `(4 + buffer_id) * 4096`) into user space.
+pub trait DeviceDriver: Debug + Send + Sync {
+    fn map(&self, offset: u64, len: usize) -> std::io::Result<Mmap>;
+    fn map_mut(&self, offset: u64, len: usize) -> std::io::Result<MmapMut>;
+    fn alloc(&self, cmd: &mut tlkm_mm_cmd) -> nix::Result<()>;
+    fn free(&self, cmd: &mut tlkm_mm_cmd) -> nix::Result<()>;
+    fn copy_to(&self, cmd: &mut tlkm_copy_cmd_to) -> nix::Result<()>;
+    fn copy_from(&self, cmd: &mut tlkm_copy_cmd_from) -> nix::Result<()>;
+    fn register_interrupt(&self, cmd: &mut tlkm_register_interrupt) -> nix::Result<()>;
+    fn dma_buffer_allocate(&self, cmd: &mut tlkm_dma_buffer_allocate) -> nix::Result<()>;
+    fn dma_buffer_free(&self, cmd: &mut tlkm_dma_buffer_op) -> nix::Result<()>;
+    fn dma_buffer_to_dev(&self, cmd: &mut tlkm_dma_buffer_op) -> nix::Result<()>;
+    fn dma_buffer_from_dev(&self, cmd: &mut tlkm_dma_buffer_op) -> nix::Result<()>;
+}
+
+impl Driver for File {
+    fn version(&self, cmd: &mut tlkm_ioctl_version_cmd) -> nix::Result<()> {
+        unsafe { tlkm_ioctl_version(self.as_raw_fd(), cmd).map(|_| ()) }
+    }
+
+    fn enum_devices(&self, cmd: &mut tlkm_ioctl_enum_devices_cmd) -> nix::Result<()> {
+        unsafe { tlkm_ioctl_enum(self.as_raw_fd(), cmd).map(|_| ()) }
+    }
+
+    fn create_device(&self, cmd: &mut tlkm_ioctl_device_cmd) -> nix::Result<()> {
+        unsafe { tlkm_ioctl_create(self.as_raw_fd(), cmd).map(|_| ()) }
+    }
+
+    fn destroy_device(&self, cmd: &mut tlkm_ioctl_device_cmd) -> nix::Result<()> {
+        unsafe { tlkm_ioctl_destroy(self.as_raw_fd(), cmd).map(|_| ()) }
+    }
+
+    fn open_device(&self, _id: DeviceId, path: &Path) -> std::io::Result<Arc<dyn DeviceDriver>> {
+        let file = OpenOptions::new().read(true).write(true).open(path)?;
+        Ok(Arc::new(file))
+    }
+}
+
+impl DeviceDriver for File {
+    fn map(&self, offset: u64, len: usize) -> std::io::Result<Mmap> {
+        unsafe { MmapOptions::new().len(len).offset(offset).map(self) }
+    }
+
+    fn map_mut(&self, offset: u64, len: usize) -> std::io::Result<MmapMut> {
+        unsafe { MmapOptions::new().len(len).offset(offset).map_mut(self) }
+    }
+
+    fn alloc(&self, cmd: &mut tlkm_mm_cmd) -> nix::Result<()> {
+        unsafe { tlkm_ioctl_alloc(self.as_raw_fd(), cmd).map(|_| ()) }
+    }
+
+    fn free(&self, cmd: &mut tlkm_mm_cmd) -> nix::Result<()> {
+        unsafe { tlkm_ioctl_free(self.as_raw_fd(), cmd).map(|_| ()) }
+    }
+
+    fn copy_to(&self, cmd: &mut tlkm_copy_cmd_to) -> nix::Result<()> {
+        unsafe { tlkm_ioctl_copy_to(self.as_raw_fd(), cmd).map(|_| ()) }
+    }
+
+    fn copy_from(&self, cmd: &mut tlkm_copy_cmd_from) -> nix::Result<()> {
+        unsafe { tlkm_ioctl_copy_from(self.as_raw_fd(), cmd).map(|_| ()) }
+    }
+
+    fn register_interrupt(&self, cmd: &mut tlkm_register_interrupt) -> nix::Result<()> {
+        unsafe { tlkm_ioctl_reg_interrupt(self.as_raw_fd(), cmd).map(|_| ()) }
+    }
+
+    fn dma_buffer_allocate(&self, cmd: &mut tlkm_dma_buffer_allocate) -> nix::Result<()> {
+        unsafe { tlkm_ioctl_dma_buffer_allocate(self.as_raw_fd(), cmd).map(|_| ()) }
+    }
+
+    fn dma_buffer_free(&self, cmd: &mut tlkm_dma_buffer_op) -> nix::Result<()> {
+        unsafe { tlkm_ioctl_dma_buffer_free(self.as_raw_fd(), cmd).map(|_| ()) }
+    }
+
+    fn dma_buffer_to_dev(&self, cmd: &mut tlkm_dma_buffer_op) -> nix::Result<()> {
+        unsafe { tlkm_ioctl_dma_buffer_to_dev(self.as_raw_fd(), cmd).map(|_| ()) }
+    }
+
+    fn dma_buffer_from_dev(&self, cmd: &mut tlkm_dma_buffer_op) -> nix::Result<()> {
+        unsafe { tlkm_ioctl_dma_buffer_from_dev(self.as_raw_fd(), cmd).map(|_| ()) }
+    }
+}
+
 /// TLKM IOCTL convenience access
 ///
 /// This struct combines all basic interactions with TLKM
@@ -283,8 +395,9 @@ ioctl_readwrite!(
 /// handle is stored for future accesses.
 
 pub struct TLKM {
-    file: Arc<File>,
+    driver: Arc<dyn Driver>,
     settings: Arc<Config>,
+    platforms: HashMap<String, Arc<dyn PlatformDriver>>,
 }
 
 /// Helper structure for device information
@@ -310,6 +423,62 @@ impl Drop for TLKM {
 impl TLKM {
     /// Open the driver chardev.
     pub fn new() -> Result<TLKM> {
+        let settings = TLKM::load_settings()?;
+
+        let path = PathBuf::from(
+            settings
+                .get_str("tlkm.main_driver_file")
+                .context(ConfigError)?,
+        );
+        let file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .open(&path)
+            .context(DriverOpen { filename: path })?;
+
+        Ok(TLKM {
+            driver: Arc::new(file),
+            settings: Arc::new(settings),
+            platforms: default_platforms(),
+        })
+    }
+
+    /// Use the given driver instead of the TLKM chardev.
+    ///
+    /// Mainly used to run the runtime on top of the [`sim`] backend.
+    /// The configuration is loaded in the same way as in [`new`].
+    ///
+    /// [`sim`]: ../sim/index.html
+    /// [`new`]: #method.new
+    pub fn with_driver(driver: Arc<dyn Driver>) -> Result<TLKM> {
+        let settings = TLKM::load_settings()?;
+
+        Ok(TLKM {
+            driver: driver,
+            settings: Arc::new(settings),
+            platforms: default_platforms(),
+        })
+    }
+
+    /// Override a configuration value, e.g. `scheduler.policy`, for devices allocated
+    /// afterwards.
+    pub fn set_config(&mut self, key: &str, value: &str) -> Result<()> {
+        Arc::make_mut(&mut self.settings)
+            .set(key, value)
+            .context(ConfigError)?;
+        Ok(())
+    }
+
+    /// Register a driver for devices of the given platform name.
+    ///
+    /// The name is matched against the device name reported by TLKM. Drivers for
+    /// `pcie`, `zynq` and `zynqmp` are registered by default and can be replaced.
+    pub fn register_platform(&mut self, name: &str, driver: Arc<dyn PlatformDriver>) {
+        trace!("Registering platform driver {}: {:?}.", name, driver);
+        self.platforms.insert(name.to_string(), driver);
+    }
+
+    fn load_settings() -> Result<Config> {
         let default_config = include_str!("../config/default.toml");
         let mut settings = Config::default();
 
@@ -332,21 +501,7 @@ impl TLKM {
 
         trace!("Using config: {:?}", settings);
 
-        let path = PathBuf::from(
-            settings
-                .get_str("tlkm.main_driver_file")
-                .context(ConfigError)?,
-        );
-        let file = OpenOptions::new()
-            .read(true)
-            .write(true)
-            .open(&path)
-            .context(DriverOpen { filename: path })?;
-
-        Ok(TLKM {
-            file: Arc::new(file),
-            settings: Arc::new(settings),
-        })
+        Ok(settings)
     }
 
     /// Retrieve version information from TLKM
@@ -355,9 +510,7 @@ impl TLKM {
     /// Unstable and not intended for parsing by downstream code.
     pub fn version(&self) -> Result<String> {
         let mut version: tlkm_ioctl_version_cmd = Default::default();
-        unsafe {
-            tlkm_ioctl_version(self.file.as_raw_fd(), &mut version).context(IOCTLVersion)?;
-        };
+        self.driver.version(&mut version).context(IOCTLVersion)?;
 
         let s = String::from_utf8_lossy(&version.version)
             .trim_matches(char::from(0))
@@ -375,9 +528,7 @@ impl TLKM {
     pub fn device_enum_len(&self) -> Result<usize> {
         trace!("Fetching available devices from driver.");
         let mut devices: tlkm_ioctl_enum_devices_cmd = Default::default();
-        unsafe {
-            tlkm_ioctl_enum(self.file.as_raw_fd(), &mut devices).context(IOCTLEnum)?;
-        };
+        self.driver.enum_devices(&mut devices).context(IOCTLEnum)?;
 
         trace!("There are {} devices.", devices.num_devs);
 
@@ -392,9 +543,7 @@ impl TLKM {
     pub fn device_enum_info(&self) -> Result<Vec<DeviceInfo>> {
         trace!("Fetching available devices from driver.");
         let mut devices: tlkm_ioctl_enum_devices_cmd = Default::default();
-        unsafe {
-            tlkm_ioctl_enum(self.file.as_raw_fd(), &mut devices).context(IOCTLEnum)?;
-        };
+        self.driver.enum_devices(&mut devices).context(IOCTLEnum)?;
 
         trace!("There are {} devices.", devices.num_devs);
 
@@ -442,9 +591,7 @@ impl TLKM {
     ) -> Result<Device> {
         trace!("Fetching available devices from driver.");
         let mut devices: tlkm_ioctl_enum_devices_cmd = Default::default();
-        unsafe {
-            tlkm_ioctl_enum(self.file.as_raw_fd(), &mut devices).context(IOCTLEnum)?;
-        };
+        self.driver.enum_devices(&mut devices).context(IOCTLEnum)?;
 
         trace!("There are {} devices.", devices.num_devs);
 
@@ -456,7 +603,7 @@ impl TLKM {
             }
             if devices.devs[x].dev_id == id {
                 return Ok(Device::new(
-                    self.file.clone(),
+                    self.driver.clone(),
                     devices.devs[x].dev_id,
                     devices.devs[x].vendor_id,
                     devices.devs[x].product_id,
@@ -465,6 +612,7 @@ impl TLKM {
                         .to_string(),
                     self.settings.clone(),
                     debug_impls,
+                    &self.platforms,
                 )
                 .context(DeviceError)?);
             }
@@ -485,9 +633,7 @@ impl TLKM {
     ) -> Result<Vec<Device>> {
         trace!("Fetching available devices from driver.");
         let mut devices: tlkm_ioctl_enum_devices_cmd = Default::default();
-        unsafe {
-            tlkm_ioctl_enum(self.file.as_raw_fd(), &mut devices).context(IOCTLEnum)?;
-        };
+        self.driver.enum_devices(&mut devices).context(IOCTLEnum)?;
 
         let mut v = Vec::new();
 
@@ -501,7 +647,7 @@ impl TLKM {
             }
             v.push(
                 Device::new(
-                    self.file.clone(),
+                    self.driver.clone(),
                     devices.devs[x].dev_id,
                     devices.devs[x].vendor_id,
                     devices.devs[x].product_id,
@@ -510,6 +656,7 @@ impl TLKM {
                         .to_string(),
                     self.settings.clone(),
                     debug_impls,
+                    &self.platforms,
                 )
                 .context(DeviceError)?,
             );
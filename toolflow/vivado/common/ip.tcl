@@ -485,6 +485,15 @@ namespace eval ::tapasco::ip {
     lappend debugable_pes [list $pe_id $name $offset $size]
   }
 
+  set memories [list]
+
+  namespace export add_memory
+  proc add_memory {name base size alignment dma} {
+    variable memories
+    puts "Adding memory $name @ $base -> $base + $size using $dma"
+    lappend memories [list $name $base $size $alignment $dma]
+  }
+
   set interrupts [list]
   set interrupt_mapping [list]
 
@@ -522,6 +531,7 @@ namespace eval ::tapasco::ip {
   # Generate JSON configuration for the status core.
   proc make_status_config_json {} {
     variable debugable_pes
+    variable memories
     variable interrupts
     variable interrupt_mapping
 
@@ -570,6 +580,15 @@ namespace eval ::tapasco::ip {
         lappend debug [json::write object "PE_ID" $pe_id "Name" $name "Offset" $offset "Size" $size]
     }
 
+    set memory_json [list]
+    foreach m $memories {
+        set name [json::write string [lindex $m 0]]
+        set base [json::write string [format "0x%016x" [lindex $m 1]]]
+        set size [json::write string [format "0x%016x" [lindex $m 2]]]
+        set dma [json::write string [lindex $m 4]]
+        lappend memory_json [json::write object "Name" $name "Base" $base "Size" $size "Alignment" [lindex $m 3] "DMA" $dma]
+    }
+
     set interrupt_json [list]
     foreach {name clk} $interrupts mapping $interrupt_mapping {
       puts "Interrupt $name @ $mapping"
@@ -611,6 +630,7 @@ namespace eval ::tapasco::ip {
                                        "Components" [json::write array {*}$pc_bases]] \
       "Debug" [json::write array {*}$debug] \
       "Interrupts" [json::write array {*}$interrupt_json] \
+      "Memories" [json::write array {*}$memory_json] \
     ]
   }
 }
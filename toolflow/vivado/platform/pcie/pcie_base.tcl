@@ -32,6 +32,11 @@
     puts "Using PCIe width $pcie_width."
   }
 
+  if { ! [info exists memory_size] } {
+    puts "No memory size defined. Assuming 4GB..."
+    set memory_size 0x100000000
+  }
+
   # scan plugin directory
   foreach f [glob -nocomplain -directory "$::env(TAPASCO_HOME_TCL)/platform/pcie/plugins" "*.tcl"] {
     source -notrace $f
@@ -210,6 +215,10 @@
     # create instances of cores: MIG core, DMA
     set mig [create_mig_core "mig"]
 
+    # describe the DDR in the status core, an empty DMA list selects all DMA engines
+    variable memory_size
+    ::tapasco::ip::add_memory "DDR" 0 $memory_size 64 ""
+
     variable pcie_width
     if { $pcie_width == "x8" } {
       set dma [tapasco::ip::create_bluedma "dma"]
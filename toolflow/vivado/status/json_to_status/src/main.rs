@@ -96,6 +96,18 @@ struct InterruptMapping {
     Mapping: u64,
 }
 
+#[allow(non_snake_case)]
+#[derive(Deserialize, Debug)]
+struct Memory {
+    Name: String,
+    Base: String,
+    Size: String,
+    #[serde(default)]
+    Alignment: u64,
+    #[serde(default)]
+    DMA: String,
+}
+
 #[allow(non_snake_case)]
 #[derive(Deserialize, Debug)]
 struct ComponentAddresses {
@@ -120,6 +132,8 @@ struct Design {
     Platform: ComponentAddresses,
     Debug: Vec<Debug>,
     Interrupts: Vec<InterruptMapping>,
+    #[serde(default)]
+    Memories: Vec<Memory>,
 }
 
 #[derive(Debug, Fail)]
@@ -388,6 +402,26 @@ fn run() -> Result<()> {
         })
         .collect();
 
+    let mut memories: Vec<status::Memory> = Vec::new();
+    for mem in json.Memories {
+        let base = from_hex_str(&mem.Base)?;
+        let size = from_hex_str(&mem.Size)?;
+        info!(
+            "Memory {}: 0x{:X} - 0x{:X} using DMA engine {}",
+            mem.Name,
+            base,
+            base + size,
+            mem.DMA
+        );
+        memories.push(status::Memory {
+            name: mem.Name,
+            base: base,
+            size: size,
+            alignment: mem.Alignment,
+            dma: mem.DMA,
+        });
+    }
+
     let mut max_offset = 0;
     let mut max_size = 0;
     for pe in &pes {
@@ -428,6 +462,7 @@ fn run() -> Result<()> {
         platform: platforms,
         clocks: clocks,
         versions: versions,
+        memories: memories,
     };
 
     let mut buf: Vec<u8> = Vec::new();